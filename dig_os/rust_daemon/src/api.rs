@@ -1,29 +1,362 @@
-use std::sync::Arc;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet},
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use axum::{
-    extract::State,
-    http::StatusCode,
-    response::IntoResponse,
+    async_trait,
+    extract::{
+        rejection::JsonRejection,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        DefaultBodyLimit, Extension, FromRequest, OriginalUri, Path, Query, Request, State,
+    },
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
+use chrono::{DateTime, Local, Utc};
 use serde::{Deserialize, Serialize};
-use tracing::warn;
+use tokio::{sync::broadcast::error::RecvError, time::MissedTickBehavior};
+use tower_http::compression::CompressionLayer;
+use tracing::{info, info_span, warn, Instrument};
 
 use crate::{
-    cgroups,
-    scheduler::{allocation_for_mode, PerformanceMode},
-    state::AppState,
+    auth::{self, Role},
+    cgroups::{self, CgroupProbe, GroupReadback, GroupWrites},
+    config::{Config, ThermalLimitSource},
+    events::Event,
+    gpu_vendor::{self, GpuVendor},
+    health::{self, HealthDetail},
+    lifetime::LifetimeCounters,
+    missions::{self, DatasetStatus, Mission, TagMatch},
+    schedule::{self, ScheduleWindow, Transition},
+    scheduler::{
+        self, allocation_for_mode, mission_recommended, recommend_mode, Allocation, PerformanceMode,
+    },
+    state::{AllocationControl, AppState, BalanceSuggestion, RuntimeState, WorkerHeartbeat},
+    telemetry::{
+        self, SourcePreference, TelemetryDelta, TelemetrySnapshot, TelemetrySource, TemperatureUnit,
+    },
+    webhook::{self, ModeChange, ModeChangeReason},
 };
 
+const WATCH_TIMEOUT: Duration = Duration::from_secs(30);
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+/// Longer client-supplied IDs are replaced rather than logged.
+const MAX_REQUEST_ID_LEN: usize = 128;
+/// How often a telemetry WebSocket is pinged; a client that has sent
+/// nothing, not even a pong, for `STREAM_PINGS_UNANSWERED` pings is dropped.
+const STREAM_PING_INTERVAL: Duration = Duration::from_secs(10);
+const STREAM_PINGS_UNANSWERED: u32 = 3;
+/// Sent with the 503 for a stream over `max_stream_subscribers`.
+const STREAM_RETRY_AFTER_SECS: u64 = 5;
+const PROJECTION_HORIZONS: [(&str, u64); 3] = [("hour", 3_600), ("day", 86_400), ("week", 604_800)];
+
+/// Listed by `GET /`; keep in step with the routes registered below.
+const ENDPOINTS: [(&str, &str); 34] = [
+    ("GET", "/health"),
+    ("GET", "/health/detail"),
+    ("GET", "/api/versions"),
+    ("GET", "/api/v1/telemetry"),
+    ("POST", "/api/v1/telemetry/refresh"),
+    ("GET", "/api/v1/telemetry/compare"),
+    ("GET", "/api/v1/telemetry/source"),
+    ("POST", "/api/v1/telemetry/source"),
+    ("GET", "/api/v1/telemetry/ws"),
+    ("GET", "/api/v1/runtime"),
+    ("GET", "/api/v1/runtime/watch"),
+    ("GET", "/api/v1/modes"),
+    ("GET", "/api/v1/recommend"),
+    ("POST", "/api/v1/mode"),
+    ("POST", "/api/v1/intensity"),
+    ("POST", "/api/v1/allocation"),
+    ("POST", "/api/v1/allocation/simulate"),
+    ("GET", "/api/v1/mode/{mode}/allocation"),
+    ("GET", "/api/v1/missions"),
+    ("POST", "/api/v1/missions/{id}/start"),
+    ("POST", "/api/v1/missions/{id}/dataset"),
+    ("GET", "/api/v1/missions/{id}/stats"),
+    ("GET", "/api/v1/events"),
+    ("GET", "/api/v1/stats"),
+    ("GET", "/api/v1/progress"),
+    ("POST", "/api/v1/worker/heartbeat"),
+    ("GET", "/api/v1/debug/dump"),
+    ("GET", "/api/v1/hardware"),
+    ("GET", "/api/v1/schedule"),
+    ("GET", "/api/v1/whoami"),
+    ("GET", "/api/v1/cgroups/members"),
+    ("GET", "/api/v1/impact"),
+    ("GET", "/api/v1/earnings/projection"),
+    ("POST", "/api/v1/session/reset"),
+];
+
+/// API versions served, oldest first, as listed by `GET /api/versions`.
+/// Each one is its own sub-router nested under `/api/<version>`; a new
+/// version adds an entry here and a `.nest` in `router`, leaving older
+/// versions' handlers and response shapes alone.
+const API_VERSIONS: [&str; 1] = ["v1"];
+
 pub fn router(state: Arc<AppState>) -> Router {
-    Router::new()
+    let config = state.config();
+    let unversioned = Router::new()
+        .route("/", get(root_index))
         .route("/health", get(health))
-        .route("/api/v1/telemetry", get(get_telemetry))
-        .route("/api/v1/runtime", get(get_runtime))
-        .route("/api/v1/mode", post(set_mode))
-        .route("/api/v1/missions", get(list_missions))
-        .with_state(state)
+        .route("/health/detail", get(health_detail))
+        .route("/api/versions", get(list_versions));
+
+    let v1 = v1(&config)
+        .route_layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            ui_contact,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            authorize,
+        ));
+    let api: Router = compressed(unversioned, &config)
+        .nest("/api/v1", v1)
+        .layer(DefaultBodyLimit::max(config.max_body_bytes))
+        .with_state(state);
+
+    // Routes only add the `Allow` header on the way out, so the 405 rewrite
+    // wraps the finished router to see it.
+    Router::new()
+        .fallback_service(api)
+        .layer(middleware::map_response(method_not_allowed))
+        .layer(middleware::from_fn(request_id))
+}
+
+/// Runs the request in a span carrying its `X-Request-Id`, or a fresh UUID
+/// without a usable one, and echoes the ID back, so a UI action can be
+/// matched with the daemon log lines it caused.
+async fn request_id(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map(str::to_string)
+        .unwrap_or_else(new_request_id);
+    let span = info_span!(
+        "request",
+        id = %id,
+        method = %request.method(),
+        path = %request.uri().path(),
+    );
+    let mut response = next.run(request).instrument(span).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID, value);
+    }
+    response
+}
+
+/// Gives each `/api/v1` request the `Role` `request_role` grants it, or turns
+/// it away.
+async fn authorize(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    match request_role(&state.config(), &request) {
+        Ok(role) => {
+            request.extensions_mut().insert(role);
+            next.run(request).await
+        }
+        Err(error) => {
+            let unauthorized = error.status == StatusCode::UNAUTHORIZED;
+            let mut response = error.into_response();
+            if unauthorized {
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            response
+        }
+    }
+}
+
+/// The role from the request's `Authorization: Bearer` key, or from
+/// `?api_key=` on a WebSocket upgrade only, since browsers can't set headers
+/// there. 401 without a known key while keys are configured; 403 when a
+/// read-only key asks for a change or the debug dump, which exposes the
+/// whole config.
+fn request_role(config: &Config, request: &Request) -> Result<Role, ApiError> {
+    let websocket = request
+        .headers()
+        .get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| {
+            request
+                .uri()
+                .query()
+                .filter(|_| websocket)?
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("api_key="))
+        });
+    let Some(role) = auth::role_for(
+        config.api_admin_key.as_ref(),
+        config.api_read_key.as_ref(),
+        presented,
+    ) else {
+        return Err(ApiError::unauthorized(
+            "missing or unknown API key".to_string(),
+        ));
+    };
+    let read_only = matches!(*request.method(), Method::GET | Method::HEAD)
+        && !request.uri().path().ends_with("/debug/dump");
+    if role == Role::ReadOnly && !read_only {
+        let path = request
+            .extensions()
+            .get::<OriginalUri>()
+            .map_or(request.uri().path(), |uri| uri.path());
+        return Err(ApiError::forbidden(format!(
+            "{} {path} needs the admin key",
+            request.method()
+        )));
+    }
+    Ok(role)
+}
+
+/// Counts every API request except worker heartbeats as UI contact, for
+/// `ui_contact_timeout_secs`.
+async fn ui_contact(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    if !request.uri().path().ends_with("/worker/heartbeat") {
+        state.touch_ui_contact();
+    }
+    next.run(request).await
+}
+
+/// A random (version 4) UUID.
+fn new_request_id() -> String {
+    let mut bytes = [0u8; 16];
+    if let Err(error) = getrandom::getrandom(&mut bytes) {
+        warn!("no randomness for a request id: {error}");
+    }
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// `/api/v1`, with paths relative to that prefix.
+fn v1(config: &Config) -> Router<Arc<AppState>> {
+    let routes = Router::new()
+        .route("/telemetry", get(get_telemetry))
+        .route("/telemetry/refresh", post(refresh_telemetry))
+        .route("/telemetry/compare", get(compare_telemetry))
+        .route(
+            "/telemetry/source",
+            get(get_telemetry_source).post(set_telemetry_source),
+        )
+        .route("/runtime", get(get_runtime))
+        .route("/modes", get(list_modes))
+        .route("/recommend", get(recommend))
+        .route("/mode", post(set_mode))
+        .route("/intensity", post(set_intensity))
+        .route("/allocation", post(set_allocation))
+        .route("/allocation/simulate", post(simulate_allocation))
+        .route("/mode/:mode/allocation", get(preview_allocation))
+        .route("/missions", get(list_missions))
+        .route("/events", get(list_events))
+        .route("/stats", get(get_stats))
+        .route("/progress", get(get_progress))
+        .route("/worker/heartbeat", post(worker_heartbeat))
+        .route("/debug/dump", get(debug_dump))
+        .route("/hardware", get(get_hardware))
+        .route("/schedule", get(get_schedule))
+        .route("/whoami", get(whoami))
+        .route("/cgroups/members", get(cgroup_members))
+        .route("/impact", get(get_impact))
+        .route("/earnings/projection", get(earnings_projection))
+        .route("/session/reset", post(reset_session))
+        .route("/missions/:id/start", post(start_mission))
+        .route("/missions/:id/dataset", post(set_dataset_status))
+        .route("/missions/:id/stats", get(mission_stats));
+
+    // Streaming endpoints send small frames or hold the request open, so
+    // they are merged in after the compression layer.
+    compressed(routes, config)
+        .route("/telemetry/ws", get(telemetry_ws))
+        .route("/runtime/watch", get(watch_runtime))
+}
+
+/// Wraps `routes` in response compression when `DIG_COMPRESSION` is on.
+fn compressed(routes: Router<Arc<AppState>>, config: &Config) -> Router<Arc<AppState>> {
+    if config.compression {
+        routes.layer(CompressionLayer::new())
+    } else {
+        routes
+    }
+}
+
+/// Replaces axum's empty 405 with the error envelope, naming the allowed
+/// methods and keeping the `Allow` header.
+async fn method_not_allowed(response: Response) -> Response {
+    if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+        return response;
+    }
+    let allow = response.headers().get(header::ALLOW).cloned();
+    let allowed = allow
+        .as_ref()
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let mut envelope =
+        ApiError::method_not_allowed(format!("method not allowed; allowed: {allowed}"))
+            .into_response();
+    if let Some(allow) = allow {
+        envelope.headers_mut().insert(header::ALLOW, allow);
+    }
+    envelope
+}
+
+/// What an operator hitting the base URL sees: the daemon, its version and
+/// every endpoint. With `DIG_ROOT_INDEX=false` it 404s like any unknown
+/// path.
+async fn root_index(State(state): State<Arc<AppState>>) -> axum::response::Response {
+    let config = state.config();
+    if !config.root_index {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    Json(RootIndex {
+        service: "dig-rust-daemon",
+        version: env!("CARGO_PKG_VERSION"),
+        node_id: config.node_id.clone(),
+        endpoints: ENDPOINTS
+            .into_iter()
+            .map(|(method, path)| EndpointEntry { method, path })
+            .collect(),
+    })
+    .into_response()
+}
+
+async fn list_versions() -> Json<ApiVersions> {
+    Json(ApiVersions {
+        versions: API_VERSIONS
+            .into_iter()
+            .map(|version| ApiVersion {
+                version,
+                base_path: format!("/api/{version}"),
+            })
+            .collect(),
+        latest: API_VERSIONS[API_VERSIONS.len() - 1],
+    })
 }
 
 async fn health() -> impl IntoResponse {
@@ -33,98 +366,1606 @@ async fn health() -> impl IntoResponse {
     }))
 }
 
-async fn get_telemetry(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+async fn health_detail(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let gpu_tool_present = probe_gpu_tool(&state).await;
+    let cgroup = cgroups::probe();
+
+    let lock = state.runtime.read().await;
+    Json(health::detail(
+        &lock,
+        &state.config(),
+        cgroup,
+        gpu_tool_present,
+        state.errors.counts(),
+    ))
+}
+
+/// Runs the GPU tool probe off the async runtime.
+async fn probe_gpu_tool(state: &AppState) -> bool {
+    let config = state.config();
+    tokio::task::spawn_blocking(move || telemetry::gpu_tool_present(&config))
+        .await
+        .unwrap_or(false)
+}
+
+/// Everything support needs in one verbose blob: config, runtime, recent
+/// events and telemetry, health, cgroup read-back and hardware detection.
+async fn debug_dump(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let config = state.config();
+    let hardware = hardware_info(&state).await;
+    let gpu_tool_present = hardware.gpu_tool_present;
+    let cgroup = hardware.cgroup.clone();
+
     let lock = state.runtime.read().await;
-    Json(lock.telemetry.clone())
+    let domain = state.mission_domain(lock.active_mission.as_deref());
+    let groups = cgroups::plan_groups(&lock.applied_allocation(&config), &config, domain);
+    Json(DebugDump {
+        generated_at: Utc::now(),
+        daemon_version: env!("CARGO_PKG_VERSION"),
+        config: format!("{config:#?}"),
+        runtime: runtime_response(&state, &lock),
+        throttled_from: lock.throttled_from,
+        source_preference: lock.source_preference,
+        stats: stats_response(&state, &lock),
+        telemetry: lock.telemetry.clone(),
+        events: lock.events.recent(usize::MAX),
+        health: health::detail(
+            &lock,
+            &config,
+            cgroup,
+            gpu_tool_present,
+            state.errors.counts(),
+        ),
+        cgroups: cgroups::read_back(&groups),
+        hardware,
+    })
+}
+
+async fn cgroup_members(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let config = state.config();
+    let groups = {
+        let lock = state.runtime.read().await;
+        let domain = state.mission_domain(lock.active_mission.as_deref());
+        cgroups::plan_groups(&lock.applied_allocation(&config), &config, domain)
+    };
+    let members = tokio::task::spawn_blocking(move || cgroups::members(&groups))
+        .await
+        .unwrap_or_default();
+    Json(members)
+}
+
+/// What the caller's key allows, so a client can check its credentials
+/// without attempting a change. Without configured keys every request is
+/// unauthenticated and unrestricted.
+async fn whoami(
+    State(state): State<Arc<AppState>>,
+    Extension(role): Extension<Role>,
+) -> impl IntoResponse {
+    let config = state.config();
+    let auth_enabled = config.api_admin_key.is_some() || config.api_read_key.is_some();
+    let capabilities = match role {
+        Role::Admin => vec![Capability::Read, Capability::Control],
+        Role::ReadOnly => vec![Capability::Read],
+    };
+    Json(WhoAmI {
+        auth_enabled,
+        // Unknown keys never get this far.
+        authenticated: auth_enabled,
+        access: match role {
+            _ if !auth_enabled => "unrestricted",
+            Role::Admin => "admin",
+            Role::ReadOnly => "read_only",
+        },
+        capabilities,
+    })
+}
+
+/// The configured windows, the one in effect and when the scheduled mode
+/// next changes, all in local time.
+async fn get_schedule(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let config = state.config();
+    let now = Local::now();
+    Json(ScheduleResponse {
+        now,
+        active: schedule::active(&config.mode_schedule, now).cloned(),
+        next_transition: schedule::next_transition(&config.mode_schedule, now),
+        windows: config.mode_schedule.clone(),
+    })
+}
+
+async fn get_hardware(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(hardware_info(&state).await)
+}
+
+/// What was detected about the host, probed fresh except for the GPU
+/// slowdown temperature, which is only read at startup.
+async fn hardware_info(state: &AppState) -> HardwareInfo {
+    let config = state.config();
+    let gpu_tool_present = probe_gpu_tool(state).await;
+    let (cpus, gpu_vendors) =
+        tokio::task::spawn_blocking(|| (cgroups::online_cpus(), gpu_vendor::detect()))
+            .await
+            .unwrap_or_default();
+    let usable = cfg!(feature = "nvml") || gpu_tool_present;
+    HardwareInfo {
+        gpu_tool_warning: config
+            .gpu_sources
+            .first()
+            .is_some_and(|source| source.nvidia())
+            .then(|| gpu_vendor::tool_mismatch(&gpu_vendors, &config.gpu_smi_cmd, usable))
+            .flatten(),
+        gpu_vendors,
+        node_id: config.node_id.clone(),
+        online_cpus: cpus,
+        cgroup: cgroups::probe(),
+        gpu_smi_cmd: config.gpu_smi_cmd.clone(),
+        gpu_tool_present,
+        nvml: cfg!(feature = "nvml"),
+        gpu_slowdown_c: config.gpu_slowdown_c,
+        thermal_limit_c: config.thermal_limit_c,
+        thermal_limit_source: config.thermal_limit_source,
+    }
+}
+
+/// The full telemetry response, or with `?fields=a,b` only those keys.
+async fn get_telemetry(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<TelemetryQuery>,
+) -> Result<axum::response::Response, ApiError> {
+    let lock = state.runtime.read().await;
+    let response = telemetry_response(&state, &lock);
+    drop(lock);
+    let Some(fields) = query.fields else {
+        return Ok(Json(response).into_response());
+    };
+
+    // Round-trip through text: `to_value` widens f32 to f64 and would
+    // print 42.49 as 42.4900016784668.
+    let all = serde_json::to_string(&response)
+        .and_then(|text| serde_json::from_str(&text))
+        .map_err(|error| ApiError::internal(format!("telemetry encoding failed: {error}")))?;
+    let serde_json::Value::Object(mut all) = all else {
+        return Err(ApiError::internal("telemetry is not an object".to_string()));
+    };
+
+    let mut selected = serde_json::Map::new();
+    for field in fields.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+        let value = all
+            .remove(field)
+            .ok_or_else(|| ApiError::bad_request(format!("unknown telemetry field: {field}")))?;
+        selected.insert(field.to_string(), value);
+    }
+    Ok(Json(serde_json::Value::Object(selected)).into_response())
+}
+
+/// The buffered snapshots nearest `from` and `to` and how every reading
+/// moved between them. Both times must fall within the history.
+async fn compare_telemetry(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<CompareQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    if query.from > query.to {
+        return Err(ApiError::bad_request(format!(
+            "from ({}) is after to ({})",
+            query.from, query.to
+        )));
+    }
+    let config = state.config();
+    let lock = state.runtime.read().await;
+    let outside = |at: DateTime<Utc>| {
+        let span = match (
+            lock.telemetry_history.front(),
+            lock.telemetry_history.back(),
+        ) {
+            (Some(first), Some(last)) => format!("{} to {}", first.timestamp, last.timestamp),
+            _ => "empty".to_string(),
+        };
+        ApiError::bad_request(format!(
+            "{at} is outside the telemetry history (buffered: {span})"
+        ))
+    };
+    let unit = config.temperature_unit;
+    let places = config.telemetry_precision.temps;
+    let from = lock
+        .snapshot_near(query.from)
+        .ok_or_else(|| outside(query.from))?
+        .in_unit(unit, places);
+    let to = lock
+        .snapshot_near(query.to)
+        .ok_or_else(|| outside(query.to))?
+        .in_unit(unit, places);
+    drop(lock);
+    Ok(Json(TelemetryComparison {
+        elapsed_secs: (to.timestamp - from.timestamp).num_milliseconds() as f64 / 1000.0,
+        delta: TelemetryDelta::between(&from, &to, config.telemetry_precision),
+        temperature_unit: unit,
+        from,
+        to,
+    }))
+}
+
+async fn refresh_telemetry(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let (mode, preference) = {
+        let lock = state.runtime.read().await;
+        (lock.mode, lock.source_preference)
+    };
+    let snapshot = state
+        .collect_telemetry(mode, preference)
+        .await
+        .map_err(|error| ApiError::internal(format!("telemetry collection failed: {error}")))?;
+
+    let mut lock = state.runtime.write().await;
+    state.store_telemetry(&mut lock, snapshot);
+    Ok(Json(telemetry_response(&state, &lock)))
+}
+
+/// Without `include` every message is a bare telemetry frame; with
+/// `?include=telemetry,events` messages carry a `type` of `telemetry` or
+/// `event`.
+async fn telemetry_ws(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<StreamQuery>,
+    ws: WebSocketUpgrade,
+) -> Result<axum::response::Response, ApiError> {
+    let include = query
+        .include
+        .as_deref()
+        .map(StreamInclude::parse)
+        .transpose()?;
+    let Some(slot) = state.try_stream_slot() else {
+        return Ok(too_many_streams());
+    };
+    Ok(ws.on_upgrade(move |socket| async move {
+        stream_telemetry(Arc::clone(&state), socket, include).await;
+        drop(slot);
+        // The idle time starts from the disconnect, not the upgrade.
+        state.touch_ui_contact();
+    }))
+}
+
+/// 503 for a stream over `max_stream_subscribers`, with a `Retry-After`.
+fn too_many_streams() -> Response {
+    ApiError::unavailable("too many streaming clients; retry later".to_string())
+        .retry_after(STREAM_RETRY_AFTER_SECS)
+        .into_response()
+}
+
+/// Sends the current telemetry, then one frame per stored snapshot. The
+/// watch channel only keeps the newest sequence number, so while a slow
+/// client's send is pending intermediate snapshots are coalesced away and
+/// reported in `dropped` on the next frame. Events are sent as they are
+/// logged; ones missed by a client too far behind are counted the same way.
+/// The socket is read throughout, so pings are answered and a close or
+/// dropped connection ends the stream even when nothing is being sent.
+/// Every frame the client sends counts as UI contact; the stream pings it
+/// often enough that a live one keeps `ui_contact_timeout_secs` at bay.
+async fn stream_telemetry(
+    state: Arc<AppState>,
+    mut socket: WebSocket,
+    include: Option<StreamInclude>,
+) {
+    let tagged = include.is_some();
+    let include = include.unwrap_or(StreamInclude {
+        telemetry: true,
+        events: false,
+    });
+    let mut updates = state.subscribe_telemetry();
+    let mut last_seq = *updates.borrow_and_update();
+    let mut events = state.runtime.read().await.events.subscribe();
+    let mut dropped = 0;
+    let mut events_dropped = 0;
+    let mut telemetry_due = include.telemetry;
+    let ping_every = stream_ping_interval(&state.config());
+    let mut pings = tokio::time::interval(ping_every);
+    pings.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut heard_at = Instant::now();
+    loop {
+        if telemetry_due {
+            let frame = {
+                let lock = state.runtime.read().await;
+                TelemetryFrame {
+                    telemetry: telemetry_response(&state, &lock),
+                    dropped,
+                }
+            };
+            let sent = if tagged {
+                send_json(&mut socket, &StreamMessage::Telemetry(Box::new(frame))).await
+            } else {
+                send_json(&mut socket, &frame).await
+            };
+            if !sent {
+                break;
+            }
+            telemetry_due = false;
+        }
+
+        tokio::select! {
+            changed = updates.changed(), if include.telemetry => {
+                if changed.is_err() {
+                    break;
+                }
+                let seq = *updates.borrow_and_update();
+                dropped = seq.saturating_sub(last_seq + 1);
+                last_seq = seq;
+                telemetry_due = true;
+            }
+            received = events.recv(), if include.events => match received {
+                Ok(event) => {
+                    let message = StreamMessage::Event {
+                        event,
+                        dropped: events_dropped,
+                    };
+                    if !send_json(&mut socket, &message).await {
+                        break;
+                    }
+                    events_dropped = 0;
+                }
+                Err(RecvError::Lagged(missed)) => events_dropped += missed,
+                Err(RecvError::Closed) => break,
+            },
+            // Reading is what answers pings and close requests: a close is
+            // acknowledged on the next read, which then ends the stream.
+            // Other client messages are ignored.
+            received = socket.recv() => match received {
+                Some(Ok(_)) => {
+                    heard_at = Instant::now();
+                    state.touch_ui_contact();
+                }
+                Some(Err(_)) | None => break,
+            },
+            _ = pings.tick() => {
+                if heard_at.elapsed() >= ping_every * STREAM_PINGS_UNANSWERED {
+                    break;
+                }
+                if socket.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// `STREAM_PING_INTERVAL`, or a third of `ui_contact_timeout_secs` when
+/// that's shorter, so a live client's pongs land well inside the timeout.
+fn stream_ping_interval(config: &Config) -> Duration {
+    match config.ui_contact_timeout_secs {
+        0 => STREAM_PING_INTERVAL,
+        timeout => STREAM_PING_INTERVAL.min(Duration::from_secs(timeout) / 3),
+    }
+    .max(Duration::from_secs(1))
+}
+
+async fn send_json(socket: &mut WebSocket, message: &impl Serialize) -> bool {
+    match serde_json::to_string(message) {
+        Ok(text) => socket.send(Message::Text(text)).await.is_ok(),
+        Err(_) => false,
+    }
+}
+
+async fn get_telemetry_source(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(telemetry_source_response(&state).await)
+}
+
+async fn set_telemetry_source(
+    State(state): State<Arc<AppState>>,
+    ApiJson(payload): ApiJson<TelemetrySourceRequest>,
+) -> impl IntoResponse {
+    state.runtime.write().await.source_preference = payload.source;
+    Json(telemetry_source_response(&state).await)
+}
+
+async fn telemetry_source_response(state: &AppState) -> TelemetrySourceResponse {
+    let gpu_tool_present = probe_gpu_tool(state).await;
+    let mut available = vec![TelemetrySource::Synthetic];
+    if gpu_tool_present {
+        available.push(TelemetrySource::RealNvidia);
+    }
+
+    let lock = state.runtime.read().await;
+    TelemetrySourceResponse {
+        selected: lock.source_preference,
+        current: lock.telemetry.source,
+        available,
+    }
+}
+
+fn telemetry_response(state: &AppState, runtime: &RuntimeState) -> TelemetryResponse {
+    let config = state.config();
+    let unit = config.temperature_unit;
+    let cooldown_until = runtime
+        .cooldown
+        .until()
+        .filter(|_| runtime.cooldown.active(Utc::now()));
+    let mut snapshot = runtime
+        .telemetry
+        .in_unit(unit, config.telemetry_precision.temps);
+    snapshot.data_quality = snapshot.quality_at(Utc::now(), runtime.stale_after_ms());
+    TelemetryResponse {
+        snapshot,
+        temperature_unit: unit,
+        cooldown_until,
+    }
 }
 
 async fn get_runtime(State(state): State<Arc<AppState>>) -> impl IntoResponse {
     let lock = state.runtime.read().await;
-    Json(RuntimeResponse {
-        mode: lock.mode,
-        allocation: lock.allocation.clone(),
-        active_mission: lock.active_mission.clone(),
+    Json(runtime_response(&state, &lock))
+}
+
+/// Long-poll: answers as soon as the runtime version differs from `since`,
+/// or with 304 after `WATCH_TIMEOUT` if nothing changed.
+async fn watch_runtime(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<WatchQuery>,
+) -> axum::response::Response {
+    let Some(_slot) = state.try_stream_slot() else {
+        return too_many_streams();
+    };
+    let _contact = state.watch_contact();
+    let mut versions = state.subscribe_runtime();
+    let changed = matches!(
+        tokio::time::timeout(
+            WATCH_TIMEOUT,
+            versions.wait_for(|version| Some(*version) != query.since),
+        )
+        .await,
+        Ok(Ok(_))
+    );
+
+    if !changed {
+        return StatusCode::NOT_MODIFIED.into_response();
+    }
+    let lock = state.runtime.read().await;
+    Json(runtime_response(&state, &lock)).into_response()
+}
+
+fn runtime_response(state: &AppState, runtime: &RuntimeState) -> RuntimeResponse {
+    let config = state.config();
+    RuntimeResponse {
+        version: runtime.version,
+        mode: runtime.mode,
+        mode_dwell_secs: runtime.mode_dwell_secs(),
+        desired_mode: runtime.throttled_from,
+        control: runtime.control.name(),
+        control_set_at: runtime.control_set_at,
+        intensity: runtime.control.intensity(),
+        allocation: runtime.applied_allocation(&config),
+        active_mission: runtime.active_mission.clone(),
+        focus_boost: runtime
+            .focus_boost_applies()
+            .then_some(config.focus_worker_boost),
+        session_xp: runtime.session_xp,
+    }
+}
+
+async fn set_mode(
+    State(state): State<Arc<AppState>>,
+    ApiJson(payload): ApiJson<ModeRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let config = state.config();
+    if !config.allowed_api_modes.contains(&payload.mode) {
+        return Err(ApiError::forbidden(format!(
+            "mode {:?} is not allowed via the API",
+            payload.mode
+        )));
+    }
+    let mut lock = state.runtime.write().await;
+    let now = Utc::now();
+    if let Some(wait) = lock.api_mode_change_wait(&config, now) {
+        return Err(ApiError::too_many_requests(format!(
+            "mode changed less than {}ms ago; retry in {}ms",
+            config.min_mode_change_interval_ms,
+            wait.as_millis()
+        ))
+        .retry_after(wait.as_secs_f64().ceil() as u64));
+    }
+
+    // An explicit mission wins; otherwise the mode's configured default
+    // (if any) replaces the current one.
+    let mission = match payload.active_mission.as_deref() {
+        Some(id) => Some(Some(startable_mission(&state, &lock, id)?.id.clone())),
+        None => config
+            .mode_default_missions
+            .get(&payload.mode)
+            .cloned()
+            .filter(|default| match default {
+                Some(id) if missions::find(&state.missions, id).is_none() => {
+                    warn!(
+                        "default mission {id} for {:?} is not in the catalog",
+                        payload.mode
+                    );
+                    false
+                }
+                _ => true,
+            }),
+    };
+    if let Some(mission) = mission {
+        if let Some(id) = &mission {
+            lock.failed_missions.remove(id);
+        }
+        if mission != lock.active_mission {
+            lock.focused_mission = None;
+        }
+        lock.active_mission = mission;
+    }
+
+    // A deferred mode becomes the one to restore once the throttle lifts.
+    let throttled = lock.throttled_from.is_some();
+    if scheduler::defers_mode_change(throttled, lock.mode, payload.mode, &config) {
+        info!(
+            "mode {:?} deferred until the thermal throttle lifts",
+            payload.mode
+        );
+        lock.throttled_from = Some(payload.mode);
+        lock.set_control(AllocationControl::Mode);
+        lock.allocation = lock.target_allocation(&config);
+        if let Err(error) = state.apply_allocation(&mut lock) {
+            warn!("cgroup allocation failed: {error}");
+        }
+        lock.api_mode_changed_at = Some(now);
+        state.mark_runtime_changed(&mut lock);
+        return Ok((StatusCode::ACCEPTED, Json(runtime_response(&state, &lock))));
+    }
+
+    let previous_mode = lock.mode;
+    lock.enter_mode(payload.mode);
+    lock.throttled_from = None;
+    lock.settle_ticks_left = config.mode_settle_ticks;
+    lock.set_control(AllocationControl::Mode);
+    lock.allocation = lock.target_allocation(&config);
+    if let Err(error) = state.apply_allocation(&mut lock) {
+        warn!("cgroup allocation failed: {error}");
+    }
+    lock.api_mode_changed_at = Some(now);
+    state.mark_runtime_changed(&mut lock);
+    if previous_mode != payload.mode {
+        webhook::notify_mode_change(
+            &config,
+            ModeChange {
+                timestamp: now,
+                old_mode: previous_mode,
+                new_mode: payload.mode,
+                reason: ModeChangeReason::Api,
+                telemetry: lock.telemetry.clone(),
+            },
+        );
+    }
+
+    Ok((StatusCode::OK, Json(runtime_response(&state, &lock))))
+}
+
+/// Sets the intensity dial, which takes over the allocation from the mode
+/// until the next explicit mode change. The mode itself is left alone.
+async fn set_intensity(
+    State(state): State<Arc<AppState>>,
+    ApiJson(payload): ApiJson<IntensityRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    if !(0.0..=1.0).contains(&payload.value) {
+        return Err(ApiError::bad_request(format!(
+            "intensity must be between 0.0 and 1.0, got {}",
+            payload.value
+        )));
+    }
+    let config = state.config();
+    let mut lock = state.runtime.write().await;
+    lock.set_control(AllocationControl::Intensity(payload.value));
+    lock.allocation = lock.target_allocation(&config);
+    if let Err(error) = state.apply_allocation(&mut lock) {
+        warn!("cgroup allocation failed: {error}");
+    }
+    state.mark_runtime_changed(&mut lock);
+    Ok(Json(runtime_response(&state, &lock)))
+}
+
+/// Applies a client-supplied allocation as is, with profile `custom`,
+/// until the next mode or intensity change. Thermal and memory guards still
+/// cut it down. Implausible allocations are only logged unless
+/// `allocation_strict` is set.
+async fn set_allocation(
+    State(state): State<Arc<AppState>>,
+    ApiJson(mut allocation): ApiJson<Allocation>,
+) -> Result<impl IntoResponse, ApiError> {
+    let config = state.config();
+    allocation.profile = "custom".to_string();
+    let warnings = allocation
+        .validate_custom(&config)
+        .map_err(ApiError::bad_request)?;
+    for warning in warnings {
+        if config.allocation_strict {
+            return Err(ApiError::bad_request(format!(
+                "implausible allocation {warning}"
+            )));
+        }
+        warn!("implausible allocation {warning}");
+    }
+    let mut lock = state.runtime.write().await;
+    lock.custom_allocation = Some(allocation);
+    lock.set_control(AllocationControl::Custom);
+    lock.allocation = lock.target_allocation(&config);
+    if let Err(error) = state.apply_allocation(&mut lock) {
+        warn!("cgroup allocation failed: {error}");
+    }
+    state.mark_runtime_changed(&mut lock);
+    Ok(Json(runtime_response(&state, &lock)))
+}
+
+/// What applying a mode's or a custom allocation would write to each
+/// cgroup file for the active mission, without writing anything. The
+/// thermal and memory guards are left out, as `preview_allocation` does.
+async fn simulate_allocation(
+    State(state): State<Arc<AppState>>,
+    ApiJson(payload): ApiJson<SimulateRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let config = state.config();
+    let (allocation, warnings) = match (payload.mode, payload.allocation) {
+        (Some(mode), None) => (allocation_for_mode(mode, &config), Vec::new()),
+        (None, Some(mut allocation)) => {
+            allocation.profile = "custom".to_string();
+            let warnings = allocation
+                .validate_custom(&config)
+                .map_err(ApiError::bad_request)?;
+            (allocation, warnings)
+        }
+        _ => {
+            return Err(ApiError::bad_request(
+                "expected exactly one of mode or allocation".to_string(),
+            ))
+        }
+    };
+    let groups = {
+        let lock = state.runtime.read().await;
+        let domain = state.mission_domain(lock.active_mission.as_deref());
+        cgroups::plan_groups(&allocation, &config, domain)
+    };
+    let cpus = tokio::task::spawn_blocking(cgroups::online_cpus)
+        .await
+        .unwrap_or(1);
+    Ok(Json(SimulationResponse {
+        groups: cgroups::planned_writes(&groups, &config, cpus),
+        allocation,
+        warnings,
+        online_cpus: cpus,
+    }))
+}
+
+/// Every mode with display metadata and its allocation under the current
+/// config, so clients can build a mode selector without hardcoding modes.
+async fn list_modes(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let config = state.config();
+    let modes: Vec<ModeDescription> = PerformanceMode::ALL
+        .into_iter()
+        .map(|mode| ModeDescription {
+            mode,
+            label: mode.label(),
+            description: mode.description(),
+            allocation: allocation_for_mode(mode, &config),
+            api_allowed: config.allowed_api_modes.contains(&mode),
+        })
+        .collect();
+    Json(modes)
+}
+
+/// Advisory only; never changes the mode.
+async fn recommend(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let lock = state.runtime.read().await;
+    Json(recommend_mode(
+        &lock.telemetry,
+        &state.config(),
+        state.mission_domain(lock.active_mission.as_deref()),
+    ))
+}
+
+/// What `mode` would allocate, without applying it.
+async fn preview_allocation(
+    State(state): State<Arc<AppState>>,
+    Path(mode): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mode: PerformanceMode = mode
+        .parse()
+        .map_err(|_| ApiError::bad_request(format!("unknown mode: {mode}")))?;
+    Ok(Json(allocation_for_mode(mode, &state.config())))
+}
+
+async fn list_events(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<EventsQuery>,
+) -> impl IntoResponse {
+    let lock = state.runtime.read().await;
+    Json(lock.events.recent(query.limit.unwrap_or(50)))
+}
+
+/// Records a liveness ping from the external worker process. A lost
+/// worker is picked up as recovered on the next worker tick.
+async fn worker_heartbeat(
+    State(state): State<Arc<AppState>>,
+    ApiJson(payload): ApiJson<HeartbeatRequest>,
+) -> impl IntoResponse {
+    let heartbeat = WorkerHeartbeat {
+        pid: payload.pid,
+        last_seen: Utc::now(),
+    };
+    state.runtime.write().await.worker_heartbeat = Some(heartbeat);
+    Json(heartbeat)
+}
+
+async fn get_progress(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let config = state.config();
+    let lock = state.runtime.read().await;
+    Json(ProgressResponse {
         session_xp: lock.session_xp,
+        xp_last_minute: lock.xp_limiter.last_minute(),
+        xp_cap_per_minute: (config.xp_cap_per_minute > 0).then_some(config.xp_cap_per_minute),
+        xp_capped: lock.xp_limiter.capped(),
     })
 }
 
-async fn set_mode(
+async fn get_stats(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let lock = state.runtime.read().await;
+    Json(stats_response(&state, &lock))
+}
+
+/// Session impact per mission domain, largest first.
+async fn get_impact(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let lock = state.runtime.read().await;
+    let mut leaderboard: Vec<DomainImpact> = lock
+        .session_impact
+        .iter()
+        .map(|(domain, impact)| DomainImpact {
+            domain: domain.clone(),
+            impact: *impact as f32,
+        })
+        .collect();
+    leaderboard.sort_by(|a, b| b.impact.total_cmp(&a.impact));
+    Json(ImpactResponse {
+        leaderboard,
+        min_impact_for_credit: state.config().min_impact_for_credit,
+    })
+}
+
+/// Extrapolates the smoothed earnings rate over a few horizons, assuming
+/// the current mode and load hold.
+async fn earnings_projection(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let rate = state.config().dig_to_usd_rate;
+    let lock = state.runtime.read().await;
+    let per_sec = lock.telemetry.earnings_per_sec_smoothed;
+    let horizons = PROJECTION_HORIZONS
+        .iter()
+        .map(|&(label, seconds)| {
+            let dig = per_sec * seconds as f32;
+            ProjectionHorizon {
+                label,
+                seconds,
+                dig,
+                usd: rate.map(|rate| dig * rate),
+            }
+        })
+        .collect();
+    Json(EarningsProjection {
+        mode: lock.mode,
+        earnings_per_sec: per_sec,
+        dig_to_usd_rate: rate,
+        horizons,
+    })
+}
+
+async fn reset_session(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<ModeRequest>,
+    Query(query): Query<ResetSessionQuery>,
 ) -> impl IntoResponse {
     let mut lock = state.runtime.write().await;
-    let allocation = allocation_for_mode(payload.mode, &state.config);
-    if let Err(error) = cgroups::apply_allocation(&allocation) {
-        warn!("cgroup allocation failed: {error}");
+    lock.reset_session(query.clear_history);
+    Json(stats_response(&state, &lock))
+}
+
+fn stats_response(state: &AppState, runtime: &RuntimeState) -> StatsResponse {
+    let earnings = runtime.session_earnings_dig as f32;
+    StatsResponse {
+        mode: runtime.mode,
+        mode_dwell_secs: runtime.mode_dwell_secs(),
+        active_mission: runtime.active_mission.clone(),
+        session_xp: runtime.session_xp,
+        session_earnings_dig: earnings,
+        session_earnings_usd: state.config().dig_to_usd_rate.map(|rate| earnings * rate),
+        completed_missions: runtime.completed_missions.iter().cloned().collect(),
+        failed_missions: runtime.failed_missions.iter().cloned().collect(),
+        balance_suggestion: runtime.balance_suggestion.clone(),
+        active_mission_progress_percent: runtime
+            .active_mission
+            .as_deref()
+            .and_then(|id| state.mission_progress(runtime, id)),
+        contribution_momentum: runtime.contribution_momentum,
+        poll_interval_ms: runtime.poll_interval_ms,
+        idle_since: runtime.idle_since,
+        stream_subscribers: state.stream_subscribers(),
+        telemetry_history: HistoryUsage {
+            snapshots: runtime.telemetry_history.len(),
+            approx_bytes: runtime.telemetry_history_bytes,
+        },
+        lifetime: state.lifetime.current(),
+    }
+}
+
+/// The catalog, optionally filtered with repeated `?tag=` parameters that
+/// must all match with `match=all` (default `any`).
+/// Tagged with an ETag over the listing as served, so the dynamic
+/// `dataset_status` and `recommended` fields change it too; a matching
+/// `If-None-Match` gets 304 without a body.
+async fn list_missions(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+) -> Result<axum::response::Response, ApiError> {
+    let mut tags = Vec::new();
+    let mut mode = TagMatch::Any;
+    for (key, value) in params {
+        match key.as_str() {
+            "tag" => tags.push(value),
+            "match" => {
+                mode = match value.as_str() {
+                    "any" => TagMatch::Any,
+                    "all" => TagMatch::All,
+                    _ => {
+                        return Err(ApiError::bad_request(format!(
+                            "invalid match: {value} (expected any or all)"
+                        )))
+                    }
+                }
+            }
+            _ => {}
+        }
     }
 
-    lock.mode = payload.mode;
-    lock.allocation = allocation.clone();
+    let config = state.config();
+    let lock = state.runtime.read().await;
+    let listings: Vec<MissionListing> = state
+        .missions
+        .iter()
+        .filter(|mission| missions::matches_tags(mission, &tags, mode))
+        .map(|mission| MissionListing {
+            mission: mission.clone(),
+            dataset_status: state.dataset_status(&lock, mission),
+            recommended: mission_recommended(mission, &lock.telemetry, &config),
+        })
+        .collect();
+    drop(lock);
 
-    (
-        StatusCode::OK,
-        Json(RuntimeResponse {
-            mode: lock.mode,
-            allocation,
-            active_mission: lock.active_mission.clone(),
-            session_xp: lock.session_xp,
-        }),
+    let body = serde_json::to_string(&listings)
+        .map_err(|error| ApiError::internal(format!("encoding missions failed: {error}")))?;
+    let etag = body_etag(&body);
+    let etag_header = (header::ETAG, etag.clone());
+    if etag_matches(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, [etag_header]).into_response());
+    }
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            etag_header,
+        ],
+        body,
     )
+        .into_response())
 }
 
-async fn list_missions() -> impl IntoResponse {
-    Json(vec![
-        Mission {
-            id: "med-pancreas-001".to_string(),
-            title: "Pancreatic Cancer Detection".to_string(),
-            bounty_dig: 500.0,
-            dataset_gb: 4.2,
-            eta_minutes: 12,
-            priority: 100,
-            domain: "medical".to_string(),
-        },
-        Mission {
-            id: "space-exoplanet-004".to_string(),
-            title: "Exoplanet Atmosphere Analysis".to_string(),
-            bounty_dig: 120.0,
-            dataset_gb: 2.1,
-            eta_minutes: 7,
-            priority: 55,
-            domain: "space".to_string(),
-        },
-        Mission {
-            id: "render-cyberpunk-2099".to_string(),
-            title: "Render Cyberpunk 2099 Frame".to_string(),
-            bounty_dig: 50.0,
-            dataset_gb: 1.4,
-            eta_minutes: 4,
-            priority: 20,
-            domain: "render".to_string(),
-        },
-    ])
+/// Strong ETag for a response body. `DefaultHasher::new` is keyed the same
+/// every time, so equal bodies get equal tags across requests.
+fn body_etag(body: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// Whether `If-None-Match` lists `etag` (weak or not) or is `*`.
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+async fn set_dataset_status(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    ApiJson(payload): ApiJson<DatasetStatusRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mission = missions::find(&state.missions, &id)
+        .ok_or_else(|| ApiError::not_found(format!("unknown mission: {id}")))?;
+    let mut lock = state.runtime.write().await;
+    lock.dataset_status
+        .insert(mission.id.clone(), payload.status);
+    Ok(Json(MissionListing {
+        mission: mission.clone(),
+        dataset_status: payload.status,
+        recommended: mission_recommended(mission, &lock.telemetry, &state.config()),
+    }))
+}
+
+/// Starts a mission; `?focus=true` also boosts the worker shares by
+/// `focus_worker_boost` for as long as it stays the active mission.
+async fn start_mission(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<StartMissionQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut lock = state.runtime.write().await;
+    let mission = startable_mission(&state, &lock, &id)?;
+
+    lock.failed_missions.remove(&mission.id);
+    lock.active_mission = Some(mission.id.clone());
+    lock.focused_mission = query.focus.then(|| mission.id.clone());
+    let config = state.config();
+    lock.allocation = lock.target_allocation(&config);
+    if let Err(error) = state.apply_allocation(&mut lock) {
+        warn!("cgroup allocation failed: {error}");
+    }
+    state.mark_runtime_changed(&mut lock);
+    Ok(Json(runtime_response(&state, &lock)))
+}
+
+async fn mission_stats(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let lock = state.runtime.read().await;
+    let mission = missions::find(&state.missions, &id)
+        .ok_or_else(|| ApiError::not_found(format!("unknown mission: {id}")))?;
+    Ok(Json(MissionStats {
+        id: mission.id.clone(),
+        active: lock.active_mission.as_deref() == Some(mission.id.as_str()),
+        completed: lock.completed_missions.contains(&mission.id),
+        failed: lock.failed_missions.contains(&mission.id),
+        elapsed_secs: lock
+            .mission_elapsed_secs
+            .get(&mission.id)
+            .copied()
+            .unwrap_or(0.0) as u64,
+        eta_minutes: mission.eta_minutes,
+        progress_percent: state.mission_progress(&lock, &mission.id).unwrap_or(0.0),
+    }))
+}
+
+/// Looks up `id` and checks its prerequisites: 404 if unknown, 409 listing
+/// what is unmet.
+fn startable_mission<'a>(
+    state: &'a AppState,
+    runtime: &RuntimeState,
+    id: &str,
+) -> Result<&'a Mission, ApiError> {
+    let mission = missions::find(&state.missions, id)
+        .ok_or_else(|| ApiError::not_found(format!("unknown mission: {id}")))?;
+
+    let unmet = missions::unmet_prerequisites(
+        mission,
+        &runtime.completed_missions,
+        state.dataset_status(runtime, mission),
+    );
+    if !unmet.is_empty() {
+        return Err(ApiError::conflict(format!(
+            "cannot start {id}: {}",
+            unmet.join(", ")
+        )));
+    }
+    Ok(mission)
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ModeRequest {
     pub mode: PerformanceMode,
+    /// Overrides the mode's default mission from config.
+    #[serde(default)]
+    pub active_mission: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
-struct RuntimeResponse {
+struct ImpactResponse {
+    /// Highest cumulative impact first.
+    leaderboard: Vec<DomainImpact>,
+    /// Ticks scoring below this were left out of the totals.
+    min_impact_for_credit: f32,
+}
+
+#[derive(Debug, Deserialize)]
+struct StartMissionQuery {
+    #[serde(default)]
+    focus: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResetSessionQuery {
+    #[serde(default)]
+    clear_history: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct IntensityRequest {
+    value: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct WhoAmI {
+    auth_enabled: bool,
+    authenticated: bool,
+    access: &'static str,
+    capabilities: Vec<Capability>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Capability {
+    /// `GET` endpoints and streams.
+    Read,
+    /// Mode, allocation, mission and other changes.
+    Control,
+}
+
+#[derive(Debug, Serialize)]
+struct ScheduleResponse {
+    now: DateTime<Local>,
+    windows: Vec<ScheduleWindow>,
+    active: Option<ScheduleWindow>,
+    /// `None` without a schedule or when it never changes the mode.
+    next_transition: Option<Transition>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SimulateRequest {
+    mode: Option<PerformanceMode>,
+    allocation: Option<Allocation>,
+}
+
+#[derive(Debug, Serialize)]
+struct SimulationResponse {
+    allocation: Allocation,
+    /// Plausibility warnings for a custom allocation, which
+    /// `POST /api/v1/allocation` rejects with `allocation_strict`.
+    warnings: Vec<String>,
+    online_cpus: u32,
+    groups: Vec<GroupWrites>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HeartbeatRequest {
+    pid: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct DatasetStatusRequest {
+    status: DatasetStatus,
+}
+
+#[derive(Debug, Deserialize)]
+struct TelemetryQuery {
+    fields: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompareQuery {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+struct TelemetryComparison {
+    from: TelemetrySnapshot,
+    to: TelemetrySnapshot,
+    /// Between the two snapshots found, not the requested times.
+    elapsed_secs: f64,
+    delta: TelemetryDelta,
+    temperature_unit: TemperatureUnit,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamQuery {
+    include: Option<String>,
+}
+
+/// What a WebSocket client asked to receive.
+#[derive(Debug, Clone, Copy)]
+struct StreamInclude {
+    telemetry: bool,
+    events: bool,
+}
+
+impl StreamInclude {
+    fn parse(value: &str) -> Result<Self, ApiError> {
+        let mut include = Self {
+            telemetry: false,
+            events: false,
+        };
+        for item in value.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            match item {
+                "telemetry" => include.telemetry = true,
+                "events" => include.events = true,
+                other => {
+                    return Err(ApiError::bad_request(format!(
+                        "unknown stream include: {other}"
+                    )))
+                }
+            }
+        }
+        if !include.telemetry && !include.events {
+            return Err(ApiError::bad_request(
+                "include must name telemetry, events or both".to_string(),
+            ));
+        }
+        Ok(include)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct WatchQuery {
+    since: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EventsQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct ModeDescription {
     mode: PerformanceMode,
+    label: &'static str,
+    description: &'static str,
     allocation: crate::scheduler::Allocation,
+    /// Whether `POST /api/v1/mode` accepts this mode.
+    api_allowed: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct EarningsProjection {
+    /// Assumptions: the projection holds this mode and smoothed rate.
+    mode: PerformanceMode,
+    earnings_per_sec: f32,
+    dig_to_usd_rate: Option<f32>,
+    horizons: Vec<ProjectionHorizon>,
+}
+
+#[derive(Debug, Serialize)]
+struct ProjectionHorizon {
+    label: &'static str,
+    seconds: u64,
+    dig: f32,
+    usd: Option<f32>,
+}
+
+#[derive(Debug, Serialize)]
+struct DomainImpact {
+    domain: String,
+    impact: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct ProgressResponse {
+    session_xp: u64,
+    xp_last_minute: u64,
+    /// `None` when uncapped.
+    xp_cap_per_minute: Option<u64>,
+    /// True while the cap is withholding XP.
+    xp_capped: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct StatsResponse {
+    mode: PerformanceMode,
+    /// Seconds since `mode` was entered.
+    mode_dwell_secs: u64,
     active_mission: Option<String>,
     session_xp: u64,
+    session_earnings_dig: f32,
+    session_earnings_usd: Option<f32>,
+    completed_missions: Vec<String>,
+    failed_missions: Vec<String>,
+    balance_suggestion: Option<BalanceSuggestion>,
+    active_mission_progress_percent: Option<f32>,
+    contribution_momentum: f32,
+    /// Current worker poll interval, longer than configured while the idle
+    /// backoff is in effect.
+    poll_interval_ms: u64,
+    idle_since: Option<DateTime<Utc>>,
+    /// Open WebSocket and long-poll clients.
+    stream_subscribers: usize,
+    telemetry_history: HistoryUsage,
+    lifetime: LifetimeCounters,
+}
+
+/// What the buffer behind `/api/v1/telemetry/compare` holds.
+#[derive(Debug, Serialize)]
+struct HistoryUsage {
+    snapshots: usize,
+    approx_bytes: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct MissionListing {
+    #[serde(flatten)]
+    mission: Mission,
+    dataset_status: DatasetStatus,
+    /// False while the GPU is too warm for this mission's domain.
+    recommended: bool,
 }
 
 #[derive(Debug, Serialize)]
-struct Mission {
+struct MissionStats {
     id: String,
-    title: String,
-    bounty_dig: f32,
-    dataset_gb: f32,
+    active: bool,
+    completed: bool,
+    /// Dropped after stalling; see `StallAction::Fail`.
+    failed: bool,
+    /// Active time towards the current run; zero once completed.
+    elapsed_secs: u64,
     eta_minutes: u16,
-    priority: u8,
-    domain: String,
+    progress_percent: f32,
+}
+
+#[derive(Debug, Deserialize)]
+struct TelemetrySourceRequest {
+    source: SourcePreference,
+}
+
+#[derive(Debug, Serialize)]
+struct TelemetrySourceResponse {
+    selected: SourcePreference,
+    current: TelemetrySource,
+    available: Vec<TelemetrySource>,
+}
+
+#[derive(Debug, Serialize)]
+struct TelemetryResponse {
+    #[serde(flatten)]
+    snapshot: TelemetrySnapshot,
+    temperature_unit: TemperatureUnit,
+    /// Set while a forced thermal cooldown is holding the reduced allocation.
+    cooldown_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+struct RootIndex {
+    service: &'static str,
+    version: &'static str,
+    node_id: String,
+    endpoints: Vec<EndpointEntry>,
+}
+
+#[derive(Debug, Serialize)]
+struct EndpointEntry {
+    method: &'static str,
+    path: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct ApiVersions {
+    versions: Vec<ApiVersion>,
+    latest: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct ApiVersion {
+    version: &'static str,
+    base_path: String,
+}
+
+#[derive(Debug, Serialize)]
+struct TelemetryFrame {
+    #[serde(flatten)]
+    telemetry: TelemetryResponse,
+    /// Snapshots skipped since the previous frame because the client fell behind.
+    dropped: u64,
+}
+
+/// One WebSocket message when the client asked for `include`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamMessage {
+    Telemetry(Box<TelemetryFrame>),
+    Event {
+        event: Event,
+        /// Events missed since the previous one because the client fell behind.
+        dropped: u64,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct DebugDump {
+    generated_at: DateTime<Utc>,
+    daemon_version: &'static str,
+    /// Debug rendering of the live config.
+    config: String,
+    runtime: RuntimeResponse,
+    throttled_from: Option<PerformanceMode>,
+    source_preference: SourcePreference,
+    stats: StatsResponse,
+    telemetry: TelemetrySnapshot,
+    events: Vec<Event>,
+    health: HealthDetail,
+    cgroups: Vec<GroupReadback>,
+    hardware: HardwareInfo,
+}
+
+#[derive(Debug, Serialize)]
+struct HardwareInfo {
+    node_id: String,
+    online_cpus: u32,
+    cgroup: CgroupProbe,
+    gpu_smi_cmd: String,
+    gpu_tool_present: bool,
+    /// Whether this build reads the GPU through NVML.
+    nvml: bool,
+    /// The GPU's own slowdown temperature, read at startup.
+    gpu_slowdown_c: Option<f32>,
+    /// Default thermal limit in effect, and whether it came from
+    /// `DIG_THERMAL_LIMIT_C` (`config`), `gpu_slowdown_c` (`hardware`) or
+    /// neither (`default`).
+    thermal_limit_c: f32,
+    thermal_limit_source: ThermalLimitSource,
+    /// Makers of the display controllers on the PCI bus.
+    gpu_vendors: BTreeSet<GpuVendor>,
+    /// Set when `gpu_vendors` suggests the GPU tool won't deliver real
+    /// readings, with the setting to change.
+    gpu_tool_warning: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RuntimeResponse {
+    version: u64,
+    mode: PerformanceMode,
+    /// Seconds since `mode` was entered.
+    mode_dwell_secs: u64,
+    /// Mode restored once the thermal throttle lifts.
+    desired_mode: Option<PerformanceMode>,
+    /// `mode`, `intensity` or `custom`: whichever was set last and drives
+    /// `allocation`.
+    control: &'static str,
+    control_set_at: DateTime<Utc>,
+    /// The dial position while `control` is `intensity`.
+    intensity: Option<f32>,
+    allocation: crate::scheduler::Allocation,
+    active_mission: Option<String>,
+    /// Multiplier `allocation` includes for a focused mission.
+    focus_boost: Option<f32>,
+    session_xp: u64,
+}
+
+/// `Json` extractor that reports bad or oversized bodies with the standard
+/// error envelope instead of axum's plain-text rejection.
+struct ApiJson<T>(T);
+
+#[async_trait]
+impl<S, T> FromRequest<S> for ApiJson<T>
+where
+    Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state).await?;
+        Ok(Self(value))
+    }
+}
+
+struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    /// Sent as `Retry-After`, in seconds.
+    retry_after: Option<u64>,
+}
+
+impl ApiError {
+    fn bad_request(message: String) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            code: "bad_request",
+            message,
+            retry_after: None,
+        }
+    }
+
+    fn not_found(message: String) -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            code: "not_found",
+            message,
+            retry_after: None,
+        }
+    }
+
+    fn unauthorized(message: String) -> Self {
+        Self {
+            status: StatusCode::UNAUTHORIZED,
+            code: "unauthorized",
+            message,
+            retry_after: None,
+        }
+    }
+
+    fn forbidden(message: String) -> Self {
+        Self {
+            status: StatusCode::FORBIDDEN,
+            code: "forbidden",
+            message,
+            retry_after: None,
+        }
+    }
+
+    fn method_not_allowed(message: String) -> Self {
+        Self {
+            status: StatusCode::METHOD_NOT_ALLOWED,
+            code: "method_not_allowed",
+            message,
+            retry_after: None,
+        }
+    }
+
+    fn conflict(message: String) -> Self {
+        Self {
+            status: StatusCode::CONFLICT,
+            code: "conflict",
+            message,
+            retry_after: None,
+        }
+    }
+
+    fn too_many_requests(message: String) -> Self {
+        Self {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            code: "too_many_requests",
+            message,
+            retry_after: None,
+        }
+    }
+
+    fn unavailable(message: String) -> Self {
+        Self {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            code: "unavailable",
+            message,
+            retry_after: None,
+        }
+    }
+
+    fn internal(message: String) -> Self {
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            code: "internal",
+            message,
+            retry_after: None,
+        }
+    }
+
+    /// At least a second, since `Retry-After` has no finer unit.
+    fn retry_after(self, secs: u64) -> Self {
+        Self {
+            retry_after: Some(secs.max(1)),
+            ..self
+        }
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        let status = rejection.status();
+        Self {
+            status,
+            code: if status == StatusCode::PAYLOAD_TOO_LARGE {
+                "payload_too_large"
+            } else {
+                "invalid_body"
+            },
+            message: rejection.body_text(),
+            retry_after: None,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (
+            self.status,
+            Json(serde_json::json!({
+                "error": {
+                    "code": self.code,
+                    "message": self.message,
+                }
+            })),
+        )
+            .into_response();
+        if let Some(secs) = self.retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, secs.into());
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+
+    use super::*;
+    use crate::auth::ApiKey;
+
+    fn config() -> Config {
+        Config {
+            api_admin_key: ApiKey::new("admin"),
+            api_read_key: ApiKey::new("read"),
+            ..Config::default()
+        }
+    }
+
+    fn request(method: Method, uri: &str, headers: &[(HeaderName, &str)]) -> Request {
+        let mut builder = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(name, *value);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn status(result: Result<Role, ApiError>) -> StatusCode {
+        result.map_or_else(|error| error.status, |_| StatusCode::OK)
+    }
+
+    #[test]
+    fn missing_key_is_unauthorized() {
+        let result = request_role(&config(), &request(Method::GET, "/mode", &[]));
+        assert_eq!(status(result), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn read_key_cannot_change_anything() {
+        let bearer = [(header::AUTHORIZATION, "Bearer read")];
+        let config = config();
+        assert_eq!(
+            request_role(&config, &request(Method::GET, "/mode", &bearer)).ok(),
+            Some(Role::ReadOnly)
+        );
+        let post = request_role(&config, &request(Method::POST, "/mode", &bearer));
+        assert_eq!(status(post), StatusCode::FORBIDDEN);
+        let dump = request_role(&config, &request(Method::GET, "/debug/dump", &bearer));
+        assert_eq!(status(dump), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn query_key_only_counts_on_websocket_upgrades() {
+        let config = config();
+        let plain = request(Method::GET, "/telemetry/ws?api_key=admin", &[]);
+        assert_eq!(
+            status(request_role(&config, &plain)),
+            StatusCode::UNAUTHORIZED
+        );
+        let upgrade = request(
+            Method::GET,
+            "/telemetry/ws?api_key=admin",
+            &[(header::UPGRADE, "websocket")],
+        );
+        assert_eq!(request_role(&config, &upgrade).ok(), Some(Role::Admin));
+    }
+
+    #[test]
+    fn stream_pings_fit_inside_the_ui_timeout() {
+        let with_timeout = |secs| Config {
+            ui_contact_timeout_secs: secs,
+            ..Config::default()
+        };
+        assert_eq!(stream_ping_interval(&with_timeout(0)), STREAM_PING_INTERVAL);
+        assert_eq!(
+            stream_ping_interval(&with_timeout(300)),
+            STREAM_PING_INTERVAL
+        );
+        assert_eq!(
+            stream_ping_interval(&with_timeout(15)),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            stream_ping_interval(&with_timeout(1)),
+            Duration::from_secs(1)
+        );
+    }
 }
 
@@ -0,0 +1,95 @@
+use std::fmt;
+
+use serde::Serialize;
+
+/// A configured API key. `Debug` never prints it, so it stays out of logs
+/// and `/api/v1/debug/dump`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ApiKey(String);
+
+impl ApiKey {
+    /// `None` for an empty or all-whitespace value.
+    pub fn new(value: &str) -> Option<Self> {
+        let value = value.trim();
+        (!value.is_empty()).then(|| Self(value.to_string()))
+    }
+
+    /// Compares in time independent of where the first difference is.
+    fn matches(&self, presented: &str) -> bool {
+        let (expected, presented) = (self.0.as_bytes(), presented.as_bytes());
+        expected.len() == presented.len()
+            && expected
+                .iter()
+                .zip(presented)
+                .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ApiKey(<redacted>)")
+    }
+}
+
+/// What a request may do.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// `GET` endpoints and streams, except the debug dump.
+    ReadOnly,
+    /// Everything, including every change.
+    Admin,
+}
+
+/// The role `presented` grants: with no keys configured auth is off and
+/// every request is admin; otherwise `None` for a missing or unknown key.
+pub fn role_for(
+    admin_key: Option<&ApiKey>,
+    read_key: Option<&ApiKey>,
+    presented: Option<&str>,
+) -> Option<Role> {
+    if admin_key.is_none() && read_key.is_none() {
+        return Some(Role::Admin);
+    }
+    let presented = presented?;
+    if admin_key.is_some_and(|key| key.matches(presented)) {
+        Some(Role::Admin)
+    } else if read_key.is_some_and(|key| key.matches(presented)) {
+        Some(Role::ReadOnly)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(value: &str) -> ApiKey {
+        ApiKey::new(value).unwrap()
+    }
+
+    #[test]
+    fn no_keys_means_admin() {
+        assert_eq!(role_for(None, None, None), Some(Role::Admin));
+        assert_eq!(role_for(None, None, Some("anything")), Some(Role::Admin));
+    }
+
+    #[test]
+    fn keys_map_to_roles() {
+        let (admin, read) = (key("admin"), key("read"));
+        let role = |presented| role_for(Some(&admin), Some(&read), presented);
+        assert_eq!(role(Some("admin")), Some(Role::Admin));
+        assert_eq!(role(Some("read")), Some(Role::ReadOnly));
+        assert_eq!(role(Some("reader")), None);
+        assert_eq!(role(None), None);
+    }
+
+    #[test]
+    fn blank_keys_are_rejected() {
+        assert!(ApiKey::new("  ").is_none());
+        assert_eq!(key(" admin ").0, "admin");
+    }
+}
+
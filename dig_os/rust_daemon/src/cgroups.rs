@@ -1,54 +1,601 @@
-use std::{fs, path::Path};
+use std::{collections::BTreeMap, fs, path::Path, str::FromStr};
 
 use anyhow::Result;
+use serde::Serialize;
 use tracing::warn;
 
-use crate::scheduler::Allocation;
+use crate::{config::Config, counters::ErrorCounters, procs, scheduler::Allocation};
 
 const CGROUP_ROOT: &str = "/sys/fs/cgroup";
 const UI_GROUP: &str = "dig-ui";
 const WORKER_GROUP: &str = "dig-worker";
 const CGROUP_PERIOD_US: u32 = 100_000;
+/// Smallest `cpu.max` quota the kernel accepts.
+const MIN_CPU_QUOTA_US: u64 = 1_000;
+/// Smallest CPU share written; shares are kept above 0.
+const MIN_CPU_PERCENT: f32 = 0.01;
 
-pub fn apply_allocation(allocation: &Allocation) -> Result<()> {
-    #[cfg(target_os = "linux")]
-    {
-        let ui_dir = Path::new(CGROUP_ROOT).join(UI_GROUP);
-        let worker_dir = Path::new(CGROUP_ROOT).join(WORKER_GROUP);
+/// How CPU shares are enforced. `Hard` caps each group with `cpu.max`;
+/// `Soft` lifts any quota and relies on `cpu.weight` alone, so idle
+/// capacity can still be borrowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuLimitMode {
+    Hard,
+    Soft,
+}
+
+impl FromStr for CpuLimitMode {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "hard" => Ok(Self::Hard),
+            "soft" => Ok(Self::Soft),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A cgroup interface file the daemon writes. Only those listed in
+/// `cgroup_files` are touched, for hosts that delegate just some of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CgroupFile {
+    CpuMax,
+    CpuWeight,
+    CpusetCpus,
+    Procs,
+}
+
+impl CgroupFile {
+    pub const ALL: [Self; 4] = [Self::CpuMax, Self::CpuWeight, Self::CpusetCpus, Self::Procs];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::CpuMax => "cpu.max",
+            Self::CpuWeight => "cpu.weight",
+            Self::CpusetCpus => "cpuset.cpus",
+            Self::Procs => "cgroup.procs",
+        }
+    }
+}
+
+impl FromStr for CgroupFile {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|file| file.name() == value.trim())
+            .ok_or(())
+    }
+}
+
+/// Outcome of the startup apply, which is retried with backoff because
+/// cgroup delegation can lag the daemon start.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InitialApply {
+    Applied,
+    Retrying,
+    Failed,
+}
+
+/// One cgroup under `CGROUP_ROOT` and the CPU share it should receive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupLimit {
+    pub name: String,
+    pub cpu_percent: f32,
+    /// `cpuset.cpus` to write; empty resets to the parent's CPUs, `None`
+    /// leaves the file alone.
+    pub cpuset: Option<String>,
+}
+
+/// Expands an allocation into the concrete groups to write. Without a
+/// domain split this is the classic `dig-ui` + `dig-worker` pair; with one,
+/// the worker share goes to `dig-worker-<domain>` for the active mission,
+/// scaled by that domain's configured share.
+pub fn plan_groups(
+    allocation: &Allocation,
+    cfg: &Config,
+    active_domain: Option<&str>,
+) -> Vec<GroupLimit> {
+    let mut groups = vec![GroupLimit {
+        name: UI_GROUP.to_string(),
+        cpu_percent: allocation.ui_cpu_percent,
+        cpuset: None,
+    }];
+
+    let share = match active_domain.filter(|_| !cfg.cgroup_domain_shares.is_empty()) {
+        Some(domain) => cfg.cgroup_domain_shares.get(domain).copied().unwrap_or(100),
+        None => 100,
+    };
+    groups.push(GroupLimit {
+        name: worker_group(cfg, active_domain),
+        cpu_percent: allocation.worker_cpu_percent * share as f32 / 100.0,
+        // Only managed once some mode pins the worker, so a mode without a
+        // pin restores every CPU rather than keeping the last one.
+        cpuset: (!cfg.mode_worker_cpusets.is_empty())
+            .then(|| allocation.worker_cpuset.clone().unwrap_or_default()),
+    });
+    groups
+}
 
-        fs::create_dir_all(&ui_dir)?;
-        fs::create_dir_all(&worker_dir)?;
+/// The group the worker share goes to: `dig-worker`, or with a domain
+/// split `dig-worker-<domain>` for the active mission.
+pub fn worker_group(cfg: &Config, active_domain: Option<&str>) -> String {
+    match active_domain.filter(|_| !cfg.cgroup_domain_shares.is_empty()) {
+        Some(domain) => format!("{WORKER_GROUP}-{}", sanitize_group_name(domain)),
+        None => WORKER_GROUP.to_string(),
+    }
+}
+
+/// What the host offers for cgroup enforcement, probed without modifying
+/// anything.
+#[derive(Debug, Clone, Serialize)]
+pub struct CgroupProbe {
+    pub version: &'static str,
+    pub writable: bool,
+}
+
+pub fn probe() -> CgroupProbe {
+    let root = Path::new(CGROUP_ROOT);
+    let version = if !cfg!(target_os = "linux") {
+        "unsupported"
+    } else if root.join("cgroup.controllers").exists() {
+        "v2"
+    } else if root.join("cpu").exists() {
+        "v1"
+    } else {
+        "none"
+    };
+
+    // Opening for write without truncating checks permissions without
+    // touching the current limits.
+    let writable = [UI_GROUP, WORKER_GROUP].iter().all(|group| {
+        fs::OpenOptions::new()
+            .write(true)
+            .open(root.join(group).join("cpu.max"))
+            .is_ok()
+    });
+
+    CgroupProbe { version, writable }
+}
+
+/// What the kernel currently holds for one planned group. Missing or
+/// unreadable files are `None`.
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupReadback {
+    pub name: String,
+    pub expected_cpu_percent: f32,
+    pub cpu_max: Option<String>,
+    pub cpu_weight: Option<String>,
+    pub cpuset_cpus: Option<String>,
+}
+
+/// Reads `cpu.max` and `cpu.weight` back for each of `groups`, to compare
+/// what was requested with what the kernel actually kept.
+pub fn read_back(groups: &[GroupLimit]) -> Vec<GroupReadback> {
+    let read = |dir: &Path, file: &str| {
+        fs::read_to_string(dir.join(file))
+            .ok()
+            .map(|value| value.trim().to_string())
+    };
+    groups
+        .iter()
+        .map(|group| {
+            let dir = Path::new(CGROUP_ROOT).join(&group.name);
+            GroupReadback {
+                name: group.name.clone(),
+                expected_cpu_percent: group.cpu_percent,
+                cpu_max: read(&dir, "cpu.max"),
+                cpu_weight: read(&dir, "cpu.weight"),
+                cpuset_cpus: read(&dir, "cpuset.cpus"),
+            }
+        })
+        .collect()
+}
+
+/// The processes the kernel has placed in one group.
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupMembers {
+    pub name: String,
+    /// `None` when `cgroup.procs` is missing or unreadable.
+    pub processes: Option<Vec<MemberProcess>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MemberProcess {
+    pub pid: u32,
+    /// `None` if the process exited between the two reads.
+    pub name: Option<String>,
+}
+
+/// Reads `cgroup.procs` for each of `groups` and names the PIDs found, to
+/// check the right processes are actually confined.
+pub fn members(groups: &[GroupLimit]) -> Vec<GroupMembers> {
+    let pids: Vec<Option<Vec<u32>>> = groups
+        .iter()
+        .map(|group| {
+            fs::read_to_string(
+                Path::new(CGROUP_ROOT)
+                    .join(&group.name)
+                    .join("cgroup.procs"),
+            )
+            .ok()
+            .map(|raw| {
+                raw.lines()
+                    .filter_map(|line| line.trim().parse().ok())
+                    .collect()
+            })
+        })
+        .collect();
+    let names = procs::names(pids.iter().flatten().flatten().copied());
+    groups
+        .iter()
+        .zip(pids)
+        .map(|(group, pids)| GroupMembers {
+            name: group.name.clone(),
+            processes: pids.map(|pids| {
+                pids.into_iter()
+                    .map(|pid| MemberProcess {
+                        pid,
+                        name: names.get(&pid).cloned(),
+                    })
+                    .collect()
+            }),
+        })
+        .collect()
+}
+
+pub fn apply_allocation(
+    allocation: &Allocation,
+    cfg: &Config,
+    active_domain: Option<&str>,
+    errors: &ErrorCounters,
+) -> Result<()> {
+    apply_groups(&plan_groups(allocation, cfg, active_domain), cfg, errors)
+}
 
-        write_cpu_limits(&ui_dir, allocation.ui_cpu_percent)?;
-        write_cpu_limits(&worker_dir, allocation.worker_cpu_percent)?;
+/// The files `apply_groups` writes for one group and their contents.
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupWrites {
+    pub name: String,
+    pub cpu_percent: f32,
+    /// CPUs' worth of time the share amounts to, which `cpu.max` enforces
+    /// as a hard limit.
+    pub effective_cpus: f32,
+    /// File name to contents; files left out of `cgroup_files` are absent,
+    /// and an empty `cpuset.cpus` resets the pin.
+    pub files: BTreeMap<&'static str, String>,
+}
+
+/// What writing `groups` would put in each file on a machine with `cpus`
+/// online CPUs, without touching anything.
+pub fn planned_writes(groups: &[GroupLimit], cfg: &Config, cpus: u32) -> Vec<GroupWrites> {
+    groups
+        .iter()
+        .map(|group| {
+            let quota_cpus = quota_cpus(group.cpu_percent, cpus, cfg.cpu_quota_whole_cores);
+            let (cpu_max, cpu_weight) =
+                cpu_limit_values(group.cpu_percent, quota_cpus, cfg.cpu_limit_mode);
+            let cpuset = group.cpuset.clone();
+            let files = [
+                (CgroupFile::CpuMax, Some(cpu_max)),
+                (CgroupFile::CpuWeight, Some(cpu_weight.to_string())),
+                (CgroupFile::CpusetCpus, cpuset),
+            ]
+            .into_iter()
+            .filter(|(file, _)| cfg.cgroup_files.contains(file))
+            .filter_map(|(file, value)| Some((file.name(), value?)))
+            .collect();
+            GroupWrites {
+                name: group.name.clone(),
+                cpu_percent: group.cpu_percent,
+                effective_cpus: quota_cpus as f32,
+                files,
+            }
+        })
+        .collect()
+}
+
+/// Writes every group's limits, skipping files left out of `cgroup_files`.
+/// Every file is attempted; the error lists each one that was missing (its
+/// controller isn't delegated yet) or couldn't be written, except a missing
+/// `cpuset.cpus`, and failed writes are counted in `errors`.
+pub fn apply_groups(groups: &[GroupLimit], cfg: &Config, errors: &ErrorCounters) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let mut failures = Vec::new();
+        for group in planned_writes(groups, cfg, online_cpus()) {
+            let dir = Path::new(CGROUP_ROOT).join(&group.name);
+            fs::create_dir_all(&dir).inspect_err(|_| errors.cgroup_write_failed())?;
+            for (file, value) in &group.files {
+                let result = if *file == CgroupFile::CpusetCpus.name() {
+                    write_cpuset(&dir, value, errors)
+                } else {
+                    write_file(&dir.join(file), value, errors)
+                };
+                if let Err(error) = result {
+                    failures.push(error);
+                }
+            }
+        }
+        if !failures.is_empty() {
+            return Err(anyhow::anyhow!(
+                "cgroup limits not applied: {}",
+                failures.join("; ")
+            ));
+        }
     }
 
     #[cfg(not(target_os = "linux"))]
     {
+        let _ = (groups, cfg, errors);
         warn!("cgroups v2 apply skipped: host is not linux");
     }
 
     Ok(())
 }
 
+/// Moves each of `pids` into `group`, skipping ones already there, and
+/// returns how many were moved. PIDs that can't be moved, e.g. because
+/// they exited meanwhile, are logged and skipped.
+pub fn attach_pids(group: &str, pids: &[u32], errors: &ErrorCounters) -> Result<usize> {
+    #[cfg(target_os = "linux")]
+    {
+        use anyhow::Context;
+
+        let dir = Path::new(CGROUP_ROOT).join(group);
+        fs::create_dir_all(&dir).inspect_err(|_| errors.cgroup_write_failed())?;
+        let procs = dir.join("cgroup.procs");
+        let present =
+            fs::read_to_string(&procs).with_context(|| format!("reading {}", procs.display()))?;
+        let present: Vec<&str> = present.lines().collect();
+        let mut moved = 0;
+        for pid in pids {
+            let pid = pid.to_string();
+            if present.contains(&pid.as_str()) {
+                continue;
+            }
+            // The kernel only takes one PID per write.
+            match fs::write(&procs, &pid) {
+                Ok(()) => moved += 1,
+                Err(error) => {
+                    errors.cgroup_write_failed();
+                    warn!("failed to move pid {pid} into {group}: {error}");
+                }
+            }
+        }
+        Ok(moved)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (group, pids, errors);
+        Ok(0)
+    }
+}
+
+fn sanitize_group_name(domain: &str) -> String {
+    domain
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+pub fn online_cpus() -> u32 {
+    use sysinfo::{CpuRefreshKind, RefreshKind, System, SystemExt};
+
+    let system = System::new_with_specifics(RefreshKind::new().with_cpu(CpuRefreshKind::new()));
+    (system.cpus().len() as u32).max(1)
+}
+
+/// CPUs' worth of time a share of `percent` of `cpus` CPUs amounts to. With
+/// `whole_cores` it is rounded to the nearest whole CPU, at least one: 35%
+/// of 8 CPUs is 3 rather than 2.8.
+fn quota_cpus(percent: f32, cpus: u32, whole_cores: bool) -> f64 {
+    let cpus = cpus.max(1) as f64;
+    let exact = cpus * clamp_cpu_percent(percent) as f64 / 100.0;
+    if whole_cores {
+        exact.round().clamp(1.0, cpus)
+    } else {
+        exact
+    }
+}
+
+/// `cpu.max` and `cpu.weight` contents for a share of `percent` (clamped to
+/// `MIN_CPU_PERCENT..=100`) of the whole machine. A `cpu.max` quota is per
+/// period across all CPUs, so it is `quota_cpus` periods: 50% on 8 CPUs is
+/// `400000 100000`. The weight maps linearly from 101 at 0.01% (199 at 1%)
+/// to 10000 at 100%, so it always stays inside the cgroup v2 range of
+/// 1..=10000 and never drops to the default of 100 that unmanaged groups
+/// get. The weight is not rounded to whole cores, even when the quota is.
+fn cpu_limit_values(percent: f32, quota_cpus: f64, mode: CpuLimitMode) -> (String, u32) {
+    let pct = clamp_cpu_percent(percent) as f64;
+    let cpu_max = match mode {
+        CpuLimitMode::Hard => {
+            let quota = (CGROUP_PERIOD_US as f64 * quota_cpus) as u64;
+            format!("{} {CGROUP_PERIOD_US}", quota.max(MIN_CPU_QUOTA_US))
+        }
+        CpuLimitMode::Soft => format!("max {CGROUP_PERIOD_US}"),
+    };
+    let cpu_weight = ((100.0 + pct * 99.0).round() as u32).clamp(1, 10_000);
+    (cpu_max, cpu_weight)
+}
+
+/// Keeps a share within (0, 100]: a zero or negative share still gets
+/// `MIN_CPU_PERCENT` rather than no time at all.
+fn clamp_cpu_percent(percent: f32) -> f32 {
+    if percent.is_nan() {
+        return MIN_CPU_PERCENT;
+    }
+    percent.clamp(MIN_CPU_PERCENT, 100.0)
+}
+
+/// Pins the group to `cpus`. Without the `cpuset` controller enabled for
+/// the subtree the file is missing and the pin is skipped with a warning,
+/// as the CPU limits still hold.
 #[cfg(target_os = "linux")]
-fn write_cpu_limits(dir: &Path, percent: u8) -> Result<()> {
-    let pct = percent.clamp(1, 100) as u32;
-    let quota = (CGROUP_PERIOD_US * pct) / 100;
-    let cpu_max = format!("{quota} {CGROUP_PERIOD_US}");
-    let cpu_weight = (((pct as f32 / 100.0) * 9900.0) + 100.0).round() as u32;
-
-    write_if_exists(&dir.join("cpu.max"), &cpu_max);
-    write_if_exists(&dir.join("cpu.weight"), &cpu_weight.to_string());
-    Ok(())
+fn write_cpuset(dir: &Path, cpus: &str, errors: &ErrorCounters) -> Result<(), String> {
+    let path = dir.join("cpuset.cpus");
+    if !path.exists() {
+        if !cpus.is_empty() {
+            warn!(
+                "cpuset controller not available for {}, worker pin to {cpus} skipped",
+                dir.display()
+            );
+        }
+        return Ok(());
+    }
+    // A bare write of nothing wouldn't reach the kernel; a newline does and
+    // clears the pin.
+    write_file(&path, if cpus.is_empty() { "\n" } else { cpus }, errors)
 }
 
+/// Writes one interface file, describing why it couldn't be.
 #[cfg(target_os = "linux")]
-fn write_if_exists(path: &Path, value: &str) {
-    if path.exists() {
-        if let Err(error) = fs::write(path, value) {
-            warn!("failed to write {}: {error}", path.display());
+fn write_file(path: &Path, value: &str, errors: &ErrorCounters) -> Result<(), String> {
+    if !path.exists() {
+        return Err(format!(
+            "{} is missing (controller not delegated?)",
+            path.display()
+        ));
+    }
+    fs::write(path, value).map_err(|error| {
+        errors.cgroup_write_failed();
+        warn!("failed to write {}: {error}", path.display());
+        format!("writing {}: {error}", path.display())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weight_spans_the_cgroup_v2_range() {
+        for (percent, weight) in [(1.0, 199), (50.0, 5050), (100.0, 10_000)] {
+            let (cpu_max, cpu_weight) = cpu_limit_values(percent, 1.0, CpuLimitMode::Hard);
+            assert_eq!(cpu_weight, weight, "{percent}%");
+            assert!((1..=10_000).contains(&cpu_weight));
+            assert_eq!(cpu_max, "100000 100000");
+        }
+    }
+
+    #[test]
+    fn out_of_range_shares_are_clamped() {
+        let (_, low) = cpu_limit_values(0.0, 0.0, CpuLimitMode::Hard);
+        let (_, high) = cpu_limit_values(250.0, 1.0, CpuLimitMode::Hard);
+        let (_, nan) = cpu_limit_values(f32::NAN, 0.0, CpuLimitMode::Hard);
+        assert_eq!((low, high, nan), (101, 10_000, 101));
+        let (cpu_max, _) = cpu_limit_values(0.0, 0.0, CpuLimitMode::Hard);
+        assert_eq!(cpu_max, format!("{MIN_CPU_QUOTA_US} {CGROUP_PERIOD_US}"));
+    }
+
+    #[test]
+    fn soft_mode_lifts_the_quota() {
+        let (cpu_max, cpu_weight) = cpu_limit_values(50.0, 4.0, CpuLimitMode::Soft);
+        assert_eq!(cpu_max, "max 100000");
+        assert_eq!(cpu_weight, 5050);
+    }
+
+    #[test]
+    fn quota_scales_with_online_cpus() {
+        let quota = quota_cpus(50.0, 8, false);
+        assert_eq!(quota, 4.0);
+        let (cpu_max, _) = cpu_limit_values(50.0, quota, CpuLimitMode::Hard);
+        assert_eq!(cpu_max, "400000 100000");
+        assert_eq!(quota_cpus(50.0, 0, false), 0.5);
+    }
+
+    #[test]
+    fn planned_writes_cover_each_group_on_eight_cpus() {
+        let groups = [
+            GroupLimit {
+                name: UI_GROUP.to_string(),
+                cpu_percent: 5.0,
+                cpuset: None,
+            },
+            GroupLimit {
+                name: WORKER_GROUP.to_string(),
+                cpu_percent: 50.0,
+                cpuset: Some("0-3".to_string()),
+            },
+        ];
+        let writes = planned_writes(&groups, &Config::default(), 8);
+        assert_eq!(writes[0].files["cpu.max"], "40000 100000");
+        assert!(!writes[0].files.contains_key("cpuset.cpus"));
+        assert_eq!(writes[1].effective_cpus, 4.0);
+        assert_eq!(writes[1].files["cpu.max"], "400000 100000");
+        assert_eq!(writes[1].files["cpu.weight"], "5050");
+        assert_eq!(writes[1].files["cpuset.cpus"], "0-3");
+    }
+
+    #[test]
+    fn planned_writes_skip_files_not_configured() {
+        let cfg = Config {
+            cgroup_files: [CgroupFile::CpuWeight].into(),
+            ..Config::default()
+        };
+        let group = GroupLimit {
+            name: WORKER_GROUP.to_string(),
+            cpu_percent: 50.0,
+            cpuset: Some(String::new()),
+        };
+        let writes = planned_writes(&[group], &cfg, 8);
+        assert_eq!(
+            writes[0].files.keys().copied().collect::<Vec<_>>(),
+            ["cpu.weight"]
+        );
+    }
+
+    #[test]
+    fn fractional_shares_keep_their_precision() {
+        let (on_one, weight) =
+            cpu_limit_values(12.5, quota_cpus(12.5, 1, false), CpuLimitMode::Hard);
+        assert_eq!(on_one, "12500 100000");
+        assert_eq!(weight, 1338);
+        let (on_eight, _) = cpu_limit_values(12.5, quota_cpus(12.5, 8, false), CpuLimitMode::Hard);
+        assert_eq!(on_eight, "100000 100000");
+        let (tiny, _) = cpu_limit_values(0.25, quota_cpus(0.25, 64, false), CpuLimitMode::Hard);
+        assert_eq!(tiny, "16000 100000");
+    }
+
+    #[test]
+    fn whole_cores_round_to_the_nearest_cpu() {
+        for (percent, cpus) in [
+            (30.0, 2.0),
+            (35.0, 3.0),
+            (56.0, 4.0),
+            (57.0, 5.0),
+            (100.0, 8.0),
+            (5.0, 1.0),
+        ] {
+            assert_eq!(quota_cpus(percent, 8, true), cpus, "{percent}%");
         }
+        let (cpu_max, weight) =
+            cpu_limit_values(35.0, quota_cpus(35.0, 8, true), CpuLimitMode::Hard);
+        assert_eq!(cpu_max, "300000 100000");
+        // The weight still follows the exact share.
+        assert_eq!(weight, 3565);
+        assert_eq!(quota_cpus(35.0, 8, false), 2.8);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn only_a_missing_cpuset_is_skipped() {
+        let dir = std::env::temp_dir().join(format!("dig-cpuset-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let errors = ErrorCounters::default();
+        assert_eq!(write_cpuset(&dir, "0-3", &errors), Ok(()));
+        assert!(write_file(&dir.join("cpu.max"), "max 100000", &errors).is_err());
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
 
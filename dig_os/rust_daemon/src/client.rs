@@ -0,0 +1,96 @@
+//! Typed async client for the daemon's HTTP API, enabled by the `client`
+//! feature.
+
+use anyhow::{anyhow, Result};
+use reqwest::{Client, RequestBuilder};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::{
+    missions::Mission,
+    scheduler::{Allocation, PerformanceMode},
+    telemetry::TelemetrySnapshot,
+};
+
+#[derive(Debug, Clone)]
+pub struct DigClient {
+    base_url: String,
+    http: Client,
+}
+
+/// `GET /api/v1/runtime` and `POST /api/v1/mode` response.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Runtime {
+    pub version: u64,
+    pub mode: PerformanceMode,
+    /// `mode`, `intensity` or `custom`, whichever last set the allocation.
+    #[serde(default)]
+    pub control: Option<String>,
+    #[serde(default)]
+    pub intensity: Option<f32>,
+    pub allocation: Allocation,
+    pub active_mission: Option<String>,
+    pub session_xp: u64,
+}
+
+#[derive(Deserialize)]
+struct ErrorEnvelope {
+    error: ErrorBody,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    code: String,
+    message: String,
+}
+
+impl DigClient {
+    /// `base_url` is the daemon root, e.g. `http://127.0.0.1:7878`.
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            http: Client::new(),
+        }
+    }
+
+    pub async fn telemetry(&self) -> Result<TelemetrySnapshot> {
+        self.send(self.http.get(self.url("/api/v1/telemetry")))
+            .await
+    }
+
+    pub async fn runtime(&self) -> Result<Runtime> {
+        self.send(self.http.get(self.url("/api/v1/runtime"))).await
+    }
+
+    pub async fn set_mode(&self, mode: PerformanceMode) -> Result<Runtime> {
+        let body = serde_json::json!({ "mode": mode });
+        self.send(self.http.post(self.url("/api/v1/mode")).json(&body))
+            .await
+    }
+
+    pub async fn missions(&self) -> Result<Vec<Mission>> {
+        self.send(self.http.get(self.url("/api/v1/missions"))).await
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base_url)
+    }
+
+    /// Decodes a success body as `T`, or turns the API's error envelope
+    /// into an error carrying its code and message.
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T> {
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response.json().await?);
+        }
+        match response.json::<ErrorEnvelope>().await {
+            Ok(envelope) => Err(anyhow!(
+                "{status}: {} ({})",
+                envelope.error.message,
+                envelope.error.code
+            )),
+            Err(_) => Err(anyhow!("{status}")),
+        }
+    }
+}
+
@@ -1,59 +1,1225 @@
-use std::{env, net::SocketAddr};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env, fmt, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
-use anyhow::{anyhow, Result};
+use anyhow::{Context, Result};
+use directories::ProjectDirs;
+use serde::Serialize;
+use sysinfo::{System, SystemExt};
+use tokio::time::MissedTickBehavior;
+use tracing::warn;
+
+use crate::{
+    auth::ApiKey,
+    cgroups::{CgroupFile, CpuLimitMode},
+    gpu_source::GpuBackend,
+    missions::{StallAction, UnknownMission},
+    schedule::{self, ScheduleWindow},
+    scheduler::{allocation_for_mode, PerformanceMode, ThrottleOverride},
+    telemetry::{SourcePreference, SyntheticProfile, TelemetryPrecision, TemperatureUnit},
+};
+
+/// Origin of `Config::thermal_limit_c`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThermalLimitSource {
+    Default,
+    Config,
+    Hardware,
+}
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub bind_addr: SocketAddr,
+    /// Identifies this machine in telemetry when aggregating a fleet.
+    pub node_id: String,
     pub poll_interval_ms: u64,
+    /// Idle time after which the worker starts backing off its poll
+    /// interval (0 disables); see `next_poll_interval_ms`.
+    pub idle_poll_after_secs: u64,
+    /// CPU and GPU load both under this, with no active mission, is idle.
+    pub idle_load_percent: f32,
+    /// Longest poll interval the idle backoff goes to.
+    pub idle_poll_max_ms: u64,
+    /// What the worker does with ticks missed while a collection overran
+    /// the interval: `Skip` them or `Delay` the cadence.
+    pub poll_missed_ticks: MissedTickBehavior,
+    /// Share of `poll_interval_ms` one worker iteration may take before it
+    /// is reported as slow (0 disables the check).
+    pub tick_budget_fraction: f32,
+    /// Worker ticks between samples of the costlier disk, network and
+    /// process stats (0 disables them); snapshots in between repeat the last.
+    pub slow_metrics_every: u32,
+    /// Stored snapshots kept for comparisons, newest last (0 keeps none).
+    pub telemetry_history_size: usize,
+    /// Approximate memory `telemetry_history` may take; the oldest
+    /// snapshots go first once over it (0 disables the budget).
+    pub telemetry_history_max_bytes: usize,
+    /// Mode applied at boot, and whenever the schedule leaves its last
+    /// window.
+    pub startup_mode: PerformanceMode,
+    /// Local-time windows that switch the mode as they start; see
+    /// `schedule::parse_schedule`. Empty disables scheduling.
+    pub mode_schedule: Vec<ScheduleWindow>,
+    /// Wait before the first cgroup apply and telemetry collection.
+    pub startup_delay_ms: u64,
+    /// Default limit for every mode: `DIG_THERMAL_LIMIT_C` if set, else the
+    /// GPU's own slowdown temperature less `hardware_thermal_margin_c`, else
+    /// 85C.
     pub thermal_limit_c: f32,
+    /// Where `thermal_limit_c` came from; an explicit `DIG_THERMAL_LIMIT_C`
+    /// takes precedence over the hardware limit.
+    pub thermal_limit_source: ThermalLimitSource,
+    pub hardware_thermal_margin_c: f32,
+    /// Slowdown temperature the GPU reported at startup; see
+    /// `with_gpu_slowdown`.
+    pub gpu_slowdown_c: Option<f32>,
+    /// Per-mode replacements for `thermal_limit_c`; see `thermal_limit_for`.
+    pub mode_thermal_limits: BTreeMap<PerformanceMode, f32>,
+    /// Consecutive samples at or over the limit before the throttle engages,
+    /// so a single transient reading is ignored.
+    pub thermal_grace_samples: u32,
+    /// A throttled worker returns to its previous mode once the GPU cools to
+    /// this temperature.
+    pub thermal_resume_c: f32,
+    /// Hard limit above every mode's `thermal_limit_c`: reaching it cuts the
+    /// worker to `critical_worker_percent` at once, Gaming included, until
+    /// the GPU cools to `thermal_resume_c`.
+    pub thermal_critical_c: f32,
+    /// Worker CPU and GPU share while over `thermal_critical_c`.
+    pub critical_worker_percent: u8,
+    /// Thermal headroom at or above which Autopilot runs its full worker
+    /// shares; closer to the limit they follow `autopilot_scale`. 0 keeps
+    /// Autopilot's allocation fixed.
+    pub autopilot_full_headroom_c: f32,
+    /// Share of Autopilot's worker allocation kept at zero headroom.
+    pub autopilot_min_scale: f32,
+    /// Curve shape: 1 reduces linearly with headroom, higher holds the
+    /// allocation longer and cuts it harder near the limit.
+    pub autopilot_curve_exponent: f32,
+    /// Multiplier on the worker shares while the active mission was
+    /// started with `focus`; see `focus_boosted`.
+    pub focus_worker_boost: f32,
+    /// Available memory below which the worker shares are halved to keep
+    /// the desktop responsive (0 disables); see `memory_pressure`.
+    pub min_free_memory_mib: u64,
+    /// Throttles within `cooldown_window_secs` that force a cooldown (0 disables).
+    pub cooldown_throttle_count: u32,
+    pub cooldown_window_secs: u64,
+    /// How long a forced cooldown holds the throttled allocation.
+    pub cooldown_secs: u64,
     pub ui_reserved_cpu_percent: u8,
     pub ui_reserved_gpu_percent: u8,
+    /// Worker share, as a percent of the mode's, while no mission is active.
+    pub idle_worker_percent: u8,
+    /// Most any mode or custom allocation may give the worker; 100 leaves
+    /// the modes as they are.
+    pub max_worker_cpu_percent: u8,
+    pub max_worker_gpu_percent: u8,
+    /// An external worker whose last heartbeat is older than this is lost
+    /// (0 disables the check).
+    pub worker_heartbeat_timeout_secs: u64,
+    /// Drop to the idle allocation while the external worker is lost.
+    pub worker_lost_idle: bool,
+    /// Process name patterns (`*` wildcards) identifying worker processes
+    /// to move into the worker cgroup. Empty disables auto-attach.
+    pub worker_process_patterns: Vec<String>,
+    /// How often running processes are matched against those patterns.
+    pub worker_attach_interval_secs: u64,
+    /// Extra attempts, with doubling backoff, when the startup cgroup apply fails.
+    pub cgroup_apply_retries: u32,
+    /// Treat implausibly low allocation sums as errors instead of warnings.
+    pub allocation_strict: bool,
+    pub temperature_unit: TemperatureUnit,
+    /// Base for every file the daemon reads or writes; relative paths in
+    /// other settings are resolved against it.
+    pub data_dir: PathBuf,
+    pub dataset_dir: PathBuf,
+    /// Per-domain share (percent of the worker allocation) for split worker
+    /// cgroups. Empty keeps the single `dig-worker` group.
+    pub cgroup_domain_shares: BTreeMap<String, u8>,
+    /// Fallback when the starting mission isn't in the catalog.
+    pub unknown_mission: UnknownMission,
+    /// Mission activated when switching to a mode; `None` clears it. Modes
+    /// without an entry keep the current mission.
+    pub mode_default_missions: BTreeMap<PerformanceMode, Option<String>>,
+    /// Replacement `profile` labels for mode allocations.
+    pub mode_profiles: BTreeMap<PerformanceMode, String>,
+    /// CPU lists the worker group is pinned to per mode, written as
+    /// `cpuset.cpus`. Modes without an entry run on every CPU.
+    pub mode_worker_cpusets: BTreeMap<PerformanceMode, String>,
+    /// Advisory UI/worker VRAM shares per mode, replacing the defaults.
+    pub mode_gpu_memory: BTreeMap<PerformanceMode, (u8, u8)>,
+    /// Modes `POST /api/v1/mode` may select. Internal transitions such as
+    /// the thermal throttle are not restricted.
+    pub allowed_api_modes: BTreeSet<PerformanceMode>,
+    /// Shortest gap between two `POST /api/v1/mode` calls; sooner ones
+    /// get 429. Internal transitions are exempt (0 disables).
+    pub min_mode_change_interval_ms: u64,
+    /// Worker ticks after an API mode change during which the thermal
+    /// throttle neither counts nor acts on readings (0 disables).
+    pub mode_settle_ticks: u32,
+    /// Whether an API mode change can lift a thermal throttle.
+    pub throttle_user_override: ThrottleOverride,
+    /// With no UI contact for this long while the worker runs above
+    /// Balanced's shares, fall back to Balanced (0 disables).
+    pub ui_contact_timeout_secs: u64,
+    pub cpu_limit_mode: CpuLimitMode,
+    /// Round each `cpu.max` quota to a whole number of CPUs (at least one)
+    /// instead of the exact fraction of the machine.
+    pub cpu_quota_whole_cores: bool,
+    /// cgroup files the daemon may write; the rest are never attempted.
+    pub cgroup_files: BTreeSet<CgroupFile>,
+    pub synthetic_profile: Option<SyntheticProfile>,
+    /// Initial GPU source preference; can be changed at runtime via the API.
+    pub telemetry_source: SourcePreference,
+    /// Consecutive samples of GPU/CPU imbalance before suggesting a change.
+    pub balance_window_samples: u32,
+    /// GPU load below which a tick earns no XP.
+    pub xp_min_gpu_load_percent: f32,
+    /// Most XP granted in any rolling minute (0 = unlimited).
+    pub xp_cap_per_minute: u64,
+    /// XP per DIG of bounty awarded when a mission completes; see
+    /// `completion_xp`.
+    pub completion_xp_per_dig: f32,
+    /// Time without a working tick (see `tick_xp`) after which the active
+    /// mission counts as stalled; 0 disables the check.
+    pub mission_stall_timeout_secs: u64,
+    pub mission_stall_action: StallAction,
+    /// USD per DIG for fiat earnings estimates; unset disables them.
+    pub dig_to_usd_rate: Option<f32>,
+    /// Time window of every smoothed telemetry field; 0 disables
+    /// smoothing. See `Ema::from_window`.
+    pub smoothing_window_secs: f32,
+    /// Fixed weight of the newest sample in `earnings_per_sec_smoothed`
+    /// (0 < a <= 1), overriding `smoothing_window_secs` for earnings only.
+    pub earnings_ema_alpha: Option<f32>,
+    /// Decimal places telemetry values are rounded to, per field group.
+    pub telemetry_precision: TelemetryPrecision,
+    /// Per-tick rate at which contribution momentum builds while working
+    /// and decays while idle (0 < r <= 1).
+    pub momentum_rate: f32,
+    /// Ticks with a lower `impact_score` earn no XP and add nothing to the
+    /// per-domain impact totals (0 credits every tick).
+    pub min_impact_for_credit: f32,
+    /// Sensor labels (case-insensitive) left out of the CPU temperature.
+    pub cpu_temp_ignore: Vec<String>,
+    /// Mission domains that load the GPU hard enough to be flagged as not
+    /// recommended while it runs hot.
+    pub gpu_heavy_domains: BTreeSet<String>,
+    /// `nvidia-smi` or a drop-in replacement emitting the same CSV.
+    pub gpu_smi_cmd: String,
+    /// GPU sources tried in order each sample; synthetic values fill in
+    /// when none delivers.
+    pub gpu_sources: Vec<GpuBackend>,
+    /// `rocm-smi` or a drop-in replacement, for the `rocm-smi` source.
+    pub rocm_smi_cmd: String,
+    /// Which `nvidia-smi` device drives telemetry and thermal decisions.
+    pub compute_gpu_index: u32,
+    /// Plausible range for raw GPU temperature readings in Celsius; values
+    /// outside it are clamped and logged.
+    pub gpu_temp_min_c: f32,
+    pub gpu_temp_max_c: f32,
+    /// Enforce `worker_gpu_percent` as a GPU power cap via `nvidia-smi -pl`.
+    pub gpu_power_limit: bool,
+    /// Warn at startup when the detected GPU vendor doesn't suit
+    /// `gpu_smi_cmd`; see `gpu_vendor::tool_mismatch`.
+    pub gpu_vendor_check: bool,
+    /// Telemetry collections (each may spawn `gpu_smi_cmd`) allowed to run
+    /// at once; further callers wait.
+    pub max_concurrent_collections: usize,
+    /// WebSocket and long-poll clients allowed at once; further ones get
+    /// 503.
+    pub max_stream_subscribers: usize,
+    /// Daily rotated log file (date suffix added); unset logs to stdout only.
+    pub log_file: Option<PathBuf>,
+    /// Whether to keep logging to stdout next to `log_file`.
+    pub log_stdout: bool,
+    /// Receives a JSON POST on every mode change; unset disables it.
+    pub webhook_url: Option<String>,
+    /// Full access to `/api/v1`. With neither key set the API is open.
+    pub api_admin_key: Option<ApiKey>,
+    /// `GET` endpoints and streams only.
+    pub api_read_key: Option<ApiKey>,
+    /// Collector that receives batches of snapshots; unset disables pushing.
+    pub telemetry_push_url: Option<String>,
+    /// Snapshots per push, unless `telemetry_push_interval_secs` passes first.
+    pub telemetry_push_batch: usize,
+    pub telemetry_push_interval_secs: u64,
+    /// gzip/brotli responses for clients that send `Accept-Encoding`.
+    pub compression: bool,
+    /// Answer `GET /` with an endpoint index instead of a 404.
+    pub root_index: bool,
+    /// Largest accepted request body; bigger ones get a 413.
+    pub max_body_bytes: usize,
+    /// PEM certificate and key; when both are set the API is served over TLS.
+    pub tls: Option<TlsPaths>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             bind_addr: "127.0.0.1:7788".parse().expect("valid socket"),
+            node_id: default_node_id(),
+            startup_mode: PerformanceMode::Balanced,
+            mode_schedule: Vec::new(),
+            startup_delay_ms: 0,
             poll_interval_ms: 1000,
+            idle_poll_after_secs: 0,
+            idle_load_percent: 10.0,
+            idle_poll_max_ms: 10_000,
+            tick_budget_fraction: 0.8,
+            slow_metrics_every: 10,
+            telemetry_history_size: 600,
+            telemetry_history_max_bytes: 4 * 1024 * 1024,
+            poll_missed_ticks: MissedTickBehavior::Skip,
             thermal_limit_c: 85.0,
+            autopilot_full_headroom_c: 0.0,
+            autopilot_min_scale: 0.3,
+            focus_worker_boost: 1.25,
+            autopilot_curve_exponent: 2.0,
+            thermal_limit_source: ThermalLimitSource::Default,
+            hardware_thermal_margin_c: 5.0,
+            gpu_slowdown_c: None,
+            mode_thermal_limits: BTreeMap::new(),
+            thermal_grace_samples: 3,
+            thermal_resume_c: 75.0,
+            thermal_critical_c: 95.0,
+            critical_worker_percent: 1,
+            min_free_memory_mib: 0,
+            cooldown_throttle_count: 3,
+            cooldown_window_secs: 600,
+            cooldown_secs: 300,
             ui_reserved_cpu_percent: 5,
             ui_reserved_gpu_percent: 5,
+            idle_worker_percent: 10,
+            max_worker_cpu_percent: 100,
+            max_worker_gpu_percent: 100,
+            worker_heartbeat_timeout_secs: 30,
+            worker_lost_idle: false,
+            worker_process_patterns: Vec::new(),
+            worker_attach_interval_secs: 10,
+            cgroup_apply_retries: 4,
+            allocation_strict: false,
+            temperature_unit: TemperatureUnit::Celsius,
+            data_dir: default_data_dir(),
+            dataset_dir: PathBuf::from("datasets"),
+            cgroup_domain_shares: BTreeMap::new(),
+            unknown_mission: UnknownMission::Clear,
+            mode_default_missions: BTreeMap::new(),
+            mode_profiles: BTreeMap::new(),
+            mode_worker_cpusets: BTreeMap::new(),
+            mode_gpu_memory: BTreeMap::new(),
+            allowed_api_modes: PerformanceMode::ALL.into_iter().collect(),
+            min_mode_change_interval_ms: 0,
+            mode_settle_ticks: 0,
+            throttle_user_override: ThrottleOverride::Defer,
+            ui_contact_timeout_secs: 0,
+            cpu_limit_mode: CpuLimitMode::Hard,
+            cpu_quota_whole_cores: false,
+            cgroup_files: CgroupFile::ALL.into_iter().collect(),
+            synthetic_profile: None,
+            telemetry_source: SourcePreference::Auto,
+            balance_window_samples: 30,
+            xp_min_gpu_load_percent: 10.0,
+            xp_cap_per_minute: 0,
+            completion_xp_per_dig: 1.0,
+            mission_stall_timeout_secs: 0,
+            mission_stall_action: StallAction::Abandon,
+            dig_to_usd_rate: None,
+            smoothing_window_secs: 5.0,
+            earnings_ema_alpha: None,
+            telemetry_precision: TelemetryPrecision::default(),
+            momentum_rate: 0.05,
+            min_impact_for_credit: 0.0,
+            cpu_temp_ignore: Vec::new(),
+            gpu_heavy_domains: BTreeSet::from(["render".to_string()]),
+            gpu_smi_cmd: "nvidia-smi".to_string(),
+            gpu_sources: GpuBackend::defaults(),
+            rocm_smi_cmd: "rocm-smi".to_string(),
+            compute_gpu_index: 0,
+            gpu_temp_min_c: -10.0,
+            gpu_temp_max_c: 125.0,
+            gpu_power_limit: false,
+            gpu_vendor_check: true,
+            max_concurrent_collections: 1,
+            max_stream_subscribers: 32,
+            log_file: None,
+            log_stdout: true,
+            webhook_url: None,
+            api_admin_key: None,
+            api_read_key: None,
+            telemetry_push_url: None,
+            telemetry_push_batch: 10,
+            telemetry_push_interval_secs: 10,
+            compression: true,
+            root_index: true,
+            max_body_bytes: 16 * 1024,
+            tls: None,
+        }
+    }
+}
+
+/// Everything wrong with a configuration at once, so it can be fixed in one
+/// go rather than one restart per problem.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.problems.as_slice() {
+            [problem] => f.write_str(problem),
+            problems => {
+                write!(f, "{} configuration problems:", problems.len())?;
+                for problem in problems {
+                    write!(f, "\n  - {problem}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Reads `DIG_*` variables through `lookup`, recording each bad value as
+/// `invalid NAME: value` and carrying on with the next, so `from_env` can
+/// report them all.
+struct EnvReader<F> {
+    lookup: F,
+    problems: Vec<String>,
+}
+
+impl<F: Fn(&str) -> Option<String>> EnvReader<F> {
+    fn new(lookup: F) -> Self {
+        Self {
+            lookup,
+            problems: Vec::new(),
         }
     }
+
+    /// `name` as set, unparsed.
+    fn raw(&self, name: &str) -> Option<String> {
+        (self.lookup)(name)
+    }
+
+    /// `name` read by `parse`; `None` when unset or rejected.
+    fn var_with<T>(&mut self, name: &str, parse: impl FnOnce(&str) -> Option<T>) -> Option<T> {
+        let value = self.raw(name)?;
+        let parsed = parse(&value);
+        if parsed.is_none() {
+            self.problem(format!("invalid {name}: {value}"));
+        }
+        parsed
+    }
+
+    fn var<T: FromStr>(&mut self, name: &str) -> Option<T> {
+        self.var_with(name, |value| value.parse().ok())
+    }
+
+    /// Overwrites `target` with `name` when it is set and valid.
+    fn set_with<T>(&mut self, name: &str, target: &mut T, parse: impl FnOnce(&str) -> Option<T>) {
+        if let Some(value) = self.var_with(name, parse) {
+            *target = value;
+        }
+    }
+
+    fn set<T: FromStr>(&mut self, name: &str, target: &mut T) {
+        self.set_with(name, target, |value| value.parse().ok());
+    }
+
+    /// Like `set`, but values `valid` rejects count as bad too.
+    fn set_if<T: FromStr>(&mut self, name: &str, target: &mut T, valid: impl FnOnce(&T) -> bool) {
+        self.set_with(name, target, |value| value.parse().ok().filter(valid));
+    }
+
+    fn problem(&mut self, problem: impl Into<String>) {
+        self.problems.push(problem.into());
+    }
+}
+
+/// Comma-separated items, blanks skipped; `None` if any item doesn't parse.
+fn parse_list<T: FromStr, C: FromIterator<T>>(value: &str) -> Option<C> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| item.parse().ok())
+        .collect()
+}
+
+fn non_blank(value: &str) -> Option<String> {
+    Some(value.to_string()).filter(|value| !value.trim().is_empty())
+}
+
+fn parse_url(value: &str) -> Option<Option<String>> {
+    reqwest::Url::parse(value).ok()?;
+    Some(Some(value.to_string()))
 }
 
 impl Config {
+    /// Reads every `DIG_*` variable over the defaults. All bad values and
+    /// cross-field violations are reported together as a `ConfigError`.
     pub fn from_env() -> Result<Self> {
+        Self::from_lookup(|name| env::var(name).ok())
+    }
+
+    /// `from_env` with the variables taken from `lookup`.
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
         let mut cfg = Self::default();
+        let mut env = EnvReader::new(lookup);
 
-        if let Ok(value) = env::var("DIG_DAEMON_ADDR") {
-            cfg.bind_addr = value
-                .parse()
-                .map_err(|_| anyhow!("invalid DIG_DAEMON_ADDR: {value}"))?;
+        env.set("DIG_DAEMON_ADDR", &mut cfg.bind_addr);
+        env.set_with("DIG_NODE_ID", &mut cfg.node_id, |value| {
+            Some(value.trim().to_string()).filter(|id| !id.is_empty())
+        });
+        env.set("DIG_POLL_INTERVAL_MS", &mut cfg.poll_interval_ms);
+        env.set("DIG_IDLE_POLL_AFTER_SECS", &mut cfg.idle_poll_after_secs);
+        env.set("DIG_IDLE_LOAD_PERCENT", &mut cfg.idle_load_percent);
+        env.set("DIG_IDLE_POLL_MAX_MS", &mut cfg.idle_poll_max_ms);
+        env.set_if(
+            "DIG_TICK_BUDGET_FRACTION",
+            &mut cfg.tick_budget_fraction,
+            |fraction: &f32| (0.0..=1.0).contains(fraction),
+        );
+        env.set("DIG_SLOW_METRICS_EVERY", &mut cfg.slow_metrics_every);
+        env.set(
+            "DIG_TELEMETRY_HISTORY_SIZE",
+            &mut cfg.telemetry_history_size,
+        );
+        env.set(
+            "DIG_TELEMETRY_HISTORY_MAX_BYTES",
+            &mut cfg.telemetry_history_max_bytes,
+        );
+        env.set_with(
+            "DIG_POLL_MISSED_TICKS",
+            &mut cfg.poll_missed_ticks,
+            |value| match value.trim().to_ascii_lowercase().as_str() {
+                "skip" => Some(MissedTickBehavior::Skip),
+                "delay" => Some(MissedTickBehavior::Delay),
+                _ => None,
+            },
+        );
+        env.set("DIG_STARTUP_MODE", &mut cfg.startup_mode);
+        env.set_with(
+            "DIG_MODE_SCHEDULE",
+            &mut cfg.mode_schedule,
+            schedule::parse_schedule,
+        );
+        env.set("DIG_STARTUP_DELAY_MS", &mut cfg.startup_delay_ms);
+        if let Some(limit) = env.var("DIG_THERMAL_LIMIT_C") {
+            cfg.thermal_limit_c = limit;
+            cfg.thermal_limit_source = ThermalLimitSource::Config;
         }
-        if let Ok(value) = env::var("DIG_POLL_INTERVAL_MS") {
-            cfg.poll_interval_ms = value
+        env.set_if(
+            "DIG_HARDWARE_THERMAL_MARGIN_C",
+            &mut cfg.hardware_thermal_margin_c,
+            |margin: &f32| *margin >= 0.0,
+        );
+        env.set_with(
+            "DIG_MODE_THERMAL_LIMITS",
+            &mut cfg.mode_thermal_limits,
+            parse_mode_limits,
+        );
+        env.set("DIG_THERMAL_GRACE_SAMPLES", &mut cfg.thermal_grace_samples);
+        env.set("DIG_THERMAL_RESUME_C", &mut cfg.thermal_resume_c);
+        env.set("DIG_THERMAL_CRITICAL_C", &mut cfg.thermal_critical_c);
+        env.set_if(
+            "DIG_CRITICAL_WORKER_PERCENT",
+            &mut cfg.critical_worker_percent,
+            |percent| *percent <= 100,
+        );
+        env.set_if(
+            "DIG_AUTOPILOT_FULL_HEADROOM_C",
+            &mut cfg.autopilot_full_headroom_c,
+            |c: &f32| c.is_finite() && *c >= 0.0,
+        );
+        env.set_if(
+            "DIG_AUTOPILOT_MIN_SCALE",
+            &mut cfg.autopilot_min_scale,
+            |scale: &f32| (0.0..=1.0).contains(scale),
+        );
+        env.set_if(
+            "DIG_AUTOPILOT_CURVE_EXPONENT",
+            &mut cfg.autopilot_curve_exponent,
+            |exponent: &f32| exponent.is_finite() && *exponent > 0.0,
+        );
+        env.set_if(
+            "DIG_FOCUS_WORKER_BOOST",
+            &mut cfg.focus_worker_boost,
+            |boost: &f32| boost.is_finite() && *boost >= 1.0,
+        );
+        env.set("DIG_MIN_FREE_MEMORY_MIB", &mut cfg.min_free_memory_mib);
+        env.set(
+            "DIG_COOLDOWN_THROTTLE_COUNT",
+            &mut cfg.cooldown_throttle_count,
+        );
+        env.set("DIG_COOLDOWN_WINDOW_SECS", &mut cfg.cooldown_window_secs);
+        env.set("DIG_COOLDOWN_SECS", &mut cfg.cooldown_secs);
+        env.set(
+            "DIG_UI_RESERVED_CPU_PERCENT",
+            &mut cfg.ui_reserved_cpu_percent,
+        );
+        env.set(
+            "DIG_UI_RESERVED_GPU_PERCENT",
+            &mut cfg.ui_reserved_gpu_percent,
+        );
+        env.set_if(
+            "DIG_IDLE_WORKER_PERCENT",
+            &mut cfg.idle_worker_percent,
+            |percent| *percent <= 100,
+        );
+        env.set_if(
+            "DIG_MAX_WORKER_CPU_PERCENT",
+            &mut cfg.max_worker_cpu_percent,
+            |percent| *percent <= 100,
+        );
+        env.set_if(
+            "DIG_MAX_WORKER_GPU_PERCENT",
+            &mut cfg.max_worker_gpu_percent,
+            |percent| *percent <= 100,
+        );
+        env.set(
+            "DIG_WORKER_HEARTBEAT_TIMEOUT_SECS",
+            &mut cfg.worker_heartbeat_timeout_secs,
+        );
+        env.set_with(
+            "DIG_WORKER_PROCESSES",
+            &mut cfg.worker_process_patterns,
+            parse_list,
+        );
+        env.set_if(
+            "DIG_WORKER_ATTACH_INTERVAL_SECS",
+            &mut cfg.worker_attach_interval_secs,
+            |secs| *secs > 0,
+        );
+        env.set("DIG_WORKER_LOST_IDLE", &mut cfg.worker_lost_idle);
+        env.set("DIG_CGROUP_APPLY_RETRIES", &mut cfg.cgroup_apply_retries);
+        env.set("DIG_ALLOCATION_STRICT", &mut cfg.allocation_strict);
+        env.set("DIG_TEMPERATURE_UNIT", &mut cfg.temperature_unit);
+        env.set("DIG_DATA_DIR", &mut cfg.data_dir);
+        env.set("DIG_DATASET_DIR", &mut cfg.dataset_dir);
+        env.set_with(
+            "DIG_CGROUP_DOMAIN_SHARES",
+            &mut cfg.cgroup_domain_shares,
+            parse_domain_shares,
+        );
+        env.set_with(
+            "DIG_MODE_DEFAULT_MISSIONS",
+            &mut cfg.mode_default_missions,
+            parse_mode_missions,
+        );
+        env.set_with(
+            "DIG_MODE_PROFILES",
+            &mut cfg.mode_profiles,
+            parse_mode_profiles,
+        );
+        env.set_with(
+            "DIG_MODE_WORKER_CPUSETS",
+            &mut cfg.mode_worker_cpusets,
+            parse_mode_cpusets,
+        );
+        env.set_with(
+            "DIG_MODE_GPU_MEMORY",
+            &mut cfg.mode_gpu_memory,
+            parse_mode_gpu_memory,
+        );
+        env.set_with(
+            "DIG_ALLOWED_API_MODES",
+            &mut cfg.allowed_api_modes,
+            parse_mode_list,
+        );
+        env.set(
+            "DIG_MIN_MODE_CHANGE_INTERVAL_MS",
+            &mut cfg.min_mode_change_interval_ms,
+        );
+        env.set("DIG_MODE_SETTLE_TICKS", &mut cfg.mode_settle_ticks);
+        env.set(
+            "DIG_THROTTLE_USER_OVERRIDE",
+            &mut cfg.throttle_user_override,
+        );
+        env.set(
+            "DIG_UI_CONTACT_TIMEOUT_SECS",
+            &mut cfg.ui_contact_timeout_secs,
+        );
+        env.set("DIG_UNKNOWN_MISSION", &mut cfg.unknown_mission);
+        env.set("DIG_CPU_LIMIT_MODE", &mut cfg.cpu_limit_mode);
+        env.set("DIG_CPU_QUOTA_WHOLE_CORES", &mut cfg.cpu_quota_whole_cores);
+        env.set_with("DIG_CGROUP_FILES", &mut cfg.cgroup_files, parse_list);
+        env.set_with(
+            "DIG_SYNTHETIC_PROFILE",
+            &mut cfg.synthetic_profile,
+            |value| value.parse().ok().map(Some),
+        );
+        env.set("DIG_TELEMETRY_SOURCE", &mut cfg.telemetry_source);
+        env.set(
+            "DIG_BALANCE_WINDOW_SAMPLES",
+            &mut cfg.balance_window_samples,
+        );
+        env.set(
+            "DIG_XP_MIN_GPU_LOAD_PERCENT",
+            &mut cfg.xp_min_gpu_load_percent,
+        );
+        env.set("DIG_XP_CAP_PER_MINUTE", &mut cfg.xp_cap_per_minute);
+        env.set_if(
+            "DIG_COMPLETION_XP_PER_DIG",
+            &mut cfg.completion_xp_per_dig,
+            |rate: &f32| rate.is_finite() && *rate >= 0.0,
+        );
+        env.set(
+            "DIG_MISSION_STALL_TIMEOUT_SECS",
+            &mut cfg.mission_stall_timeout_secs,
+        );
+        env.set("DIG_MISSION_STALL_ACTION", &mut cfg.mission_stall_action);
+        env.set_with("DIG_TO_USD_RATE", &mut cfg.dig_to_usd_rate, |value| {
+            value
                 .parse()
-                .map_err(|_| anyhow!("invalid DIG_POLL_INTERVAL_MS: {value}"))?;
+                .ok()
+                .filter(|rate: &f32| *rate >= 0.0)
+                .map(Some)
+        });
+        env.set_if(
+            "DIG_SMOOTHING_WINDOW_SECS",
+            &mut cfg.smoothing_window_secs,
+            |secs: &f32| secs.is_finite() && *secs >= 0.0,
+        );
+        env.set_with(
+            "DIG_EARNINGS_EMA_ALPHA",
+            &mut cfg.earnings_ema_alpha,
+            |value| {
+                value
+                    .parse()
+                    .ok()
+                    .filter(|alpha: &f32| *alpha > 0.0 && *alpha <= 1.0)
+                    .map(Some)
+            },
+        );
+        env.set_with(
+            "DIG_TELEMETRY_PRECISION",
+            &mut cfg.telemetry_precision,
+            parse_precision,
+        );
+        env.set_if("DIG_MOMENTUM_RATE", &mut cfg.momentum_rate, |rate: &f32| {
+            *rate > 0.0 && *rate <= 1.0
+        });
+        env.set_if(
+            "DIG_MIN_IMPACT_FOR_CREDIT",
+            &mut cfg.min_impact_for_credit,
+            |score: &f32| score.is_finite() && *score >= 0.0,
+        );
+        env.set_with("DIG_CPU_TEMP_IGNORE", &mut cfg.cpu_temp_ignore, parse_list);
+        env.set_with(
+            "DIG_GPU_HEAVY_DOMAINS",
+            &mut cfg.gpu_heavy_domains,
+            parse_list,
+        );
+        env.set_with("DIG_GPU_SMI_CMD", &mut cfg.gpu_smi_cmd, non_blank);
+        let sources = env.var_with("DIG_GPU_SOURCES", |value| {
+            parse_list(value).filter(|sources: &Vec<GpuBackend>| !sources.is_empty())
+        });
+        match sources {
+            Some(sources) if !cfg!(feature = "nvml") && sources.contains(&GpuBackend::Nvml) => {
+                env.problem("invalid DIG_GPU_SOURCES: nvml (built without the nvml feature)");
+            }
+            Some(sources) => cfg.gpu_sources = sources,
+            None => {}
         }
-        if let Ok(value) = env::var("DIG_THERMAL_LIMIT_C") {
-            cfg.thermal_limit_c = value
-                .parse()
-                .map_err(|_| anyhow!("invalid DIG_THERMAL_LIMIT_C: {value}"))?;
+        env.set_with("DIG_ROCM_SMI_CMD", &mut cfg.rocm_smi_cmd, non_blank);
+        env.set("DIG_COMPUTE_GPU_INDEX", &mut cfg.compute_gpu_index);
+        env.set("DIG_GPU_TEMP_MIN_C", &mut cfg.gpu_temp_min_c);
+        env.set("DIG_GPU_TEMP_MAX_C", &mut cfg.gpu_temp_max_c);
+        env.set("DIG_GPU_VENDOR_CHECK", &mut cfg.gpu_vendor_check);
+        env.set("DIG_GPU_POWER_LIMIT", &mut cfg.gpu_power_limit);
+        env.set_if(
+            "DIG_MAX_CONCURRENT_COLLECTIONS",
+            &mut cfg.max_concurrent_collections,
+            |limit: &usize| *limit > 0,
+        );
+        env.set_if(
+            "DIG_MAX_STREAM_SUBSCRIBERS",
+            &mut cfg.max_stream_subscribers,
+            |limit: &usize| *limit > 0,
+        );
+        env.set_with("DIG_LOG_FILE", &mut cfg.log_file, |value| {
+            value.parse().ok().map(Some)
+        });
+        env.set("DIG_LOG_STDOUT", &mut cfg.log_stdout);
+        env.set_with("DIG_WEBHOOK_URL", &mut cfg.webhook_url, parse_url);
+        env.set_with("DIG_API_ADMIN_KEY", &mut cfg.api_admin_key, |value| {
+            ApiKey::new(value).map(Some)
+        });
+        env.set_with("DIG_API_READ_KEY", &mut cfg.api_read_key, |value| {
+            ApiKey::new(value).map(Some)
+        });
+        env.set_with(
+            "DIG_TELEMETRY_PUSH_URL",
+            &mut cfg.telemetry_push_url,
+            parse_url,
+        );
+        env.set_if(
+            "DIG_TELEMETRY_PUSH_BATCH",
+            &mut cfg.telemetry_push_batch,
+            |batch| *batch > 0,
+        );
+        env.set_if(
+            "DIG_TELEMETRY_PUSH_INTERVAL_SECS",
+            &mut cfg.telemetry_push_interval_secs,
+            |secs| *secs > 0,
+        );
+        env.set("DIG_COMPRESSION", &mut cfg.compression);
+        env.set("DIG_ROOT_INDEX", &mut cfg.root_index);
+        env.set("DIG_MAX_BODY_BYTES", &mut cfg.max_body_bytes);
+        match (env.raw("DIG_TLS_CERT"), env.raw("DIG_TLS_KEY")) {
+            (Some(cert), Some(key)) => {
+                cfg.tls = Some(TlsPaths {
+                    cert: PathBuf::from(cert),
+                    key: PathBuf::from(key),
+                });
+            }
+            (None, None) => {}
+            _ => env.problem("DIG_TLS_CERT and DIG_TLS_KEY must be set together"),
         }
-        if let Ok(value) = env::var("DIG_UI_RESERVED_CPU_PERCENT") {
-            cfg.ui_reserved_cpu_percent = value
-                .parse()
-                .map_err(|_| anyhow!("invalid DIG_UI_RESERVED_CPU_PERCENT: {value}"))?;
+
+        cfg.dataset_dir = cfg.data_path(&cfg.dataset_dir);
+        cfg.log_file = cfg.log_file.as_deref().map(|path| cfg.data_path(path));
+        if let Some(tls) = &cfg.tls {
+            cfg.tls = Some(TlsPaths {
+                cert: cfg.data_path(&tls.cert),
+                key: cfg.data_path(&tls.key),
+            });
         }
-        if let Ok(value) = env::var("DIG_UI_RESERVED_GPU_PERCENT") {
-            cfg.ui_reserved_gpu_percent = value
-                .parse()
-                .map_err(|_| anyhow!("invalid DIG_UI_RESERVED_GPU_PERCENT: {value}"))?;
+        let mut problems = env.problems;
+        problems.extend(cfg.problems());
+        if problems.is_empty() {
+            Ok(cfg)
+        } else {
+            Err(ConfigError { problems }.into())
+        }
+    }
+
+    /// GPU temperature at which `mode` throttles: its override if one is
+    /// configured, else `thermal_limit_c`.
+    pub fn thermal_limit_for(&self, mode: PerformanceMode) -> f32 {
+        self.mode_thermal_limits
+            .get(&mode)
+            .copied()
+            .unwrap_or(self.thermal_limit_c)
+    }
+
+    /// Longest a worker iteration may take before it counts as slow, or
+    /// `None` with the check disabled.
+    pub fn tick_budget(&self) -> Option<Duration> {
+        (self.tick_budget_fraction > 0.0).then(|| {
+            Duration::from_millis(self.poll_interval_ms).mul_f32(self.tick_budget_fraction)
+        })
+    }
+
+    pub fn smoothing_window(&self) -> Duration {
+        Duration::from_secs_f32(self.smoothing_window_secs)
+    }
+
+    /// Resolves `path` against `data_dir` unless it is already absolute.
+    pub fn data_path(&self, path: &Path) -> PathBuf {
+        self.data_dir.join(path)
+    }
+
+    /// Creates `data_dir` (owner-only on unix) if it doesn't exist yet.
+    pub fn create_data_dir(&self) -> Result<()> {
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder
+            .create(&self.data_dir)
+            .with_context(|| format!("creating data dir {}", self.data_dir.display()))
+    }
+
+    /// Records the GPU's slowdown temperature and, unless
+    /// `DIG_THERMAL_LIMIT_C` is set, derives `thermal_limit_c` from it. A
+    /// derived limit that conflicts with the other thermal
+    /// settings is logged and the configured one kept.
+    pub fn with_gpu_slowdown(mut self, slowdown_c: Option<f32>) -> Self {
+        self.gpu_slowdown_c = slowdown_c;
+        let Some(slowdown_c) =
+            slowdown_c.filter(|_| self.thermal_limit_source != ThermalLimitSource::Config)
+        else {
+            return self;
+        };
+        let derived = Config {
+            thermal_limit_c: slowdown_c - self.hardware_thermal_margin_c,
+            thermal_limit_source: ThermalLimitSource::Hardware,
+            ..self.clone()
+        };
+        let problems = derived.thermal_problems();
+        if problems.is_empty() {
+            return derived;
+        }
+        warn!(
+            "not deriving the thermal limit from the {slowdown_c}C GPU slowdown temperature, keeping {}C: {}",
+            self.thermal_limit_c,
+            problems.join("; ")
+        );
+        self
+    }
+
+    /// Checks invariants that span several fields. Run after all sources
+    /// have been merged so the final combination is what gets checked.
+    pub fn validate(&self) -> Result<()> {
+        let problems = self.problems();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError { problems }.into())
+        }
+    }
+
+    /// Every cross-field violation, in the order `validate` checks them.
+    fn problems(&self) -> Vec<String> {
+        let mut problems = self.thermal_problems();
+        if !self.worker_process_patterns.is_empty()
+            && !self.cgroup_files.contains(&CgroupFile::Procs)
+        {
+            problems
+                .push("DIG_WORKER_PROCESSES needs cgroup.procs in DIG_CGROUP_FILES".to_string());
         }
+        if self.poll_interval_ms == 0 {
+            problems.push("DIG_POLL_INTERVAL_MS must be greater than 0".to_string());
+        }
+        if self.api_read_key.is_some() && self.api_admin_key.is_none() {
+            problems.push(
+                "DIG_API_READ_KEY needs DIG_API_ADMIN_KEY, or nothing could change the daemon"
+                    .to_string(),
+            );
+        }
+        if self.api_read_key.is_some() && self.api_read_key == self.api_admin_key {
+            problems.push("DIG_API_READ_KEY and DIG_API_ADMIN_KEY must differ".to_string());
+        }
+        if self.idle_poll_after_secs > 0 && self.idle_poll_max_ms < self.poll_interval_ms {
+            problems.push(format!(
+                "DIG_IDLE_POLL_MAX_MS ({}) must be at least DIG_POLL_INTERVAL_MS ({})",
+                self.idle_poll_max_ms, self.poll_interval_ms
+            ));
+        }
+        if self.gpu_temp_min_c >= self.gpu_temp_max_c {
+            problems.push(format!(
+                "DIG_GPU_TEMP_MIN_C ({}) must be below DIG_GPU_TEMP_MAX_C ({})",
+                self.gpu_temp_min_c, self.gpu_temp_max_c
+            ));
+        }
+        if self.ui_reserved_cpu_percent > 100 || self.ui_reserved_gpu_percent > 100 {
+            problems.push("DIG_UI_RESERVED_*_PERCENT must be at most 100".to_string());
+        }
+        for mode in PerformanceMode::ALL {
+            let warnings = match allocation_for_mode(mode, self).validate() {
+                Ok(warnings) => warnings,
+                Err(error) => {
+                    problems.push(format!("invalid allocation {error}"));
+                    continue;
+                }
+            };
+            for warning in warnings {
+                if self.allocation_strict {
+                    problems.push(format!("implausible allocation {warning}"));
+                } else {
+                    warn!("implausible allocation {warning}");
+                }
+            }
+        }
+        problems
+    }
+
+    /// The thermal limits must stay ordered: resume below every limit, the
+    /// critical temperature above them all.
+    fn thermal_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.thermal_limit_c - self.thermal_resume_c < 1.0 {
+            problems.push(format!(
+                "DIG_THERMAL_RESUME_C ({}) must be at least 1C below DIG_THERMAL_LIMIT_C ({})",
+                self.thermal_resume_c, self.thermal_limit_c
+            ));
+        }
+        for (mode, limit) in &self.mode_thermal_limits {
+            if *limit - self.thermal_resume_c < 1.0 || *limit > self.gpu_temp_max_c {
+                problems.push(format!(
+                    "DIG_MODE_THERMAL_LIMITS: {mode:?} limit {limit}C must be at least 1C above DIG_THERMAL_RESUME_C ({}) and at most DIG_GPU_TEMP_MAX_C ({})",
+                    self.thermal_resume_c,
+                    self.gpu_temp_max_c
+                ));
+            }
+        }
+        let highest_limit = PerformanceMode::ALL
+            .into_iter()
+            .map(|mode| self.thermal_limit_for(mode))
+            .fold(f32::MIN, f32::max);
+        if self.thermal_critical_c <= highest_limit {
+            problems.push(format!(
+                "DIG_THERMAL_CRITICAL_C ({}) must be above every thermal limit (highest is {highest_limit}C)",
+                self.thermal_critical_c
+            ));
+        }
+        problems
+    }
+
+    /// Merges a reloaded config over the running one. The listener settings
+    /// (`bind_addr`, `tls`, `max_body_bytes`, `compression`) are only read at
+    /// startup, as are logging (`log_file`, `log_stdout`),
+    /// `max_concurrent_collections` and `max_stream_subscribers`, so changes
+    /// to them are ignored with a warning.
+    pub fn reloaded(&self, mut fresh: Config) -> Config {
+        if fresh.bind_addr != self.bind_addr {
+            warn!(
+                "config reload: DIG_DAEMON_ADDR change to {} ignored until restart",
+                fresh.bind_addr
+            );
+            fresh.bind_addr = self.bind_addr;
+        }
+        if fresh.tls != self.tls {
+            warn!("config reload: DIG_TLS_CERT/DIG_TLS_KEY change ignored until restart");
+            fresh.tls = self.tls.clone();
+        }
+        if fresh.max_body_bytes != self.max_body_bytes {
+            warn!("config reload: DIG_MAX_BODY_BYTES change ignored until restart");
+            fresh.max_body_bytes = self.max_body_bytes;
+        }
+        if fresh.compression != self.compression {
+            warn!("config reload: DIG_COMPRESSION change ignored until restart");
+            fresh.compression = self.compression;
+        }
+        if fresh.log_file != self.log_file || fresh.log_stdout != self.log_stdout {
+            warn!("config reload: DIG_LOG_FILE/DIG_LOG_STDOUT change ignored until restart");
+            fresh.log_file = self.log_file.clone();
+            fresh.log_stdout = self.log_stdout;
+        }
+        if fresh.max_concurrent_collections != self.max_concurrent_collections {
+            warn!("config reload: DIG_MAX_CONCURRENT_COLLECTIONS change ignored until restart");
+            fresh.max_concurrent_collections = self.max_concurrent_collections;
+        }
+        if fresh.max_stream_subscribers != self.max_stream_subscribers {
+            warn!("config reload: DIG_MAX_STREAM_SUBSCRIBERS change ignored until restart");
+            fresh.max_stream_subscribers = self.max_stream_subscribers;
+        }
+        // The GPU is only queried at startup.
+        fresh.with_gpu_slowdown(self.gpu_slowdown_c)
+    }
+}
+
+/// Parses `render=60,medical=100` into a domain -> percent map.
+fn parse_domain_shares(value: &str) -> Option<BTreeMap<String, u8>> {
+    let mut shares = BTreeMap::new();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (domain, share) = entry.split_once('=')?;
+        let share: u8 = share.trim().parse().ok()?;
+        if domain.trim().is_empty() || share > 100 {
+            return None;
+        }
+        shares.insert(domain.trim().to_string(), share);
+    }
+    Some(shares)
+}
+
+/// System hostname, or `unknown`.
+fn default_node_id() -> String {
+    System::new()
+        .host_name()
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Platform data directory (e.g. `~/.local/share/dig-os` on Linux), or the
+/// working directory when no home directory is known.
+fn default_data_dir() -> PathBuf {
+    ProjectDirs::from("", "", "dig-os")
+        .map(|dirs| dirs.data_dir().to_path_buf())
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// Parses `gaming=fps-first,sleep=overnight` into a mode -> label map.
+fn parse_mode_profiles(value: &str) -> Option<BTreeMap<PerformanceMode, String>> {
+    let mut profiles = BTreeMap::new();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (mode, profile) = entry.split_once('=')?;
+        let profile = profile.trim();
+        if profile.is_empty() {
+            return None;
+        }
+        profiles.insert(mode.parse().ok()?, profile.to_string());
+    }
+    Some(profiles)
+}
+
+/// Parses `gaming=6-7;sleep=0-5,8` into a mode -> cpuset list map. Entries
+/// are `;`-separated since the lists themselves contain commas.
+fn parse_mode_cpusets(value: &str) -> Option<BTreeMap<PerformanceMode, String>> {
+    let mut cpusets = BTreeMap::new();
+    for entry in value.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let (mode, cpus) = entry.split_once('=')?;
+        let cpus = cpus.trim();
+        if !valid_cpu_list(cpus) {
+            return None;
+        }
+        cpusets.insert(mode.parse().ok()?, cpus.to_string());
+    }
+    Some(cpusets)
+}
+
+/// Parses `gaming=25/40,sleep=5/90` into a mode -> (UI, worker) VRAM
+/// percentage map.
+fn parse_mode_gpu_memory(value: &str) -> Option<BTreeMap<PerformanceMode, (u8, u8)>> {
+    let mut shares = BTreeMap::new();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (mode, split) = entry.split_once('=')?;
+        let (ui, worker) = split.split_once('/')?;
+        let percent = |n: &str| n.trim().parse::<u8>().ok().filter(|p| *p <= 100);
+        shares.insert(mode.parse().ok()?, (percent(ui)?, percent(worker)?));
+    }
+    Some(shares)
+}
+
+/// Whether `cpus` is a non-empty kernel CPU list: `3`, `0-3`, `0-3,8,10-11`.
+pub(crate) fn valid_cpu_list(cpus: &str) -> bool {
+    let cpu = |n: &str| n.parse::<u32>().ok();
+    !cpus.is_empty()
+        && cpus.split(',').all(|item| match item.split_once('-') {
+            Some((first, last)) => matches!((cpu(first), cpu(last)), (Some(a), Some(b)) if a <= b),
+            None => cpu(item).is_some(),
+        })
+}
+
+/// Parses `gaming=90,sleep=78` into a mode -> Celsius map.
+fn parse_mode_limits(value: &str) -> Option<BTreeMap<PerformanceMode, f32>> {
+    let mut limits = BTreeMap::new();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (mode, limit) = entry.split_once('=')?;
+        let limit: f32 = limit.trim().parse().ok()?;
+        limits.insert(mode.parse().ok()?, limit);
+    }
+    Some(limits)
+}
+
+/// Parses `temps=1,earnings=6` over the default precision. Groups not
+/// named keep their default.
+fn parse_precision(value: &str) -> Option<TelemetryPrecision> {
+    let mut precision = TelemetryPrecision::default();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (group, places) = entry.split_once('=')?;
+        let places: u8 = places
+            .trim()
+            .parse()
+            .ok()
+            .filter(|places| *places <= TelemetryPrecision::MAX_PLACES)?;
+        match group.trim() {
+            "temps" => precision.temps = places,
+            "loads" => precision.loads = places,
+            "earnings" => precision.earnings = places,
+            "other" => precision.other = places,
+            _ => return None,
+        }
+    }
+    Some(precision)
+}
+
+/// Parses `gaming,balanced` into a set of modes.
+fn parse_mode_list(value: &str) -> Option<BTreeSet<PerformanceMode>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(|mode| mode.parse().ok())
+        .collect()
+}
+
+/// Parses `gaming=none,sleep=med-pancreas-001` into per-mode missions.
+fn parse_mode_missions(value: &str) -> Option<BTreeMap<PerformanceMode, Option<String>>> {
+    let mut missions = BTreeMap::new();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (mode, mission) = entry.split_once('=')?;
+        let mode: PerformanceMode = mode.parse().ok()?;
+        let mission = match mission.trim() {
+            "" => return None,
+            "none" => None,
+            id => Some(id.to_string()),
+        };
+        missions.insert(mode, mission);
+    }
+    Some(missions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_env_reports_every_bad_variable() {
+        let bad = [
+            ("DIG_POLL_INTERVAL_MS", "often"),
+            ("DIG_NODE_ID", "  "),
+            ("DIG_AUTOPILOT_MIN_SCALE", "2"),
+            ("DIG_GPU_SOURCES", "nvidia-smi,cuda"),
+            ("DIG_WEBHOOK_URL", "not a url"),
+            ("DIG_TLS_CERT", "cert.pem"),
+        ];
+        let vars: BTreeMap<_, _> = bad.into_iter().collect();
+        let result = Config::from_lookup(|name| vars.get(name).map(|value| value.to_string()));
+
+        let error = result.expect_err("bad values should be rejected");
+        let problems = &error.downcast_ref::<ConfigError>().unwrap().problems;
+        assert_eq!(problems.len(), bad.len(), "{problems:?}");
+        for (name, value) in &bad[..5] {
+            assert!(problems.contains(&format!("invalid {name}: {value}")));
+        }
+        assert!(problems.contains(&"DIG_TLS_CERT and DIG_TLS_KEY must be set together".into()));
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn resume_must_be_a_degree_below_the_limit() {
+        let at = |thermal_resume_c| Config {
+            thermal_limit_c: 85.0,
+            thermal_resume_c,
+            ..Config::default()
+        };
+        assert!(at(84.0).validate().is_ok());
+        for resume in [84.5, 85.0, 90.0] {
+            let cfg = at(resume);
+            let error = cfg.validate().unwrap_err();
+            assert!(
+                error.to_string().contains("DIG_THERMAL_RESUME_C"),
+                "{error}"
+            );
+        }
+    }
 
-        Ok(cfg)
+    #[test]
+    fn validate_reports_all_cross_field_problems() {
+        let cfg = Config {
+            thermal_resume_c: 90.0,
+            poll_interval_ms: 0,
+            api_read_key: ApiKey::new("read"),
+            ..Config::default()
+        };
+        let problems = cfg.problems();
+        assert_eq!(problems.len(), 3, "{problems:?}");
+        let error = cfg.validate().unwrap_err();
+        assert!(error.to_string().starts_with("3 configuration problems:"));
     }
 }
 
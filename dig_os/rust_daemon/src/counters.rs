@@ -0,0 +1,57 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+/// Failures since startup that are otherwise only logged, so a rising rate
+/// can be alerted on. Shared by reference with the collectors and cgroup
+/// writers; bumping is a relaxed atomic add.
+#[derive(Debug, Default)]
+pub struct ErrorCounters {
+    gpu_read_failures: AtomicU64,
+    cgroup_write_failures: AtomicU64,
+    parse_failures: AtomicU64,
+    telemetry_push_failures: AtomicU64,
+}
+
+/// Point-in-time copy of `ErrorCounters` for responses.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ErrorCounts {
+    /// GPU tool runs that failed to start, exited non-zero or had no line
+    /// for the compute GPU.
+    pub gpu_read_failures: u64,
+    /// cgroup files or directories that could not be written.
+    pub cgroup_write_failures: u64,
+    /// GPU tool output with unparseable values, including ones spoilt by
+    /// bytes that weren't UTF-8 (`N/A` style placeholders don't count).
+    pub parse_failures: u64,
+    /// Batches `telemetry_push_url` didn't accept.
+    pub telemetry_push_failures: u64,
+}
+
+impl ErrorCounters {
+    pub fn gpu_read_failed(&self) {
+        self.gpu_read_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cgroup_write_failed(&self) {
+        self.cgroup_write_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn parse_failed(&self) {
+        self.parse_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn telemetry_push_failed(&self) {
+        self.telemetry_push_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> ErrorCounts {
+        ErrorCounts {
+            gpu_read_failures: self.gpu_read_failures.load(Ordering::Relaxed),
+            cgroup_write_failures: self.cgroup_write_failures.load(Ordering::Relaxed),
+            parse_failures: self.parse_failures.load(Ordering::Relaxed),
+            telemetry_push_failures: self.telemetry_push_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
@@ -0,0 +1,153 @@
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::{
+    missions::StallAction,
+    scheduler::{Imbalance, PerformanceMode},
+};
+
+const EVENT_LOG_CAPACITY: usize = 256;
+/// Events a live subscriber may fall behind by before it misses some.
+const EVENT_STREAM_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    ThermalThrottle {
+        reason: ThrottleReason,
+        gpu_index: u32,
+        temp_c: f32,
+        limit_c: f32,
+        previous_mode: PerformanceMode,
+        new_mode: PerformanceMode,
+    },
+    ThermalResume {
+        gpu_index: u32,
+        temp_c: f32,
+        resume_c: f32,
+        restored_mode: PerformanceMode,
+    },
+    ThermalEmergency {
+        gpu_index: u32,
+        temp_c: f32,
+        critical_c: f32,
+        mode: PerformanceMode,
+    },
+    ThermalEmergencyCleared {
+        gpu_index: u32,
+        temp_c: f32,
+        resume_c: f32,
+    },
+    ScheduledModeChange {
+        previous_mode: PerformanceMode,
+        new_mode: PerformanceMode,
+        /// Whether a window started; `false` when the last one ended and
+        /// the startup mode returned.
+        window_started: bool,
+    },
+    UiContactLost {
+        idle_secs: u64,
+        previous_mode: PerformanceMode,
+        new_mode: PerformanceMode,
+    },
+    MemoryPressure {
+        available_mib: u64,
+        min_free_mib: u64,
+    },
+    MemoryRecovered {
+        available_mib: u64,
+    },
+    CooldownStarted {
+        until: DateTime<Utc>,
+        throttles: u32,
+        window_secs: u64,
+    },
+    CooldownEnded,
+    BalanceSuggestion {
+        imbalance: Imbalance,
+        suggestion: String,
+    },
+    TelemetryAnomaly {
+        anomaly: String,
+    },
+    MissionCompleted {
+        mission_id: String,
+        bounty_dig: f32,
+        xp: u64,
+    },
+    MissionStalled {
+        mission_id: String,
+        stalled_secs: i64,
+        action: StallAction,
+    },
+    WorkerLost {
+        pid: u32,
+        last_seen: DateTime<Utc>,
+    },
+    WorkerRecovered {
+        pid: u32,
+    },
+}
+
+/// Machine-readable cause attached to thermal events.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ThrottleReason {
+    GpuOverLimit,
+}
+
+/// Bounded in-memory log; the oldest events are dropped first. Every push
+/// is also broadcast to live subscribers.
+#[derive(Debug)]
+pub struct EventLog {
+    events: VecDeque<Event>,
+    live: broadcast::Sender<Event>,
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        let (live, _) = broadcast::channel(EVENT_STREAM_CAPACITY);
+        Self {
+            events: VecDeque::new(),
+            live,
+        }
+    }
+}
+
+impl EventLog {
+    pub fn push(&mut self, kind: EventKind) -> &Event {
+        if self.events.len() == EVENT_LOG_CAPACITY {
+            self.events.pop_front();
+        }
+        let event = Event {
+            timestamp: Utc::now(),
+            kind,
+        };
+        // No subscribers is the common case, not an error.
+        let _ = self.live.send(event.clone());
+        self.events.push_back(event);
+        self.events.back().expect("just pushed")
+    }
+
+    /// Receives every event pushed from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.live.subscribe()
+    }
+
+    /// Up to `limit` most recent events, oldest first.
+    pub fn recent(&self, limit: usize) -> Vec<Event> {
+        let skip = self.events.len().saturating_sub(limit);
+        self.events.iter().skip(skip).cloned().collect()
+    }
+}
+
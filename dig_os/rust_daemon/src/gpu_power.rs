@@ -0,0 +1,169 @@
+use std::{process::Command, sync::Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use tracing::info;
+
+use crate::config::Config;
+
+/// Board power cap for a worker share of `percent` (clamped to 0..=100),
+/// scaled linearly between the card's minimum and maximum limits and
+/// rounded to whole watts, which every driver accepts.
+pub fn power_limit_watts(percent: u8, min_w: f32, max_w: f32) -> f32 {
+    let share = percent.min(100) as f32 / 100.0;
+    (min_w + (max_w - min_w) * share).round()
+}
+
+/// The card's adjustable power range in watts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowerLimits {
+    pub min_w: f32,
+    pub max_w: f32,
+}
+
+/// Caps the compute GPU's power draw at the worker GPU share via
+/// `nvidia-smi -pl`. Needs root (or equivalent), so it only exists when
+/// `gpu_power_limit` is enabled. The range is read once, at startup.
+#[derive(Debug)]
+pub struct PowerCap {
+    limits: PowerLimits,
+    /// Latest wanted cap in watts; `None` after a failed write, so that the
+    /// next request retries it.
+    wanted: Mutex<Option<f32>>,
+    /// Cap last written to the card. Held for the whole write, so writes
+    /// never overlap and a late one can't undo a newer value.
+    written: Mutex<Option<f32>>,
+}
+
+impl PowerCap {
+    pub fn new(limits: PowerLimits) -> Self {
+        Self {
+            limits,
+            wanted: Mutex::new(None),
+            written: Mutex::new(None),
+        }
+    }
+
+    /// Records `worker_gpu_percent` as the wanted cap. Returns whether that
+    /// changed the wattage, i.e. whether `write` has anything to do.
+    pub fn request(&self, worker_gpu_percent: u8) -> bool {
+        let watts = power_limit_watts(worker_gpu_percent, self.limits.min_w, self.limits.max_w);
+        let mut wanted = self.wanted.lock().unwrap_or_else(|e| e.into_inner());
+        wanted.replace(watts) != Some(watts)
+    }
+
+    /// Writes the latest wanted cap unless the card already has it.
+    /// Blocking; call from `spawn_blocking`.
+    pub fn write(&self, cfg: &Config) -> Result<()> {
+        let mut written = self.written.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(watts) = *self.wanted.lock().unwrap_or_else(|e| e.into_inner()) else {
+            return Ok(());
+        };
+        if *written == Some(watts) {
+            return Ok(());
+        }
+        if let Err(error) = set_limit(watts, cfg) {
+            let mut wanted = self.wanted.lock().unwrap_or_else(|e| e.into_inner());
+            if *wanted == Some(watts) {
+                *wanted = None;
+            }
+            return Err(error);
+        }
+        *written = Some(watts);
+        let PowerLimits { min_w, max_w } = self.limits;
+        info!(
+            "gpu {} power limit set to {watts:.0}W (range {min_w}-{max_w}W)",
+            cfg.compute_gpu_index
+        );
+        Ok(())
+    }
+}
+
+fn set_limit(watts: f32, cfg: &Config) -> Result<()> {
+    let index = cfg.compute_gpu_index.to_string();
+    let output = Command::new(&cfg.gpu_smi_cmd)
+        .args(["-i", &index, "-pl", &format!("{watts:.0}")])
+        .output()
+        .with_context(|| format!("running {}", cfg.gpu_smi_cmd))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} -pl {watts:.0} failed: {}",
+            cfg.gpu_smi_cmd,
+            String::from_utf8_lossy(&output.stdout).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// The card's `power.min_limit` and `power.max_limit`.
+pub fn read_limits(cfg: &Config) -> Result<PowerLimits> {
+    let index = cfg.compute_gpu_index.to_string();
+    let output = Command::new(&cfg.gpu_smi_cmd)
+        .args([
+            "-i",
+            &index,
+            "--query-gpu=power.min_limit,power.max_limit",
+            "--format=csv,noheader,nounits",
+        ])
+        .output()
+        .with_context(|| format!("running {}", cfg.gpu_smi_cmd))?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    let line = text.lines().next().unwrap_or_default();
+    let mut fields = line
+        .split(',')
+        .map(|field| field.trim().parse::<f32>().ok());
+    match (fields.next().flatten(), fields.next().flatten()) {
+        (Some(min_w), Some(max_w)) if output.status.success() && min_w <= max_w => {
+            Ok(PowerLimits { min_w, max_w })
+        }
+        _ => Err(anyhow!(
+            "gpu {} reports no adjustable power limits: {}",
+            cfg.compute_gpu_index,
+            line.trim()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use super::*;
+
+    #[test]
+    fn unchanged_wattage_is_not_rewritten() {
+        let dir = std::env::temp_dir().join(format!("dig-power-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("calls");
+        let _ = fs::remove_file(&log);
+        let tool = dir.join("nvidia-smi");
+        fs::write(
+            &tool,
+            format!("#!/bin/sh\necho \"$@\" >> {}\n", log.display()),
+        )
+        .unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+        let cfg = Config {
+            gpu_smi_cmd: tool.display().to_string(),
+            ..Config::default()
+        };
+        let cap = PowerCap::new(PowerLimits {
+            min_w: 100.0,
+            max_w: 300.0,
+        });
+
+        assert!(cap.request(50));
+        cap.write(&cfg).unwrap();
+        assert!(!cap.request(50));
+        cap.write(&cfg).unwrap();
+        assert!(cap.request(100));
+        cap.write(&cfg).unwrap();
+
+        let calls = fs::read_to_string(&log).unwrap();
+        assert_eq!(
+            calls.lines().collect::<Vec<_>>(),
+            ["-i 0 -pl 200", "-i 0 -pl 300"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
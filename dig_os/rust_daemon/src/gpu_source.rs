@@ -0,0 +1,423 @@
+use std::{borrow::Cow, process::Command, str::FromStr};
+
+use serde::Serialize;
+use tracing::debug;
+
+use crate::{
+    config::Config,
+    counters::ErrorCounters,
+    telemetry::{synthetic_gpu, GpuReading, TelemetrySource},
+};
+
+/// What a GPU source may look at for one sample.
+pub struct CollectContext<'a> {
+    pub cfg: &'a Config,
+    pub errors: &'a ErrorCounters,
+    /// This sample's CPU load, which the synthetic source follows.
+    pub cpu_load: f32,
+}
+
+/// One way of reading the `compute_gpu_index`-th GPU. A new vendor tool is
+/// added by implementing this and listing it in `GpuBackend`.
+pub trait GpuSource: Send + Sync {
+    /// What snapshots built from this source's readings report as `source`.
+    fn kind(&self) -> TelemetrySource;
+
+    /// `None` when the source can't deliver a reading right now, so the
+    /// next one in `gpu_sources` is tried.
+    fn collect(&self, ctx: &CollectContext<'_>) -> Option<GpuReading>;
+}
+
+/// A GPU source that can be named in `DIG_GPU_SOURCES`.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum GpuBackend {
+    /// The NVIDIA driver library; only with the `nvml` feature.
+    Nvml,
+    NvidiaSmi,
+    RocmSmi,
+    /// Always delivers, so any source after it is never tried.
+    Synthetic,
+}
+
+impl FromStr for GpuBackend {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "nvml" => Ok(Self::Nvml),
+            "nvidia-smi" => Ok(Self::NvidiaSmi),
+            "rocm-smi" => Ok(Self::RocmSmi),
+            "synthetic" => Ok(Self::Synthetic),
+            _ => Err(()),
+        }
+    }
+}
+
+impl GpuBackend {
+    /// The default order: NVML (where built in), then `nvidia-smi`.
+    pub fn defaults() -> Vec<Self> {
+        let nvidia_smi = [Self::NvidiaSmi];
+        if cfg!(feature = "nvml") {
+            [&[Self::Nvml][..], &nvidia_smi].concat()
+        } else {
+            nvidia_smi.to_vec()
+        }
+    }
+
+    /// Whether this reads through the NVIDIA driver or its tool.
+    pub fn nvidia(self) -> bool {
+        matches!(self, Self::Nvml | Self::NvidiaSmi)
+    }
+
+    pub fn source(self) -> &'static dyn GpuSource {
+        match self {
+            Self::Nvml => &Nvml,
+            Self::NvidiaSmi => &NvidiaSmi,
+            Self::RocmSmi => &RocmSmi,
+            Self::Synthetic => &Synthetic,
+        }
+    }
+}
+
+/// The first reading from `backends`, in order, that has a load or a
+/// temperature, and the kind of source it came from.
+pub fn collect(
+    backends: &[GpuBackend],
+    ctx: &CollectContext<'_>,
+) -> Option<(GpuReading, TelemetrySource)> {
+    backends.iter().find_map(|backend| {
+        let source = backend.source();
+        source
+            .collect(ctx)
+            .filter(|reading| reading.util.is_some() || reading.temp.is_some())
+            .map(|reading| (reading, source.kind()))
+    })
+}
+
+struct Nvml;
+
+impl GpuSource for Nvml {
+    fn kind(&self) -> TelemetrySource {
+        TelemetrySource::RealNvidia
+    }
+
+    #[cfg(feature = "nvml")]
+    fn collect(&self, ctx: &CollectContext<'_>) -> Option<GpuReading> {
+        crate::nvml::read(ctx.cfg.compute_gpu_index)
+    }
+
+    #[cfg(not(feature = "nvml"))]
+    fn collect(&self, _ctx: &CollectContext<'_>) -> Option<GpuReading> {
+        None
+    }
+}
+
+/// One CSV line per device from `gpu_smi_cmd`. Failures are counted in
+/// `errors` as well as falling through to the next source.
+struct NvidiaSmi;
+
+impl GpuSource for NvidiaSmi {
+    fn kind(&self) -> TelemetrySource {
+        TelemetrySource::RealNvidia
+    }
+
+    fn collect(&self, ctx: &CollectContext<'_>) -> Option<GpuReading> {
+        let (cfg, errors) = (ctx.cfg, ctx.errors);
+        let output = Command::new(&cfg.gpu_smi_cmd)
+            .args([
+                "--query-gpu=utilization.gpu,temperature.gpu,memory.used,power.draw",
+                "--format=csv,noheader,nounits",
+            ])
+            .output();
+        let output = match output {
+            Ok(output) if output.status.success() => output,
+            _ => {
+                errors.gpu_read_failed();
+                return None;
+            }
+        };
+
+        // A stray byte from a misconfigured locale only spoils the field it
+        // lands in; `parse_gpu_line` treats that one as garbled.
+        let text = String::from_utf8_lossy(&output.stdout);
+        if let Cow::Owned(_) = text {
+            debug!(
+                "{} output was not valid UTF-8; decoded lossily",
+                cfg.gpu_smi_cmd
+            );
+        }
+        let Some(line) = text.lines().nth(cfg.compute_gpu_index as usize) else {
+            errors.gpu_read_failed();
+            return None;
+        };
+        let reading = parse_gpu_line(line);
+        if reading.garbled {
+            errors.parse_failed();
+        }
+        Some(reading.reading)
+    }
+}
+
+/// A parsed line, and whether any field held something other than a
+/// number or an unsupported-value placeholder.
+struct ParsedGpuLine {
+    reading: GpuReading,
+    garbled: bool,
+}
+
+/// Parses one `utilization.gpu,temperature.gpu,memory.used,power.draw`
+/// line. Drivers differ in padding and report unsupported fields as `N/A`
+/// or `[Not Supported]`; those (and anything else unparseable, which also
+/// marks the line garbled) become `None` for that field only.
+fn parse_gpu_line(line: &str) -> ParsedGpuLine {
+    let mut garbled = false;
+    let mut fields = line.split(',').map(|field| {
+        parse_gpu_field(field).unwrap_or_else(|| {
+            garbled = true;
+            None
+        })
+    });
+    let reading = GpuReading {
+        util: fields.next().flatten(),
+        temp: fields.next().flatten(),
+        memory_used_mib: fields.next().flatten(),
+        power_w: fields.next().flatten(),
+        temp_clamped: false,
+    };
+    ParsedGpuLine { reading, garbled }
+}
+
+/// `Some(None)` for an unsupported-value placeholder, `None` for anything
+/// that isn't a finite number either.
+fn parse_gpu_field(field: &str) -> Option<Option<f32>> {
+    let field = field
+        .trim()
+        .trim_end_matches('%')
+        .trim_end_matches('C')
+        .trim_end_matches("MiB")
+        .trim_end_matches('W')
+        .trim();
+    match field {
+        "" | "N/A" | "[N/A]" | "[Not Supported]" => Some(None),
+        value => value
+            .parse::<f32>()
+            .ok()
+            .filter(|v| v.is_finite())
+            .map(Some),
+    }
+}
+
+/// `rocm_smi_cmd --json` for the `compute_gpu_index`-th AMD GPU. Failures
+/// are counted like `nvidia-smi`'s.
+struct RocmSmi;
+
+impl GpuSource for RocmSmi {
+    fn kind(&self) -> TelemetrySource {
+        TelemetrySource::RealAmd
+    }
+
+    fn collect(&self, ctx: &CollectContext<'_>) -> Option<GpuReading> {
+        let (cfg, errors) = (ctx.cfg, ctx.errors);
+        let output = Command::new(&cfg.rocm_smi_cmd)
+            .args([
+                "-d",
+                &cfg.compute_gpu_index.to_string(),
+                "--showuse",
+                "--showtemp",
+                "--showpower",
+                "--showmeminfo",
+                "vram",
+                "--json",
+            ])
+            .output();
+        let output = match output {
+            Ok(output) if output.status.success() => output,
+            _ => {
+                errors.gpu_read_failed();
+                return None;
+            }
+        };
+        let reading = parse_rocm_json(&String::from_utf8_lossy(&output.stdout));
+        if reading.is_none() {
+            errors.parse_failed();
+        }
+        reading
+    }
+}
+
+/// The first card in `rocm-smi --json` output. Values are strings; ones
+/// that don't parse are left out, like unsupported `nvidia-smi` fields.
+fn parse_rocm_json(text: &str) -> Option<GpuReading> {
+    let json: serde_json::Value = serde_json::from_str(text).ok()?;
+    let card = json
+        .as_object()?
+        .iter()
+        .find(|(key, _)| key.starts_with("card"))?
+        .1
+        .as_object()?;
+    let field = |matches: &dyn Fn(&str) -> bool| {
+        card.iter()
+            .filter(|(key, _)| matches(key))
+            .find_map(|(_, value)| value.as_str()?.trim().parse::<f32>().ok())
+            .filter(|value| value.is_finite())
+    };
+    Some(GpuReading {
+        util: field(&|key| key == "GPU use (%)"),
+        // The edge sensor matches what `nvidia-smi` reports; newer cards
+        // may only have junction or memory sensors.
+        temp: field(&|key| key == "Temperature (Sensor edge) (C)")
+            .or_else(|| field(&|key| key.starts_with("Temperature"))),
+        memory_used_mib: field(&|key| key == "VRAM Total Used Memory (B)")
+            .map(|bytes| bytes / (1024.0 * 1024.0)),
+        power_w: field(&|key| key.ends_with("Graphics Package Power (W)")),
+        temp_clamped: false,
+    })
+}
+
+/// Load following the CPU with a slow wave on top, and a temperature
+/// following the load.
+struct Synthetic;
+
+impl GpuSource for Synthetic {
+    fn kind(&self) -> TelemetrySource {
+        TelemetrySource::Synthetic
+    }
+
+    fn collect(&self, ctx: &CollectContext<'_>) -> Option<GpuReading> {
+        let (util, temp) = synthetic_gpu(ctx.cpu_load);
+        Some(GpuReading {
+            util: Some(util),
+            temp: Some(temp),
+            ..GpuReading::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use super::*;
+
+    #[test]
+    fn parses_padded_lines() {
+        let parsed = parse_gpu_line("  45, 67 ,  1024 , 120.50");
+        assert!(!parsed.garbled);
+        assert_eq!(parsed.reading.util, Some(45.0));
+        assert_eq!(parsed.reading.temp, Some(67.0));
+        assert_eq!(parsed.reading.memory_used_mib, Some(1024.0));
+        assert_eq!(parsed.reading.power_w, Some(120.5));
+    }
+
+    #[test]
+    fn unsupported_fields_become_none() {
+        // Laptop and older consumer drivers report what they can't measure
+        // with either placeholder.
+        for line in [
+            "12, 40, 512, [N/A]",
+            "12, 40, 512, N/A",
+            "12, 40, 512, [Not Supported]",
+        ] {
+            let parsed = parse_gpu_line(line);
+            assert!(!parsed.garbled, "{line}");
+            assert_eq!(parsed.reading.util, Some(12.0));
+            assert_eq!(parsed.reading.temp, Some(40.0));
+            assert_eq!(parsed.reading.power_w, None);
+        }
+    }
+
+    #[test]
+    fn units_are_stripped() {
+        assert_eq!(parse_gpu_field(" 87 %"), Some(Some(87.0)));
+        assert_eq!(parse_gpu_field("61C"), Some(Some(61.0)));
+        assert_eq!(parse_gpu_field("2048 MiB"), Some(Some(2048.0)));
+        assert_eq!(parse_gpu_field("95.3 W"), Some(Some(95.3)));
+    }
+
+    #[test]
+    fn garbage_spoils_only_its_field() {
+        let parsed = parse_gpu_line("hot, 70, 100, inf");
+        assert!(parsed.garbled);
+        assert_eq!(parsed.reading.util, None);
+        assert_eq!(parsed.reading.temp, Some(70.0));
+        assert_eq!(parsed.reading.memory_used_mib, Some(100.0));
+        assert_eq!(parsed.reading.power_w, None);
+    }
+
+    #[test]
+    fn short_lines_leave_the_rest_unset() {
+        let parsed = parse_gpu_line("30, 55");
+        assert!(!parsed.garbled);
+        assert_eq!(parsed.reading.temp, Some(55.0));
+        assert_eq!(parsed.reading.memory_used_mib, None);
+    }
+
+    #[test]
+    fn invalid_utf8_spoils_only_its_field() {
+        let text = String::from_utf8_lossy(b"47, 6\xff1, 2048, 80\n");
+        let parsed = parse_gpu_line(text.lines().next().unwrap());
+        assert!(parsed.garbled);
+        assert_eq!(parsed.reading.util, Some(47.0));
+        assert_eq!(parsed.reading.temp, None);
+        assert_eq!(parsed.reading.power_w, Some(80.0));
+    }
+
+    #[test]
+    fn nvidia_smi_output_that_isnt_utf8_still_reads() {
+        let dir = std::env::temp_dir().join(format!("dig-lossy-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let tool = dir.join("nvidia-smi");
+        fs::write(&tool, "#!/bin/sh\nprintf '47, 6\\3771, 2048, 80\\n'\n").unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+        let cfg = Config {
+            gpu_smi_cmd: tool.display().to_string(),
+            ..Config::default()
+        };
+        let errors = ErrorCounters::default();
+        let ctx = CollectContext {
+            cfg: &cfg,
+            errors: &errors,
+            cpu_load: 0.0,
+        };
+        let reading = NvidiaSmi
+            .collect(&ctx)
+            .expect("a reading despite the stray byte");
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(reading.util, Some(47.0));
+        assert_eq!(reading.temp, None);
+        assert_eq!(errors.counts().parse_failures, 1);
+        assert_eq!(errors.counts().gpu_read_failures, 0);
+    }
+
+    #[test]
+    fn parses_rocm_smi_json() {
+        let text = r#"{
+            "card0": {
+                "GPU use (%)": "63",
+                "Temperature (Sensor edge) (C)": "58.0",
+                "Temperature (Sensor junction) (C)": "71.0",
+                "Average Graphics Package Power (W)": "142.0",
+                "VRAM Total Memory (B)": "17163091968",
+                "VRAM Total Used Memory (B)": "2147483648"
+            }
+        }"#;
+        let reading = parse_rocm_json(text).unwrap();
+        assert_eq!(reading.util, Some(63.0));
+        assert_eq!(reading.temp, Some(58.0));
+        assert_eq!(reading.power_w, Some(142.0));
+        assert_eq!(reading.memory_used_mib, Some(2048.0));
+    }
+
+    #[test]
+    fn rocm_cards_without_an_edge_sensor_use_another() {
+        let text =
+            r#"{"card1": {"GPU use (%)": "N/A", "Temperature (Sensor junction) (C)": "66.0"}}"#;
+        let reading = parse_rocm_json(text).unwrap();
+        assert_eq!(reading.util, None);
+        assert_eq!(reading.temp, Some(66.0));
+        assert!(parse_rocm_json("not json").is_none());
+        assert!(parse_rocm_json(r#"{"system": {}}"#).is_none());
+    }
+}
+
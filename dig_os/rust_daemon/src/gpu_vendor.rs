@@ -0,0 +1,77 @@
+use std::{collections::BTreeSet, fs, path::Path};
+
+use serde::Serialize;
+
+const PCI_DEVICES: &str = "/sys/bus/pci/devices";
+/// PCI base class of display controllers (VGA, 3D, other).
+const DISPLAY_CLASS: u32 = 0x03;
+
+/// Maker of a display controller found on the PCI bus.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum GpuVendor {
+    Nvidia,
+    Amd,
+    Intel,
+    Other,
+}
+
+impl GpuVendor {
+    fn from_pci_id(id: u32) -> Self {
+        match id {
+            0x10de => Self::Nvidia,
+            0x1002 | 0x1022 => Self::Amd,
+            0x8086 => Self::Intel,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Vendors of every display controller in sysfs; empty where PCI devices
+/// can't be listed, e.g. off Linux or in some containers.
+pub fn detect() -> BTreeSet<GpuVendor> {
+    let Ok(devices) = fs::read_dir(PCI_DEVICES) else {
+        return BTreeSet::new();
+    };
+    let read_hex = |dir: &Path, file: &str| {
+        let raw = fs::read_to_string(dir.join(file)).ok()?;
+        u32::from_str_radix(raw.trim().trim_start_matches("0x"), 16).ok()
+    };
+    devices
+        .flatten()
+        .map(|device| device.path())
+        .filter(|dir| read_hex(dir, "class").is_some_and(|class| class >> 16 == DISPLAY_CLASS))
+        .filter_map(|dir| read_hex(&dir, "vendor"))
+        .map(GpuVendor::from_pci_id)
+        .collect()
+}
+
+/// Why the NVIDIA GPU tool won't deliver real readings on hardware with
+/// `vendors`, with the setting to change; `None` when it should, or when
+/// nothing was detected to judge by.
+pub fn tool_mismatch(
+    vendors: &BTreeSet<GpuVendor>,
+    tool: &str,
+    tool_present: bool,
+) -> Option<String> {
+    if vendors.is_empty() {
+        return None;
+    }
+    if !vendors.contains(&GpuVendor::Nvidia) {
+        let found: Vec<String> = vendors
+            .iter()
+            .map(|vendor| format!("{vendor:?}").to_lowercase())
+            .collect();
+        return Some(format!(
+            "no NVIDIA GPU found (detected: {}), but GPU telemetry is read with {tool}, which only supports NVIDIA; readings will be synthetic. Set DIG_GPU_SOURCES=rocm-smi for AMD, point DIG_GPU_SMI_CMD at a wrapper printing nvidia-smi's CSV, or set DIG_TELEMETRY_SOURCE=synthetic to make that explicit",
+            found.join(", ")
+        ));
+    }
+    if !tool_present {
+        return Some(format!(
+            "NVIDIA GPU found but {tool} can't be run; readings will be synthetic. Install the NVIDIA driver utilities or set DIG_GPU_SMI_CMD to the full path of nvidia-smi"
+        ));
+    }
+    None
+}
+
@@ -0,0 +1,187 @@
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::{
+    cgroups::{CgroupProbe, InitialApply},
+    config::Config,
+    counters::ErrorCounts,
+    state::RuntimeState,
+    telemetry::{DataQuality, TelemetrySource},
+    webhook::{self, WebhookDeliveries},
+};
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    Degraded,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthDetail {
+    pub status: Status,
+    pub telemetry: TelemetryHealth,
+    pub cgroups: CgroupHealth,
+    pub worker: WorkerHealth,
+    pub external_worker: ExternalWorkerHealth,
+    pub gpu_tool: GpuToolHealth,
+    /// Failure counts since startup; informational, they don't affect
+    /// `status`.
+    pub errors: ErrorCounts,
+    /// Mode-change webhook outcomes since startup; informational too.
+    pub webhook: WebhookDeliveries,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TelemetryHealth {
+    pub status: Status,
+    pub last_snapshot_age_ms: i64,
+    pub source: TelemetrySource,
+    pub data_quality: DataQuality,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CgroupHealth {
+    pub status: Status,
+    pub version: &'static str,
+    pub writable: bool,
+    pub applied: bool,
+    pub initial_apply: InitialApply,
+    pub initial_apply_attempts: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkerHealth {
+    pub status: Status,
+    pub state: &'static str,
+    pub last_tick_age_ms: i64,
+    pub last_tick_duration_ms: u64,
+    /// Ticks so far that took longer than `tick_budget_fraction` of the
+    /// poll interval.
+    pub slow_ticks: u64,
+}
+
+/// Liveness of the worker process the allocations are for, as reported
+/// through `POST /api/v1/worker/heartbeat`.
+#[derive(Debug, Serialize)]
+pub struct ExternalWorkerHealth {
+    pub status: Status,
+    /// `unknown` until the first heartbeat, then `alive` or `lost`.
+    pub state: &'static str,
+    pub pid: Option<u32>,
+    pub last_seen_age_ms: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GpuToolHealth {
+    pub status: Status,
+    pub present: bool,
+}
+
+/// Builds the per-subsystem report. Anything older than
+/// `RuntimeState::stale_after_ms` counts as stalled.
+pub fn detail(
+    runtime: &RuntimeState,
+    cfg: &Config,
+    cgroup: CgroupProbe,
+    gpu_tool_present: bool,
+    errors: ErrorCounts,
+) -> HealthDetail {
+    let now = Utc::now();
+    let stale_after_ms = runtime.stale_after_ms();
+
+    let snapshot_age = (now - runtime.telemetry.timestamp).num_milliseconds();
+    let data_quality = runtime.telemetry.quality_at(now, stale_after_ms);
+    let telemetry = TelemetryHealth {
+        status: match data_quality {
+            DataQuality::Stale => Status::Failed,
+            DataQuality::Synthetic | DataQuality::RealClamped => Status::Degraded,
+            DataQuality::Real => Status::Ok,
+        },
+        last_snapshot_age_ms: snapshot_age,
+        source: runtime.telemetry.source,
+        data_quality,
+    };
+
+    let cgroups = CgroupHealth {
+        status: match (cgroup.version, cgroup.writable && runtime.cgroups_applied) {
+            ("v2", true) => Status::Ok,
+            ("v2", false) => Status::Degraded,
+            _ => Status::Failed,
+        },
+        version: cgroup.version,
+        writable: cgroup.writable,
+        applied: runtime.cgroups_applied,
+        initial_apply: runtime.initial_cgroup_apply,
+        initial_apply_attempts: runtime.initial_cgroup_attempts,
+    };
+
+    let tick_age = (now - runtime.worker_tick_at).num_milliseconds();
+    let slow = cfg
+        .tick_budget()
+        .is_some_and(|budget| runtime.worker_tick_duration > budget);
+    let (status, state) = if tick_age > stale_after_ms {
+        (Status::Failed, "stalled")
+    } else if slow {
+        (Status::Degraded, "slow")
+    } else {
+        (Status::Ok, "running")
+    };
+    let worker = WorkerHealth {
+        status,
+        state,
+        last_tick_age_ms: tick_age,
+        last_tick_duration_ms: runtime.worker_tick_duration.as_millis() as u64,
+        slow_ticks: runtime.slow_worker_ticks,
+    };
+
+    let external_worker = ExternalWorkerHealth {
+        status: if runtime.heartbeat_expired(now, cfg) {
+            Status::Degraded
+        } else {
+            Status::Ok
+        },
+        state: match runtime.worker_heartbeat {
+            None => "unknown",
+            Some(_) if runtime.heartbeat_expired(now, cfg) => "lost",
+            Some(_) => "alive",
+        },
+        pid: runtime.worker_heartbeat.map(|heartbeat| heartbeat.pid),
+        last_seen_age_ms: runtime
+            .worker_heartbeat
+            .map(|heartbeat| (now - heartbeat.last_seen).num_milliseconds()),
+    };
+
+    let gpu_tool = GpuToolHealth {
+        status: if gpu_tool_present {
+            Status::Ok
+        } else {
+            Status::Degraded
+        },
+        present: gpu_tool_present,
+    };
+
+    let status = [
+        telemetry.status,
+        cgroups.status,
+        worker.status,
+        external_worker.status,
+        gpu_tool.status,
+    ]
+    .into_iter()
+    .max()
+    .unwrap_or(Status::Ok);
+
+    HealthDetail {
+        status,
+        telemetry,
+        cgroups,
+        worker,
+        external_worker,
+        gpu_tool,
+        errors,
+        webhook: webhook::deliveries(),
+    }
+}
+
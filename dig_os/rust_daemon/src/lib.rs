@@ -0,0 +1,26 @@
+pub mod api;
+pub mod auth;
+pub mod cgroups;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod config;
+pub mod counters;
+pub mod events;
+pub mod gpu_power;
+pub mod gpu_source;
+pub mod gpu_vendor;
+pub mod health;
+pub mod lifetime;
+pub mod missions;
+#[cfg(feature = "nvml")]
+mod nvml;
+pub mod procs;
+pub mod push;
+pub mod schedule;
+pub mod scheduler;
+pub mod selftest;
+pub mod smoothing;
+pub mod state;
+pub mod telemetry;
+pub mod webhook;
+
@@ -0,0 +1,76 @@
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::config::Config;
+
+const LIFETIME_FILE: &str = "lifetime.json";
+
+/// How often the running total is written back, bounding what a crash loses.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Counters accumulated over every run of the daemon on this machine.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct LifetimeCounters {
+    pub restarts: u64,
+    pub uptime_secs: u64,
+}
+
+/// Tracks this run on top of the counters persisted by previous ones in
+/// `<data dir>/lifetime.json`.
+pub struct Lifetime {
+    path: PathBuf,
+    prior: LifetimeCounters,
+    started: Instant,
+}
+
+impl Lifetime {
+    /// Loads the persisted counters and counts this boot as a restart if a
+    /// previous run left any. A missing file starts from zero; a corrupt one
+    /// is logged and replaced.
+    pub fn start(cfg: &Config) -> Self {
+        let path = cfg.data_path(LIFETIME_FILE.as_ref());
+        let prior = match fs::read_to_string(&path) {
+            Ok(text) => match serde_json::from_str::<LifetimeCounters>(&text) {
+                Ok(counters) => LifetimeCounters {
+                    restarts: counters.restarts + 1,
+                    ..counters
+                },
+                Err(error) => {
+                    warn!("ignoring unreadable {}: {error}", path.display());
+                    LifetimeCounters::default()
+                }
+            },
+            Err(_) => LifetimeCounters::default(),
+        };
+        Self {
+            path,
+            prior,
+            started: Instant::now(),
+        }
+    }
+
+    /// Counters including the time elapsed in this run.
+    pub fn current(&self) -> LifetimeCounters {
+        LifetimeCounters {
+            restarts: self.prior.restarts,
+            uptime_secs: self.prior.uptime_secs + self.started.elapsed().as_secs(),
+        }
+    }
+
+    /// Writes `current()` through a temporary file so a crash mid-write
+    /// can't leave a truncated file behind.
+    pub fn persist(&self) -> Result<()> {
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(&self.current())?)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
@@ -1,80 +1,859 @@
-mod api;
-mod cgroups;
-mod config;
-mod scheduler;
-mod state;
-mod telemetry;
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    path::Path,
+    sync::Arc,
+};
 
-use std::sync::Arc;
+use anyhow::{anyhow, Context, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use chrono::{Local, Utc};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{interval, sleep, Duration, Instant, Interval, MissedTickBehavior};
+use tracing::{debug, error, info, warn};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
-use anyhow::Result;
-use scheduler::{allocation_for_mode, PerformanceMode};
-use tokio::time::{sleep, Duration};
-use tracing::{info, warn};
+use dig_rust_daemon::{
+    api, cgroups,
+    cgroups::InitialApply,
+    config::Config,
+    counters::ErrorCounters,
+    events::{EventKind, EventLog, ThrottleReason},
+    gpu_vendor,
+    lifetime::{self, Lifetime},
+    missions::{self, StallAction},
+    procs, push, schedule,
+    scheduler::{
+        above_balanced, autopilot_scale, effective_allocation, machine_idle, memory_pressure,
+        next_poll_interval_ms, thermal_decision, thermal_emergency, throttle_lowers_worker,
+        BalanceTracker, CooldownTracker, PerformanceMode, ThermalAction, ThermalState,
+    },
+    selftest, state,
+    state::{AllocationControl, BalanceSuggestion, RuntimeState},
+    telemetry, webhook,
+    webhook::{ModeChange, ModeChangeReason},
+};
 
-use crate::{config::Config, state::RuntimeState};
+const INITIAL_APPLY_BACKOFF: Duration = Duration::from_secs(1);
+const LOG_FILTER: &str = "info";
+/// Exit status when the listen address is taken, so service managers and
+/// scripts can tell it apart from other startup failures.
+const EXIT_ADDR_IN_USE: i32 = 3;
+
+/// Installs the global subscriber: stdout unless disabled, plus a daily
+/// rotated file when `log_file` is set. The returned guard flushes the
+/// file writer on drop.
+fn init_logging(config: &Config) -> Result<Option<WorkerGuard>> {
+    let (file_layer, guard) = match &config.log_file {
+        Some(path) => {
+            let dir = path.parent().unwrap_or(Path::new("."));
+            let name = path
+                .file_name()
+                .ok_or_else(|| anyhow!("DIG_LOG_FILE has no file name: {}", path.display()))?;
+            std::fs::create_dir_all(dir)?;
+            let (writer, guard) =
+                tracing_appender::non_blocking(tracing_appender::rolling::daily(dir, name));
+            (
+                Some(fmt::layer().with_ansi(false).with_writer(writer)),
+                Some(guard),
+            )
+        }
+        None => (None, None),
+    };
+    let stdout_layer = config.log_stdout.then(fmt::layer);
+
+    tracing_subscriber::registry()
+        .with(EnvFilter::new(LOG_FILTER))
+        .with(stdout_layer)
+        .with(file_layer)
+        .init();
+    Ok(guard)
+}
+
+fn poll_cadence(interval_ms: u64, config: &Config) -> (Duration, MissedTickBehavior) {
+    (Duration::from_millis(interval_ms), config.poll_missed_ticks)
+}
+
+/// Worker ticks on a fixed cadence measured from tick start, so collection
+/// time doesn't add drift. A tick that overruns the period skips the ticks
+/// it missed (or, with `delay`, restarts the cadence from when it finished)
+/// instead of bursting to catch up.
+fn poll_ticker((period, missed): (Duration, MissedTickBehavior)) -> Interval {
+    let mut ticker = interval(period);
+    ticker.set_missed_tick_behavior(missed);
+    ticker
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt().with_env_filter("info").init();
+    if std::env::args().skip(1).any(|arg| arg == "--selftest") {
+        tracing_subscriber::fmt().with_env_filter(LOG_FILTER).init();
+        return selftest::run();
+    }
 
-    let config = Config::from_env()?;
-    let initial_mode = PerformanceMode::Balanced;
-    let allocation = allocation_for_mode(initial_mode, &config);
-    if let Err(error) = cgroups::apply_allocation(&allocation) {
+    // The log file location comes from the config, so anything logged while
+    // loading it goes to stdout only.
+    let config = tracing::subscriber::with_default(
+        tracing_subscriber::fmt()
+            .with_env_filter(LOG_FILTER)
+            .finish(),
+        Config::from_env,
+    )?;
+    let log_guard = init_logging(&config)?;
+    let slowdown_c = telemetry::read_gpu_slowdown_c(&config);
+    let config = config.with_gpu_slowdown(slowdown_c);
+    if let Some(slowdown_c) = config.gpu_slowdown_c {
+        info!(
+            "gpu {} slows down at {slowdown_c}C; thermal limit {}C",
+            config.compute_gpu_index, config.thermal_limit_c
+        );
+    }
+    // Only the NVIDIA sources are judged; see `gpu_vendor::tool_mismatch`.
+    if config.gpu_vendor_check
+        && config
+            .gpu_sources
+            .first()
+            .is_some_and(|source| source.nvidia())
+    {
+        let usable = cfg!(feature = "nvml") || telemetry::gpu_tool_present(&config);
+        if let Some(problem) =
+            gpu_vendor::tool_mismatch(&gpu_vendor::detect(), &config.gpu_smi_cmd, usable)
+        {
+            warn!("{problem}");
+        }
+    }
+    config.create_data_dir()?;
+    // Bound before any cgroup writes, so a second instance leaves the
+    // running one's allocation alone.
+    let listener = match std::net::TcpListener::bind(config.bind_addr) {
+        Err(error) if error.kind() == std::io::ErrorKind::AddrInUse => {
+            error!(
+                "port {} is already in use (on {}), likely by another dig-rust-daemon; stop it or set DIG_DAEMON_ADDR to a free address",
+                config.bind_addr.port(),
+                config.bind_addr.ip()
+            );
+            drop(log_guard);
+            std::process::exit(EXIT_ADDR_IN_USE);
+        }
+        result => result.with_context(|| format!("failed to bind {}", config.bind_addr))?,
+    };
+    listener.set_nonblocking(true)?;
+    if config.startup_delay_ms > 0 {
+        // Lets systemd finish delegating the cgroup subtree before the first
+        // apply, which would otherwise find no files to write.
+        info!("waiting {}ms before first apply", config.startup_delay_ms);
+        sleep(Duration::from_millis(config.startup_delay_ms)).await;
+    }
+    let initial_mode = config.startup_mode;
+    let allocation = effective_allocation(initial_mode, &config, true);
+    let catalog = missions::default_catalog();
+    missions::validate_catalog(&catalog).context("invalid mission catalog")?;
+    let initial_mission =
+        missions::resolve_active(&catalog, "med-pancreas-001", config.unknown_mission);
+    let initial_domain = initial_mission
+        .as_deref()
+        .and_then(|id| missions::find(&catalog, id))
+        .map(|m| m.domain.as_str());
+    let errors = Arc::new(ErrorCounters::default());
+    let initial_apply = cgroups::apply_allocation(&allocation, &config, initial_domain, &errors);
+    if let Err(error) = &initial_apply {
         warn!("initial cgroups apply failed: {error}");
     }
+    let initial_cgroup_apply = match initial_apply {
+        Ok(()) => InitialApply::Applied,
+        Err(_) if config.cgroup_apply_retries > 0 => InitialApply::Retrying,
+        Err(_) => InitialApply::Failed,
+    };
 
-    let initial_telemetry = telemetry::collect_snapshot(initial_mode);
+    let initial_gpu_share = allocation.worker_gpu_percent;
+    let initial_telemetry =
+        telemetry::collect_snapshot(initial_mode, &config, config.telemetry_source, &errors);
     let shared = Arc::new(state::AppState::new(
         config.clone(),
+        catalog.clone(),
         RuntimeState {
+            version: 0,
             mode: initial_mode,
+            mode_entered_at: Utc::now(),
+            throttled_from: None,
+            over_limit_samples: 0,
+            settle_ticks_left: 0,
+            control: AllocationControl::Mode,
+            control_set_at: Utc::now(),
+            custom_allocation: None,
+            thermal_emergency: false,
+            memory_pressure: false,
+            autopilot_scale: 1.0,
+            scheduled_mode: None,
+            api_mode_changed_at: None,
             allocation,
             telemetry: initial_telemetry,
-            active_mission: Some("med-pancreas-001".to_string()),
+            telemetry_history: VecDeque::new(),
+            telemetry_history_bytes: 0,
+            source_preference: config.telemetry_source,
+            active_mission: initial_mission,
+            focused_mission: None,
             session_xp: 0,
+            xp_limiter: Default::default(),
+            session_earnings_dig: 0.0,
+            session_impact: BTreeMap::new(),
+            contribution_momentum: 0.0,
+            completed_missions: BTreeSet::new(),
+            failed_missions: BTreeSet::new(),
+            mission_progress_at: BTreeMap::new(),
+            dataset_status: BTreeMap::new(),
+            mission_elapsed_secs: BTreeMap::new(),
+            cgroups_applied: initial_cgroup_apply == InitialApply::Applied,
+            initial_cgroup_apply,
+            initial_cgroup_attempts: 1,
+            worker_tick_at: Utc::now(),
+            worker_tick_duration: Duration::ZERO,
+            poll_interval_ms: config.poll_interval_ms,
+            idle_since: None,
+            slow_worker_ticks: 0,
+            worker_heartbeat: None,
+            worker_lost: false,
+            events: EventLog::default(),
+            balance: BalanceTracker::default(),
+            cooldown: CooldownTracker::default(),
+            balance_suggestion: None,
         },
+        Lifetime::start(&config),
+        Arc::clone(&errors),
     ));
+    shared.request_power_cap(initial_gpu_share);
+    if let Err(error) = shared.lifetime.persist() {
+        warn!("failed to persist lifetime counters: {error}");
+    }
+    let flush_state = Arc::clone(&shared);
+    tokio::spawn(async move {
+        loop {
+            sleep(lifetime::FLUSH_INTERVAL).await;
+            if let Err(error) = flush_state.lifetime.persist() {
+                warn!("failed to persist lifetime counters: {error}");
+            }
+        }
+    });
+
+    tokio::spawn(push::run(Arc::clone(&shared)));
+
+    // Patterns and interval are read every round so a reload takes effect.
+    let attach_state = Arc::clone(&shared);
+    tokio::spawn(async move {
+        loop {
+            let config = attach_state.config();
+            sleep(Duration::from_secs(config.worker_attach_interval_secs)).await;
+            if config.worker_process_patterns.is_empty() {
+                continue;
+            }
+            let group = {
+                let lock = attach_state.runtime.read().await;
+                cgroups::worker_group(
+                    &config,
+                    attach_state.mission_domain(lock.active_mission.as_deref()),
+                )
+            };
+            let patterns = config.worker_process_patterns.clone();
+            let state = Arc::clone(&attach_state);
+            let attached = tokio::task::spawn_blocking(move || {
+                state
+                    .attach_pids(&group, &procs::matching_pids(&patterns))
+                    .map(|moved| (group, moved))
+            })
+            .await;
+            match attached {
+                Ok(Ok((_, 0))) => {}
+                Ok(Ok((group, moved))) => info!("attached {moved} worker process(es) to {group}"),
+                Ok(Err(error)) => warn!("worker process attach failed: {error}"),
+                Err(error) => warn!("worker process attach task failed: {error}"),
+            }
+        }
+    });
+
+    if initial_cgroup_apply == InitialApply::Retrying {
+        let retry_state = Arc::clone(&shared);
+        tokio::spawn(async move {
+            let mut backoff = INITIAL_APPLY_BACKOFF;
+            for _ in 0..retry_state.config().cgroup_apply_retries {
+                sleep(backoff).await;
+                backoff *= 2;
+                let mut lock = retry_state.write_runtime("cgroup retry").await;
+                lock.initial_cgroup_attempts += 1;
+                // A mode change may have applied successfully meanwhile.
+                if lock.cgroups_applied || retry_state.apply_allocation(&mut lock).is_ok() {
+                    info!(
+                        "cgroups applied after {} attempts",
+                        lock.initial_cgroup_attempts
+                    );
+                    lock.initial_cgroup_apply = InitialApply::Applied;
+                    return;
+                }
+            }
+            let mut lock = retry_state.write_runtime("cgroup retry").await;
+            warn!(
+                "giving up on initial cgroups apply after {} attempts",
+                lock.initial_cgroup_attempts
+            );
+            lock.initial_cgroup_apply = InitialApply::Failed;
+        });
+    }
 
     let worker_state = Arc::clone(&shared);
     tokio::spawn(async move {
+        let config = worker_state.config();
+        let mut cadence = poll_cadence(config.poll_interval_ms, &config);
+        let mut ticker = poll_ticker(cadence);
+        let mut ticks: u64 = 0;
         loop {
-            let current_mode = {
+            ticker.tick().await;
+            let tick_started = Instant::now();
+            let config = worker_state.config();
+            let (current_mode, preference, interval_ms) = {
                 let lock = worker_state.runtime.read().await;
-                lock.mode
+                (lock.mode, lock.source_preference, lock.poll_interval_ms)
+            };
+            if poll_cadence(interval_ms, &config) != cadence {
+                cadence = poll_cadence(interval_ms, &config);
+                ticker = poll_ticker(cadence);
+                ticker.tick().await;
+            }
+            // Collect off the async runtime and without the lock held; only
+            // the cheap bookkeeping below runs under the write lock.
+            let mut snapshot = match worker_state
+                .collect_telemetry(current_mode, preference)
+                .await
+            {
+                Ok(snapshot) => snapshot,
+                Err(error) => {
+                    warn!("telemetry collection failed: {error}");
+                    continue;
+                }
             };
-            let snapshot = telemetry::collect_snapshot(current_mode);
 
-            let needs_thermal_throttle = snapshot.gpu_temp_c >= worker_state.config.thermal_limit_c;
-            let mut lock = worker_state.runtime.write().await;
-            lock.telemetry = snapshot.clone();
+            // The first tick samples them too, so they're there from the start.
+            if config.slow_metrics_every > 0
+                && ticks.is_multiple_of(config.slow_metrics_every as u64)
+            {
+                match tokio::task::spawn_blocking(telemetry::collect_slow_metrics).await {
+                    Ok(slow) => snapshot.slow = Some(slow),
+                    Err(error) => warn!("slow metrics collection failed: {error}"),
+                }
+            }
+            ticks += 1;
+
+            let mut lock = worker_state.write_runtime("worker tick").await;
+            snapshot.settling = lock.settle_ticks_left > 0;
+            lock.settle_ticks_left = lock.settle_ticks_left.saturating_sub(1);
+            snapshot.anomalies = telemetry::detect_anomalies(&lock.telemetry, &snapshot);
+            for anomaly in &snapshot.anomalies {
+                if !lock.telemetry.anomalies.contains(anomaly) {
+                    warn!("telemetry anomaly: {anomaly}");
+                    lock.events.push(EventKind::TelemetryAnomaly {
+                        anomaly: anomaly.clone(),
+                    });
+                }
+            }
+            let thermal_limit_c = config.thermal_limit_for(lock.mode);
+            if snapshot.settling {
+                // Neither counted nor reset: the transient says nothing
+                // about the new steady state.
+            } else if snapshot.gpu_temp_c >= thermal_limit_c {
+                lock.over_limit_samples = lock.over_limit_samples.saturating_add(1);
+            } else {
+                lock.over_limit_samples = 0;
+            }
+            worker_state.store_telemetry(&mut lock, snapshot.clone());
+            let now = Utc::now();
+            let elapsed_secs =
+                (now - lock.worker_tick_at).num_milliseconds().max(0) as f64 / 1000.0;
+            lock.worker_tick_at = now;
+            lock.session_earnings_dig += snapshot.earnings_per_sec as f64 * elapsed_secs;
+            let domain = worker_state
+                .mission_domain(lock.active_mission.as_deref())
+                .filter(|_| snapshot.impact_score >= config.min_impact_for_credit);
+            if let Some(domain) = domain {
+                *lock.session_impact.entry(domain.to_string()).or_default() +=
+                    snapshot.impact_score as f64;
+            }
+
+            let worker_lost = lock.heartbeat_expired(now, &config);
+            if worker_lost != lock.worker_lost {
+                lock.worker_lost = worker_lost;
+                if let Some(heartbeat) = lock.worker_heartbeat {
+                    if worker_lost {
+                        warn!(
+                            "external worker {} stopped sending heartbeats",
+                            heartbeat.pid
+                        );
+                        lock.events.push(EventKind::WorkerLost {
+                            pid: heartbeat.pid,
+                            last_seen: heartbeat.last_seen,
+                        });
+                    } else {
+                        info!("external worker {} is back", heartbeat.pid);
+                        lock.events
+                            .push(EventKind::WorkerRecovered { pid: heartbeat.pid });
+                    }
+                }
+                if config.worker_lost_idle {
+                    lock.allocation = lock.target_allocation(&config);
+                    if let Err(error) = worker_state.apply_allocation(&mut lock) {
+                        warn!("cgroups apply after worker liveness change failed: {error}");
+                    }
+                    worker_state.mark_runtime_changed(&mut lock);
+                }
+            }
 
-            if needs_thermal_throttle && lock.mode != PerformanceMode::Gaming {
-                let throttled_mode = PerformanceMode::Balanced;
-                lock.mode = throttled_mode;
-                lock.allocation = allocation_for_mode(throttled_mode, &worker_state.config);
-                if let Err(error) = cgroups::apply_allocation(&lock.allocation) {
-                    warn!("thermal cgroups apply failed: {error}");
+            let emergency = thermal_emergency(snapshot.gpu_temp_c, lock.thermal_emergency, &config);
+            if emergency != lock.thermal_emergency {
+                lock.thermal_emergency = emergency;
+                if emergency {
+                    error!(
+                        "thermal emergency: gpu={}C critical={}C, cutting worker to {}%",
+                        snapshot.gpu_temp_c,
+                        config.thermal_critical_c,
+                        config.critical_worker_percent
+                    );
+                    let mode = lock.mode;
+                    lock.events.push(EventKind::ThermalEmergency {
+                        gpu_index: config.compute_gpu_index,
+                        temp_c: snapshot.gpu_temp_c,
+                        critical_c: config.thermal_critical_c,
+                        mode,
+                    });
+                } else {
+                    info!(
+                        "thermal emergency cleared: gpu={}C resume={}C",
+                        snapshot.gpu_temp_c, config.thermal_resume_c
+                    );
+                    lock.events.push(EventKind::ThermalEmergencyCleared {
+                        gpu_index: config.compute_gpu_index,
+                        temp_c: snapshot.gpu_temp_c,
+                        resume_c: config.thermal_resume_c,
+                    });
+                }
+                lock.allocation = lock.target_allocation(&config);
+                if let Err(error) = worker_state.apply_allocation(&mut lock) {
+                    warn!("cgroups apply on thermal emergency change failed: {error}");
                 }
+                worker_state.mark_runtime_changed(&mut lock);
+            }
+
+            let pressure =
+                memory_pressure(snapshot.memory_available_mib, lock.memory_pressure, &config);
+            if pressure != lock.memory_pressure {
+                lock.memory_pressure = pressure;
+                let available_mib = snapshot.memory_available_mib.unwrap_or_default();
+                if pressure {
+                    warn!(
+                        "low memory: {available_mib}MiB available, under {}MiB; halving worker shares",
+                        config.min_free_memory_mib
+                    );
+                    lock.events.push(EventKind::MemoryPressure {
+                        available_mib,
+                        min_free_mib: config.min_free_memory_mib,
+                    });
+                } else {
+                    info!("memory recovered: {available_mib}MiB available");
+                    lock.events
+                        .push(EventKind::MemoryRecovered { available_mib });
+                }
+                lock.allocation = lock.target_allocation(&config);
+                if let Err(error) = worker_state.apply_allocation(&mut lock) {
+                    warn!("cgroups apply on memory pressure change failed: {error}");
+                }
+                worker_state.mark_runtime_changed(&mut lock);
+            }
+
+            if lock.mode == PerformanceMode::Autopilot
+                && lock.control == AllocationControl::Mode
+                && lock.throttled_from.is_none()
+            {
+                let headroom_c = config.thermal_limit_for(lock.mode) - snapshot.gpu_temp_c;
+                let scale = autopilot_scale(headroom_c, &config);
+                if scale != lock.autopilot_scale {
+                    debug!("autopilot: {headroom_c:.1}C of headroom, worker scale {scale}");
+                    lock.autopilot_scale = scale;
+                    lock.allocation = lock.target_allocation(&config);
+                    if let Err(error) = worker_state.apply_allocation(&mut lock) {
+                        warn!("cgroups apply on autopilot scaling failed: {error}");
+                    }
+                    worker_state.mark_runtime_changed(&mut lock);
+                }
+            }
+
+            if lock.cooldown.expire(now) {
+                info!("thermal cooldown finished");
+                lock.events.push(EventKind::CooldownEnded);
+            }
+
+            let thermal = ThermalState {
+                mode: lock.mode,
+                mode_overridden: lock.control != AllocationControl::Mode,
+                throttle_lowers_worker: throttle_lowers_worker(&lock.allocation, &config),
+                throttled_from: lock.throttled_from,
+                over_limit_samples: lock.over_limit_samples,
+                cooldown_active: lock.cooldown.active(now),
+            };
+            // The thermal emergency above still reacts to settling readings.
+            let decision = (!snapshot.settling)
+                .then(|| thermal_decision(&snapshot, &thermal, &config))
+                .flatten();
+            match decision {
+                Some(ThermalAction::Throttle(throttled_mode)) => {
+                    let previous_mode = lock.mode;
+                    lock.enter_mode(throttled_mode);
+                    lock.throttled_from = Some(previous_mode);
+                    lock.allocation = lock.target_allocation(&config);
+                    if let Err(error) = worker_state.apply_allocation(&mut lock) {
+                        warn!("thermal cgroups apply failed: {error}");
+                    }
+                    warn!(
+                        "thermal throttle engaged: gpu={}C limit={}C",
+                        snapshot.gpu_temp_c, thermal_limit_c
+                    );
+                    worker_state.mark_runtime_changed(&mut lock);
+                    webhook::notify_mode_change(
+                        &config,
+                        ModeChange {
+                            timestamp: now,
+                            old_mode: previous_mode,
+                            new_mode: throttled_mode,
+                            reason: ModeChangeReason::ThermalThrottle,
+                            telemetry: snapshot.clone(),
+                        },
+                    );
+                    lock.events.push(EventKind::ThermalThrottle {
+                        reason: ThrottleReason::GpuOverLimit,
+                        gpu_index: config.compute_gpu_index,
+                        temp_c: snapshot.gpu_temp_c,
+                        limit_c: thermal_limit_c,
+                        previous_mode,
+                        new_mode: throttled_mode,
+                    });
+                    if let Some(until) = lock.cooldown.record_throttle(now, &config) {
+                        warn!(
+                            "repeated thermal throttling: holding reduced allocation until {until}"
+                        );
+                        lock.events.push(EventKind::CooldownStarted {
+                            until,
+                            throttles: config.cooldown_throttle_count,
+                            window_secs: config.cooldown_window_secs,
+                        });
+                    }
+                }
+                Some(ThermalAction::Resume(restored_mode)) => {
+                    lock.throttled_from = None;
+                    let previous_mode = lock.mode;
+                    lock.enter_mode(restored_mode);
+                    lock.allocation = lock.target_allocation(&config);
+                    if let Err(error) = worker_state.apply_allocation(&mut lock) {
+                        warn!("thermal resume cgroups apply failed: {error}");
+                    }
+                    worker_state.mark_runtime_changed(&mut lock);
+                    webhook::notify_mode_change(
+                        &config,
+                        ModeChange {
+                            timestamp: now,
+                            old_mode: previous_mode,
+                            new_mode: restored_mode,
+                            reason: ModeChangeReason::ThermalResume,
+                            telemetry: snapshot.clone(),
+                        },
+                    );
+                    info!(
+                        "thermal throttle released: gpu={}C resume={}C mode={restored_mode:?}",
+                        snapshot.gpu_temp_c, config.thermal_resume_c
+                    );
+                    lock.events.push(EventKind::ThermalResume {
+                        gpu_index: config.compute_gpu_index,
+                        temp_c: snapshot.gpu_temp_c,
+                        resume_c: config.thermal_resume_c,
+                        restored_mode,
+                    });
+                }
+                None => {}
+            }
+
+            // Deferred while throttled, so the switch happens once the GPU
+            // has cooled instead of undoing the throttle.
+            if !config.mode_schedule.is_empty() && lock.throttled_from.is_none() {
+                let window_mode =
+                    schedule::active(&config.mode_schedule, Local::now()).map(|window| window.mode);
+                if window_mode != lock.scheduled_mode {
+                    lock.scheduled_mode = window_mode;
+                    let previous_mode = lock.mode;
+                    let new_mode = window_mode.unwrap_or(config.startup_mode);
+                    lock.enter_mode(new_mode);
+                    lock.set_control(AllocationControl::Mode);
+                    lock.allocation = lock.target_allocation(&config);
+                    if let Err(error) = worker_state.apply_allocation(&mut lock) {
+                        warn!("scheduled mode cgroups apply failed: {error}");
+                    }
+                    worker_state.mark_runtime_changed(&mut lock);
+                    info!("schedule: switching from {previous_mode:?} to {new_mode:?}");
+                    if previous_mode != new_mode {
+                        webhook::notify_mode_change(
+                            &config,
+                            ModeChange {
+                                timestamp: now,
+                                old_mode: previous_mode,
+                                new_mode,
+                                reason: ModeChangeReason::Schedule,
+                                telemetry: snapshot.clone(),
+                            },
+                        );
+                    }
+                    lock.events.push(EventKind::ScheduledModeChange {
+                        previous_mode,
+                        new_mode,
+                        window_started: window_mode.is_some(),
+                    });
+                }
+            }
+
+            // Falls back only once: Balanced itself never runs above
+            // Balanced, so the next trigger needs a new high-load change.
+            let ui_idle = worker_state.ui_idle();
+            if config.ui_contact_timeout_secs > 0
+                && ui_idle.as_secs() >= config.ui_contact_timeout_secs
+                && lock.throttled_from.is_none()
+                && above_balanced(&lock.allocation, &config)
+            {
+                let previous_mode = lock.mode;
+                let new_mode = PerformanceMode::Balanced;
+                lock.enter_mode(new_mode);
+                lock.set_control(AllocationControl::Mode);
+                lock.allocation = lock.target_allocation(&config);
+                if let Err(error) = worker_state.apply_allocation(&mut lock) {
+                    warn!("ui contact fallback cgroups apply failed: {error}");
+                }
+                worker_state.mark_runtime_changed(&mut lock);
                 warn!(
-                    "thermal throttle engaged: gpu={}C limit={}C",
-                    snapshot.gpu_temp_c, worker_state.config.thermal_limit_c
+                    "no UI contact for {}s: switching from {previous_mode:?} to Balanced",
+                    ui_idle.as_secs()
                 );
+                if previous_mode != new_mode {
+                    webhook::notify_mode_change(
+                        &config,
+                        ModeChange {
+                            timestamp: now,
+                            old_mode: previous_mode,
+                            new_mode,
+                            reason: ModeChangeReason::UiContactLost,
+                            telemetry: snapshot.clone(),
+                        },
+                    );
+                }
+                lock.events.push(EventKind::UiContactLost {
+                    idle_secs: ui_idle.as_secs(),
+                    previous_mode,
+                    new_mode,
+                });
+            }
+
+            if let Some(imbalance) = lock
+                .balance
+                .observe(&snapshot, config.balance_window_samples)
+            {
+                let suggestion = imbalance.suggestion().to_string();
+                info!("allocation suggestion: {suggestion}");
+                let timestamp = lock
+                    .events
+                    .push(EventKind::BalanceSuggestion {
+                        imbalance,
+                        suggestion: suggestion.clone(),
+                    })
+                    .timestamp;
+                lock.balance_suggestion = Some(BalanceSuggestion {
+                    timestamp,
+                    imbalance,
+                    suggestion,
+                });
+            }
+
+            let xp = state::tick_xp(
+                &snapshot,
+                lock.active_mission.is_some(),
+                config.xp_min_gpu_load_percent,
+                config.min_impact_for_credit,
+            );
+            let granted = lock.xp_limiter.grant(now, xp, config.xp_cap_per_minute);
+            lock.session_xp = lock.session_xp.saturating_add(granted);
+            lock.contribution_momentum = state::tick_momentum(
+                lock.contribution_momentum,
+                snapshot.impact_score,
+                xp > 0,
+                config.momentum_rate,
+            );
+
+            // Only the active mission is tracked, so one started again later
+            // gets a fresh stall clock.
+            let active = lock.active_mission.clone();
+            lock.mission_progress_at
+                .retain(|id, _| active.as_deref() == Some(id.as_str()));
+            if let Some(mission) = lock
+                .active_mission
+                .as_deref()
+                .and_then(|id| missions::find(&worker_state.missions, id))
+            {
+                let elapsed = lock
+                    .mission_elapsed_secs
+                    .entry(mission.id.clone())
+                    .or_default();
+                *elapsed += elapsed_secs;
+                if missions::progress_percent(mission, *elapsed) >= 100.0 {
+                    lock.mission_elapsed_secs.remove(&mission.id);
+                    lock.completed_missions.insert(mission.id.clone());
+                    lock.session_earnings_dig += mission.bounty_dig as f64;
+                    // A one-off award, so the per-minute cap doesn't apply.
+                    let xp = state::completion_xp(mission, config.completion_xp_per_dig);
+                    lock.session_xp = lock.session_xp.saturating_add(xp);
+                    lock.active_mission = None;
+                    lock.focused_mission = None;
+                    lock.allocation = lock.target_allocation(&config);
+                    if let Err(error) = worker_state.apply_allocation(&mut lock) {
+                        warn!("cgroups apply after mission completion failed: {error}");
+                    }
+                    worker_state.mark_runtime_changed(&mut lock);
+                    info!(
+                        "mission {} completed: +{} DIG, +{xp} XP",
+                        mission.id, mission.bounty_dig
+                    );
+                    lock.events.push(EventKind::MissionCompleted {
+                        mission_id: mission.id.clone(),
+                        bounty_dig: mission.bounty_dig,
+                        xp,
+                    });
+                } else {
+                    let progress_at = *lock
+                        .mission_progress_at
+                        .entry(mission.id.clone())
+                        .or_insert(now);
+                    if xp > 0 {
+                        lock.mission_progress_at.insert(mission.id.clone(), now);
+                    } else if state::mission_stalled(
+                        progress_at,
+                        now,
+                        config.mission_stall_timeout_secs,
+                    ) {
+                        let action = config.mission_stall_action;
+                        if action == StallAction::Fail {
+                            lock.mission_elapsed_secs.remove(&mission.id);
+                            lock.failed_missions.insert(mission.id.clone());
+                        }
+                        lock.active_mission = None;
+                        lock.focused_mission = None;
+                        lock.allocation = lock.target_allocation(&config);
+                        if let Err(error) = worker_state.apply_allocation(&mut lock) {
+                            warn!("cgroups apply after mission stall failed: {error}");
+                        }
+                        worker_state.mark_runtime_changed(&mut lock);
+                        let stalled_secs = (now - progress_at).num_seconds();
+                        warn!(
+                            "mission {} stalled for {stalled_secs}s, {action:?}",
+                            mission.id
+                        );
+                        lock.events.push(EventKind::MissionStalled {
+                            mission_id: mission.id.clone(),
+                            stalled_secs,
+                            action,
+                        });
+                    }
+                }
             }
 
-            lock.session_xp = lock
-                .session_xp
-                .saturating_add((snapshot.impact_score / 10.0).max(1.0) as u64);
+            if machine_idle(&snapshot, lock.active_mission.is_some(), &config) {
+                lock.idle_since.get_or_insert(now);
+            } else {
+                lock.idle_since = None;
+            }
+            let idle_for_secs = lock.idle_since.map(|since| (now - since).num_seconds());
+            let interval_ms = next_poll_interval_ms(lock.poll_interval_ms, idle_for_secs, &config);
+            if interval_ms != lock.poll_interval_ms {
+                if interval_ms > lock.poll_interval_ms {
+                    info!("machine idle, polling every {interval_ms}ms");
+                } else {
+                    info!("activity resumed, polling every {interval_ms}ms");
+                }
+                lock.poll_interval_ms = interval_ms;
+            }
 
-            sleep(Duration::from_millis(worker_state.config.poll_interval_ms)).await;
+            let tick_duration = tick_started.elapsed();
+            lock.worker_tick_duration = tick_duration;
+            if let Some(budget) = config
+                .tick_budget()
+                .filter(|budget| tick_duration > *budget)
+            {
+                lock.slow_worker_ticks += 1;
+                warn!(
+                    "worker tick took {}ms, over its {}ms budget",
+                    tick_duration.as_millis(),
+                    budget.as_millis()
+                );
+            }
+            drop(lock);
         }
     });
 
-    let app = api::router(shared);
-    let listener = tokio::net::TcpListener::bind(config.bind_addr).await?;
-    info!("dig-rust-daemon listening on {}", config.bind_addr);
-    axum::serve(listener, app).await?;
+    #[cfg(unix)]
+    {
+        let reload_state = Arc::clone(&shared);
+        let mut hangups = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                match Config::from_env() {
+                    Ok(fresh) => {
+                        reload_state.reload_config(fresh);
+                        info!("config reloaded on SIGHUP");
+                    }
+                    Err(error) => warn!("config reload failed, keeping current config: {error}"),
+                }
+            }
+        });
+    }
+
+    tokio::select! {
+        result = serve(listener, api::router(Arc::clone(&shared)), &config) => result?,
+        result = shutdown_signal() => {
+            result?;
+            info!("shutting down");
+        }
+    }
+    shared.lifetime.persist()
+}
+
+async fn serve(listener: std::net::TcpListener, app: axum::Router, config: &Config) -> Result<()> {
+    match &config.tls {
+        Some(tls) => {
+            let rustls = RustlsConfig::from_pem_file(&tls.cert, &tls.key).await?;
+            info!("dig-rust-daemon listening on {} (tls)", config.bind_addr);
+            axum_server::from_tcp_rustls(listener, rustls)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        None => {
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            info!("dig-rust-daemon listening on {}", config.bind_addr);
+            axum::serve(listener, app).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM where available.
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terms = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terms.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
     Ok(())
 }
 
@@ -0,0 +1,356 @@
+use std::{cmp::Ordering, collections::BTreeSet, path::Path, str::FromStr};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// Highest `priority` a mission may have.
+pub const MAX_PRIORITY: u8 = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mission {
+    pub id: String,
+    pub title: String,
+    pub bounty_dig: f32,
+    pub dataset_gb: f32,
+    pub eta_minutes: u16,
+    pub priority: u8,
+    pub domain: String,
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    #[serde(default)]
+    pub requires_dataset: bool,
+    /// Free-form labels for catalog filtering, e.g. `gpu-heavy`, `short`.
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// How a tag filter combines several tags.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TagMatch {
+    #[default]
+    Any,
+    All,
+}
+
+/// What to do with a starting mission that isn't in the catalog.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownMission {
+    /// Start with no active mission.
+    #[default]
+    Clear,
+    /// Start the highest-priority mission with no dependencies instead.
+    HighestPriority,
+}
+
+impl FromStr for UnknownMission {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "clear" => Ok(Self::Clear),
+            "highest_priority" => Ok(Self::HighestPriority),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What happens to an active mission that has stalled for
+/// `mission_stall_timeout_secs`.
+#[derive(Debug, Clone, Copy, Default, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StallAction {
+    /// Deactivate it, keeping its progress for when it's started again.
+    #[default]
+    Abandon,
+    /// Deactivate it, discard its progress and record it as failed.
+    Fail,
+}
+
+impl FromStr for StallAction {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "abandon" => Ok(Self::Abandon),
+            "fail" => Ok(Self::Fail),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Where a mission's dataset stands. `Downloading` can only be reported
+/// through the API; the other two are also inferred from the dataset dir.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DatasetStatus {
+    Absent,
+    Downloading,
+    Ready,
+}
+
+pub fn default_catalog() -> Vec<Mission> {
+    vec![
+        Mission {
+            id: "med-pancreas-001".to_string(),
+            title: "Pancreatic Cancer Detection".to_string(),
+            bounty_dig: 500.0,
+            dataset_gb: 4.2,
+            eta_minutes: 12,
+            priority: 100,
+            domain: "medical".to_string(),
+            depends_on: Vec::new(),
+            requires_dataset: false,
+            tags: vec!["ml".to_string(), "long".to_string()],
+        },
+        Mission {
+            id: "space-exoplanet-004".to_string(),
+            title: "Exoplanet Atmosphere Analysis".to_string(),
+            bounty_dig: 120.0,
+            dataset_gb: 2.1,
+            eta_minutes: 7,
+            priority: 55,
+            domain: "space".to_string(),
+            depends_on: Vec::new(),
+            requires_dataset: false,
+            tags: vec!["science".to_string(), "short".to_string()],
+        },
+        Mission {
+            id: "render-cyberpunk-2099".to_string(),
+            title: "Render Cyberpunk 2099 Frame".to_string(),
+            bounty_dig: 50.0,
+            dataset_gb: 1.4,
+            eta_minutes: 4,
+            priority: 20,
+            domain: "render".to_string(),
+            depends_on: Vec::new(),
+            requires_dataset: false,
+            tags: vec!["gpu-heavy".to_string(), "short".to_string()],
+        },
+    ]
+}
+
+/// Rejects a catalog with an empty or repeated id, a negative or
+/// non-finite bounty or dataset size, a zero ETA or a priority above
+/// `MAX_PRIORITY`, naming the first offending mission.
+pub fn validate_catalog(catalog: &[Mission]) -> Result<()> {
+    let mut ids = BTreeSet::new();
+    for (index, mission) in catalog.iter().enumerate() {
+        let id = &mission.id;
+        if id.trim().is_empty() {
+            bail!("mission #{index} ({:?}) has an empty id", mission.title);
+        }
+        if !ids.insert(id.as_str()) {
+            bail!("mission {id} appears more than once in the catalog");
+        }
+        if !(mission.bounty_dig.is_finite() && mission.bounty_dig >= 0.0) {
+            bail!(
+                "mission {id}: bounty_dig {} is not a non-negative number",
+                mission.bounty_dig
+            );
+        }
+        if !(mission.dataset_gb.is_finite() && mission.dataset_gb >= 0.0) {
+            bail!(
+                "mission {id}: dataset_gb {} is not a non-negative number",
+                mission.dataset_gb
+            );
+        }
+        if mission.eta_minutes == 0 {
+            bail!("mission {id}: eta_minutes must be above 0");
+        }
+        if mission.priority > MAX_PRIORITY {
+            bail!(
+                "mission {id}: priority {} is above {MAX_PRIORITY}",
+                mission.priority
+            );
+        }
+    }
+    Ok(())
+}
+
+pub fn find<'a>(catalog: &'a [Mission], id: &str) -> Option<&'a Mission> {
+    catalog.iter().find(|mission| mission.id == id)
+}
+
+/// Picks the highest-scoring mission. Equal scores fall back to
+/// [`tiebreak`], so the same inputs always pick the same mission.
+pub fn select<'a>(scored: impl IntoIterator<Item = (&'a Mission, f32)>) -> Option<&'a Mission> {
+    scored
+        .into_iter()
+        .max_by(|(a, a_score), (b, b_score)| {
+            a_score.total_cmp(b_score).then_with(|| tiebreak(a, b))
+        })
+        .map(|(mission, _)| mission)
+}
+
+/// `id` if the catalog has it; otherwise what `policy` falls back to, with
+/// a warning so a catalog that dropped the mission doesn't go unnoticed.
+pub fn resolve_active(catalog: &[Mission], id: &str, policy: UnknownMission) -> Option<String> {
+    if find(catalog, id).is_some() {
+        return Some(id.to_string());
+    }
+    let fallback = match policy {
+        UnknownMission::Clear => None,
+        UnknownMission::HighestPriority => catalog
+            .iter()
+            .filter(|mission| mission.depends_on.is_empty())
+            .max_by(|a, b| tiebreak(a, b)),
+    };
+    match fallback {
+        Some(mission) => warn!(
+            "active mission {id} is not in the catalog; starting {} instead",
+            mission.id
+        ),
+        None => warn!("active mission {id} is not in the catalog; starting with none"),
+    }
+    fallback.map(|mission| mission.id.clone())
+}
+
+/// Order between equally-scored missions, `Greater` meaning `a` wins:
+/// higher `priority`, then higher `bounty_dig`, then the lexicographically
+/// smaller `id`.
+pub fn tiebreak(a: &Mission, b: &Mission) -> Ordering {
+    a.priority
+        .cmp(&b.priority)
+        .then_with(|| a.bounty_dig.total_cmp(&b.bounty_dig))
+        .then_with(|| b.id.cmp(&a.id))
+}
+
+/// Whether `mission` carries any (or all) of `tags`. An empty filter
+/// matches everything.
+pub fn matches_tags(mission: &Mission, tags: &[String], mode: TagMatch) -> bool {
+    let has = |tag: &String| mission.tags.contains(tag);
+    match mode {
+        _ if tags.is_empty() => true,
+        TagMatch::Any => tags.iter().any(has),
+        TagMatch::All => tags.iter().all(has),
+    }
+}
+
+/// Share of the mission's ETA covered by `elapsed_secs` of active time,
+/// capped at 100.
+pub fn progress_percent(mission: &Mission, elapsed_secs: f64) -> f32 {
+    let eta_secs = mission.eta_minutes as f64 * 60.0;
+    if eta_secs <= 0.0 {
+        return 100.0;
+    }
+    ((elapsed_secs / eta_secs) * 100.0).min(100.0) as f32
+}
+
+/// The last status reported for `mission`'s dataset, otherwise `Ready` when
+/// `<dataset_dir>/<mission id>` exists and `Absent` when it doesn't.
+pub fn dataset_status(
+    mission: &Mission,
+    reported: Option<DatasetStatus>,
+    dataset_dir: &Path,
+) -> DatasetStatus {
+    reported.unwrap_or_else(|| {
+        if dataset_dir.join(&mission.id).exists() {
+            DatasetStatus::Ready
+        } else {
+            DatasetStatus::Absent
+        }
+    })
+}
+
+/// Lists every prerequisite of `mission` that is not yet met. Missions with
+/// `requires_dataset` need their dataset `Ready`.
+pub fn unmet_prerequisites(
+    mission: &Mission,
+    completed: &BTreeSet<String>,
+    dataset: DatasetStatus,
+) -> Vec<String> {
+    let mut unmet: Vec<String> = mission
+        .depends_on
+        .iter()
+        .filter(|id| !completed.contains(*id))
+        .map(|id| format!("mission {id} not completed"))
+        .collect();
+
+    let missing = match dataset {
+        DatasetStatus::Absent => Some("absent"),
+        DatasetStatus::Downloading => Some("still downloading"),
+        DatasetStatus::Ready => None,
+    };
+    if let Some(missing) = missing.filter(|_| mission.requires_dataset) {
+        unmet.push(format!("dataset for {} {missing}", mission.id));
+    }
+    unmet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tagged(catalog: &[Mission], tags: &[&str], mode: TagMatch) -> Vec<String> {
+        let tags: Vec<String> = tags.iter().map(|tag| tag.to_string()).collect();
+        catalog
+            .iter()
+            .filter(|mission| matches_tags(mission, &tags, mode))
+            .map(|mission| mission.id.clone())
+            .collect()
+    }
+
+    #[test]
+    fn tag_filters_match_any_or_all() {
+        let catalog = default_catalog();
+        assert_eq!(
+            tagged(&catalog, &["gpu-heavy", "long"], TagMatch::Any),
+            ["med-pancreas-001", "render-cyberpunk-2099"]
+        );
+        assert_eq!(
+            tagged(&catalog, &["gpu-heavy", "short"], TagMatch::All),
+            ["render-cyberpunk-2099"]
+        );
+        assert!(tagged(&catalog, &["gpu-heavy", "long"], TagMatch::All).is_empty());
+        assert_eq!(tagged(&catalog, &[], TagMatch::All).len(), catalog.len());
+    }
+
+    fn mission(id: &str, priority: u8, bounty_dig: f32) -> Mission {
+        Mission {
+            id: id.to_string(),
+            priority,
+            bounty_dig,
+            ..default_catalog().remove(0)
+        }
+    }
+
+    #[test]
+    fn ties_go_to_priority_then_bounty_then_id() {
+        let low = mission("a", 10, 900.0);
+        let high = mission("z", 50, 10.0);
+        assert_eq!(select([(&low, 1.0), (&high, 1.0)]).unwrap().id, "z");
+
+        let cheap = mission("a", 50, 10.0);
+        assert_eq!(select([(&cheap, 1.0), (&high, 1.0)]).unwrap().id, "a");
+
+        let rich = mission("m", 50, 20.0);
+        assert_eq!(
+            select([(&cheap, 1.0), (&rich, 1.0), (&high, 1.0)])
+                .unwrap()
+                .id,
+            "m"
+        );
+    }
+
+    #[test]
+    fn selection_ignores_input_order() {
+        let (a, b, c) = (
+            mission("a", 5, 5.0),
+            mission("b", 5, 5.0),
+            mission("c", 5, 5.0),
+        );
+        for order in [[&a, &b, &c], [&c, &b, &a], [&b, &c, &a]] {
+            let scored = order.map(|mission| (mission, 2.0));
+            assert_eq!(select(scored).unwrap().id, "a");
+        }
+    }
+
+    #[test]
+    fn a_higher_score_beats_the_tiebreak() {
+        let (weak, strong) = (mission("a", 100, 900.0), mission("b", 0, 0.0));
+        assert_eq!(select([(&weak, 1.0), (&strong, 1.5)]).unwrap().id, "b");
+    }
+}
+
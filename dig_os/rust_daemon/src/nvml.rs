@@ -0,0 +1,54 @@
+use std::sync::OnceLock;
+
+use nvml_wrapper::{
+    enum_wrappers::device::{TemperatureSensor, TemperatureThreshold},
+    Nvml,
+};
+use tracing::warn;
+
+use crate::telemetry::GpuReading;
+
+/// Loaded once; `None` when the NVML library isn't present, in which case
+/// callers fall back to `nvidia-smi`.
+static NVML: OnceLock<Option<Nvml>> = OnceLock::new();
+
+fn nvml() -> Option<&'static Nvml> {
+    NVML.get_or_init(|| match Nvml::init() {
+        Ok(nvml) => Some(nvml),
+        Err(error) => {
+            warn!("NVML unavailable, using nvidia-smi: {error}");
+            None
+        }
+    })
+    .as_ref()
+}
+
+/// Queries the `index`-th device directly through the driver, avoiding a
+/// process spawn per sample.
+pub fn read(index: u32) -> Option<GpuReading> {
+    let device = nvml()?.device_by_index(index).ok()?;
+
+    Some(GpuReading {
+        util: device.utilization_rates().ok().map(|u| u.gpu as f32),
+        temp: device
+            .temperature(TemperatureSensor::Gpu)
+            .ok()
+            .map(|t| t as f32),
+        memory_used_mib: device
+            .memory_info()
+            .ok()
+            .map(|m| (m.used / (1024 * 1024)) as f32),
+        power_w: device.power_usage().ok().map(|mw| mw as f32 / 1000.0),
+        temp_clamped: false,
+    })
+}
+
+/// The `index`-th device's hardware slowdown threshold.
+pub fn slowdown_temp(index: u32) -> Option<f32> {
+    let device = nvml()?.device_by_index(index).ok()?;
+    device
+        .temperature_threshold(TemperatureThreshold::Slowdown)
+        .ok()
+        .map(|t| t as f32)
+}
+
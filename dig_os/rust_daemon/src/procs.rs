@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+
+use sysinfo::{Pid, PidExt, ProcessExt, ProcessRefreshKind, RefreshKind, System, SystemExt};
+
+/// PIDs of running processes whose name matches any of `patterns`, never
+/// including the daemon itself.
+pub fn matching_pids(patterns: &[String]) -> Vec<u32> {
+    if patterns.is_empty() {
+        return Vec::new();
+    }
+    let system =
+        System::new_with_specifics(RefreshKind::new().with_processes(ProcessRefreshKind::new()));
+    let own_pid = std::process::id();
+    let mut pids: Vec<u32> = system
+        .processes()
+        .iter()
+        .filter(|(_, process)| {
+            patterns
+                .iter()
+                .any(|pattern| name_matches(pattern, process.name()))
+        })
+        .map(|(pid, _)| pid.as_u32())
+        .filter(|pid| *pid != own_pid)
+        .collect();
+    pids.sort_unstable();
+    pids
+}
+
+/// Names of those of `pids` still running.
+pub fn names(pids: impl IntoIterator<Item = u32>) -> HashMap<u32, String> {
+    let mut system = System::new();
+    pids.into_iter()
+        .filter_map(|pid| {
+            let pid = Pid::from_u32(pid);
+            system.refresh_process_specifics(pid, ProcessRefreshKind::new());
+            let name = system.process(pid)?.name().to_string();
+            Some((pid.as_u32(), name))
+        })
+        .collect()
+}
+
+/// Case-sensitive match of a whole process name against `pattern`, where
+/// `*` stands for any run of characters: `dig-*`, `*-worker`, `python*`.
+pub fn name_matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No `*` at all: the prefix has to be the whole name.
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
@@ -0,0 +1,81 @@
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use reqwest::Client;
+use serde::Serialize;
+use tokio::time::{sleep_until, Instant};
+use tracing::warn;
+
+use crate::{state::AppState, telemetry::TelemetrySnapshot};
+
+const PUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize)]
+struct PushBatch<'a> {
+    node_id: &'a str,
+    snapshots: Vec<TelemetrySnapshot>,
+}
+
+/// Collects every stored snapshot and POSTs them to `telemetry_push_url`
+/// in batches of `telemetry_push_batch`, or whatever has accumulated after
+/// `telemetry_push_interval_secs`. Sends run in the background, so a slow
+/// collector never holds up the next batch; a failed batch is logged,
+/// counted and dropped. Config is re-read each round, so a reload can turn
+/// pushing on or off.
+pub async fn run(state: Arc<AppState>) {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    let client = CLIENT.get_or_init(|| {
+        Client::builder()
+            .timeout(PUSH_TIMEOUT)
+            .build()
+            .unwrap_or_default()
+    });
+
+    let mut updates = state.subscribe_telemetry();
+    let mut batch = Vec::new();
+    let mut flush_at = Instant::now();
+    loop {
+        let config = state.config();
+        let Some(url) = config.telemetry_push_url.clone() else {
+            batch.clear();
+            if updates.changed().await.is_err() {
+                return;
+            }
+            continue;
+        };
+        let interval = Duration::from_secs(config.telemetry_push_interval_secs);
+        tokio::select! {
+            changed = updates.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                batch.push(state.runtime.read().await.telemetry.clone());
+                if batch.len() < config.telemetry_push_batch {
+                    continue;
+                }
+            }
+            _ = sleep_until(flush_at) => {}
+        }
+        flush_at = Instant::now() + interval;
+        if batch.is_empty() {
+            continue;
+        }
+
+        let body = PushBatch {
+            node_id: &config.node_id,
+            snapshots: std::mem::take(&mut batch),
+        };
+        let count = body.snapshots.len();
+        let request = client.post(url).json(&body);
+        let push_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(error) = request.send().await.and_then(|r| r.error_for_status()) {
+                push_state.errors.telemetry_push_failed();
+                warn!("telemetry push of {count} snapshot(s) failed: {error}");
+            }
+        });
+    }
+}
+
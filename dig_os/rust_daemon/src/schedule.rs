@@ -0,0 +1,137 @@
+use chrono::{DateTime, Datelike, Days, Local, NaiveTime, TimeZone, Weekday};
+use serde::Serialize;
+
+use crate::scheduler::PerformanceMode;
+
+const WEEK: [Weekday; 7] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+    Weekday::Sat,
+    Weekday::Sun,
+];
+
+/// One `mode@[days ]HH:MM-HH:MM` entry of `mode_schedule`, in local time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ScheduleWindow {
+    pub mode: PerformanceMode,
+    /// Days the window starts on, Monday first.
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    /// Earlier than `start` for a window that runs past midnight.
+    pub end: NaiveTime,
+}
+
+impl ScheduleWindow {
+    pub fn contains(&self, at: DateTime<Local>) -> bool {
+        let time = at.time();
+        let today = at.weekday();
+        if self.start < self.end {
+            self.days.contains(&today) && time >= self.start && time < self.end
+        } else {
+            (self.days.contains(&today) && time >= self.start)
+                || (self.days.contains(&today.pred()) && time < self.end)
+        }
+    }
+}
+
+/// The mode the schedule asks for from `at` on, and when.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Transition {
+    pub at: DateTime<Local>,
+    /// `None` when no window covers it.
+    pub mode: Option<PerformanceMode>,
+}
+
+/// The window in effect at `at`; where windows overlap the first listed
+/// wins.
+pub fn active(windows: &[ScheduleWindow], at: DateTime<Local>) -> Option<&ScheduleWindow> {
+    windows.iter().find(|window| window.contains(at))
+}
+
+/// The next time after `now` the scheduled mode changes, looking a week
+/// ahead.
+pub fn next_transition(windows: &[ScheduleWindow], now: DateTime<Local>) -> Option<Transition> {
+    let current = active(windows, now).map(|window| window.mode);
+    let mut boundaries: Vec<DateTime<Local>> = (0..=7)
+        .filter_map(|offset| now.date_naive().checked_add_days(Days::new(offset)))
+        .flat_map(|date| {
+            windows
+                .iter()
+                .flat_map(move |window| [date.and_time(window.start), date.and_time(window.end)])
+        })
+        // A boundary skipped by a DST jump has no local time; it is passed
+        // over rather than guessed.
+        .filter_map(|naive| Local.from_local_datetime(&naive).earliest())
+        .filter(|at| *at > now)
+        .collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+    boundaries.into_iter().find_map(|at| {
+        let mode = active(windows, at).map(|window| window.mode);
+        (mode != current).then_some(Transition { at, mode })
+    })
+}
+
+/// Parses `sleep@00:00-07:00;gaming@mon-fri 18:00-23:00`: `;`-separated
+/// windows, each a mode, `@`, optional comma-separated days or day ranges
+/// (`mon-fri`, `sat,sun`; every day without them) and a start-end time.
+pub fn parse_schedule(value: &str) -> Option<Vec<ScheduleWindow>> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(parse_window)
+        .collect()
+}
+
+fn parse_window(entry: &str) -> Option<ScheduleWindow> {
+    let (mode, spec) = entry.split_once('@')?;
+    let mode = mode.trim().parse().ok()?;
+    let mut parts = spec.split_whitespace();
+    let (days, times) = match (parts.next()?, parts.next(), parts.next()) {
+        (times, None, None) => (WEEK.to_vec(), times),
+        (days, Some(times), None) => (parse_days(days)?, times),
+        _ => return None,
+    };
+    let (start, end) = times.split_once('-')?;
+    let start = NaiveTime::parse_from_str(start, "%H:%M").ok()?;
+    let end = NaiveTime::parse_from_str(end, "%H:%M").ok()?;
+    (start != end).then_some(ScheduleWindow {
+        mode,
+        days,
+        start,
+        end,
+    })
+}
+
+/// `mon-fri,sun` into those weekdays, Monday first. Ranges may wrap
+/// around the weekend: `fri-mon`.
+fn parse_days(value: &str) -> Option<Vec<Weekday>> {
+    let mut days = [false; 7];
+    for part in value.split(',') {
+        let (from, to) = match part.split_once('-') {
+            Some((from, to)) => (from.parse::<Weekday>().ok()?, to.parse::<Weekday>().ok()?),
+            None => {
+                let day = part.parse::<Weekday>().ok()?;
+                (day, day)
+            }
+        };
+        let mut day = from;
+        loop {
+            days[day.num_days_from_monday() as usize] = true;
+            if day == to {
+                break;
+            }
+            day = day.succ();
+        }
+    }
+    Some(
+        WEEK.into_iter()
+            .filter(|day| days[day.num_days_from_monday() as usize])
+            .collect(),
+    )
+}
+
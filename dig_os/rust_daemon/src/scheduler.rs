@@ -1,8 +1,22 @@
+use std::{collections::VecDeque, str::FromStr};
+
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-use crate::config::Config;
+use crate::{
+    config::{valid_cpu_list, Config},
+    missions::Mission,
+    telemetry::TelemetrySnapshot,
+};
+
+/// Absolute lower bound for the UI share of CPU and GPU, applied after the
+/// config reservation so no configuration can starve the desktop.
+pub const UI_FLOOR_PERCENT: u8 = 2;
+
+/// Thermal headroom a GPU-heavy mission needs before it is recommended.
+pub const GPU_HEAVY_MIN_HEADROOM_C: f32 = 10.0;
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(rename_all = "snake_case")]
 pub enum PerformanceMode {
     Gaming,
@@ -11,45 +25,1123 @@ pub enum PerformanceMode {
     Autopilot,
 }
 
+impl PerformanceMode {
+    pub const ALL: [PerformanceMode; 4] =
+        [Self::Gaming, Self::Balanced, Self::Sleep, Self::Autopilot];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Gaming => "Gaming",
+            Self::Balanced => "Balanced",
+            Self::Sleep => "Sleep",
+            Self::Autopilot => "Autopilot",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::Gaming => {
+                "Keeps most of the machine free for a game; the worker gets a small share"
+            }
+            Self::Balanced => "Worker takes most resources while the desktop stays responsive",
+            Self::Sleep => "Machine is unattended; the worker takes nearly everything",
+            Self::Autopilot => {
+                "Worker runs near full load with a little more UI headroom than Sleep"
+            }
+        }
+    }
+}
+
+impl FromStr for PerformanceMode {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "gaming" => Ok(Self::Gaming),
+            "balanced" => Ok(Self::Balanced),
+            "sleep" => Ok(Self::Sleep),
+            "autopilot" => Ok(Self::Autopilot),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Below this combined UI + worker share a resource is almost entirely
+/// unmanaged, which is more likely a misconfiguration than intended slack.
+pub const MIN_PLAUSIBLE_SUM_PERCENT: u8 = 25;
+
+/// CPU/GPU shares for the `dig-ui` and `dig-worker` groups.
+///
+/// For each resource `ui + worker` must not exceed 100. Anything left over is
+/// deliberately unreserved headroom for processes outside both groups (for
+/// example a game in Gaming mode), not a rounding error.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Allocation {
-    pub ui_cpu_percent: u8,
-    pub worker_cpu_percent: u8,
+    /// CPU shares may be fractional, e.g. 12.5 on a many-core box where
+    /// a whole percent is a sizeable slice of a core.
+    pub ui_cpu_percent: f32,
+    pub worker_cpu_percent: f32,
     pub ui_gpu_percent: u8,
     pub worker_gpu_percent: u8,
-    pub profile: &'static str,
+    /// VRAM shares. Nothing partitions GPU memory on the daemon's side, so
+    /// these are advisory: the external worker is expected to cap itself
+    /// (e.g. through an MPS pinned memory limit) from `/api/v1/runtime`.
+    #[serde(default)]
+    pub ui_gpu_mem_percent: u8,
+    #[serde(default)]
+    pub worker_gpu_mem_percent: u8,
+    /// Label for UIs and logs; the mode name unless `DIG_MODE_PROFILES`
+    /// overrides it, or `idle` while no mission is active.
+    #[serde(default)]
+    pub profile: String,
+    /// CPUs the worker group is pinned to (a cpuset list such as `0-3,8`),
+    /// from `DIG_MODE_WORKER_CPUSETS`; `None` leaves it on every CPU.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker_cpuset: Option<String>,
 }
 
+impl Allocation {
+    /// Errors when a resource is oversubscribed; otherwise returns warnings
+    /// for sums below `MIN_PLAUSIBLE_SUM_PERCENT`.
+    pub fn validate(&self) -> Result<Vec<String>, String> {
+        let mut warnings = Vec::new();
+        for (role, share) in [
+            ("ui", self.ui_cpu_percent),
+            ("worker", self.worker_cpu_percent),
+        ] {
+            if !(0.0..=100.0).contains(&share) {
+                return Err(format!(
+                    "{}: cpu {role} {share}% is not between 0% and 100%",
+                    self.profile
+                ));
+            }
+        }
+        for (resource, ui, worker) in [
+            ("cpu", self.ui_cpu_percent, self.worker_cpu_percent),
+            (
+                "gpu",
+                self.ui_gpu_percent as f32,
+                self.worker_gpu_percent as f32,
+            ),
+        ] {
+            let sum = ui + worker;
+            if sum > 100.0 {
+                return Err(format!(
+                    "{}: {resource} ui {ui}% + worker {worker}% exceeds 100%",
+                    self.profile
+                ));
+            }
+            if sum < MIN_PLAUSIBLE_SUM_PERCENT as f32 {
+                warnings.push(format!(
+                    "{}: {resource} ui {ui}% + worker {worker}% leaves {}% unmanaged",
+                    self.profile,
+                    cpu_share(100.0 - sum)
+                ));
+            }
+        }
+        let mem_sum = self.ui_gpu_mem_percent as u16 + self.worker_gpu_mem_percent as u16;
+        if mem_sum > 100 {
+            return Err(format!(
+                "{}: gpu memory ui {}% + worker {}% exceeds 100%",
+                self.profile, self.ui_gpu_mem_percent, self.worker_gpu_mem_percent
+            ));
+        }
+        Ok(warnings)
+    }
+
+    /// `validate` for allocations supplied by a client, which also may not
+    /// take a UI share below `ui_reserved_*_percent` or `UI_FLOOR_PERCENT`,
+    /// give the worker more than `max_worker_*_percent` and may only pin
+    /// the worker to a CPU list.
+    pub fn validate_custom(&self, cfg: &Config) -> Result<Vec<String>, String> {
+        for (resource, ui, reserved) in [
+            ("cpu", self.ui_cpu_percent, cfg.ui_reserved_cpu_percent),
+            (
+                "gpu",
+                self.ui_gpu_percent as f32,
+                cfg.ui_reserved_gpu_percent,
+            ),
+        ] {
+            let floor = reserved.max(UI_FLOOR_PERCENT);
+            if ui < floor as f32 {
+                return Err(format!(
+                    "{}: {resource} ui {ui}% is below the {floor}% UI floor",
+                    self.profile
+                ));
+            }
+        }
+        for (resource, worker, cap) in [
+            ("cpu", self.worker_cpu_percent, cfg.max_worker_cpu_percent),
+            (
+                "gpu",
+                self.worker_gpu_percent as f32,
+                cfg.max_worker_gpu_percent,
+            ),
+        ] {
+            if worker > cap as f32 {
+                return Err(format!(
+                    "{}: {resource} worker {worker}% is above the {cap}% worker cap",
+                    self.profile
+                ));
+            }
+        }
+        if let Some(cpus) = &self.worker_cpuset {
+            if !valid_cpu_list(cpus) {
+                return Err(format!(
+                    "{}: worker_cpuset {cpus:?} is not a CPU list",
+                    self.profile
+                ));
+            }
+        }
+        self.validate()
+    }
+}
+
+/// The worker share yields to the UI reservation, so raising
+/// `ui_reserved_*_percent` never oversubscribes a resource, and never
+/// exceeds `max_worker_*_percent`.
 pub fn allocation_for_mode(mode: PerformanceMode, cfg: &Config) -> Allocation {
+    let mut allocation = base_allocation(mode, cfg);
+    allocation.worker_cpu_percent = allocation
+        .worker_cpu_percent
+        .min(100.0 - allocation.ui_cpu_percent)
+        .min(cfg.max_worker_cpu_percent as f32);
+    allocation.worker_gpu_percent = allocation
+        .worker_gpu_percent
+        .min(100 - allocation.ui_gpu_percent)
+        .min(cfg.max_worker_gpu_percent);
+    if let Some(profile) = cfg.mode_profiles.get(&mode) {
+        allocation.profile = profile.clone();
+    }
+    allocation.worker_cpuset = cfg.mode_worker_cpusets.get(&mode).cloned();
+    if let Some(&(ui, worker)) = cfg.mode_gpu_memory.get(&mode) {
+        allocation.ui_gpu_mem_percent = ui;
+        allocation.worker_gpu_mem_percent = worker;
+    }
+    allocation
+}
+
+/// Allocation to apply for `mode`. With no active mission there is no work
+/// to do, so the worker shares are scaled to `idle_worker_percent` of the
+/// mode's.
+pub fn effective_allocation(
+    mode: PerformanceMode,
+    cfg: &Config,
+    mission_active: bool,
+) -> Allocation {
+    idle_adjusted(allocation_for_mode(mode, cfg), cfg, mission_active)
+}
+
+/// Scales `allocation` to the idle share unless a mission is active.
+pub fn idle_adjusted(mut allocation: Allocation, cfg: &Config, mission_active: bool) -> Allocation {
+    if !mission_active {
+        let scale = |share: u8| (share as u16 * cfg.idle_worker_percent as u16 / 100) as u8;
+        allocation.worker_cpu_percent =
+            cpu_share(allocation.worker_cpu_percent * cfg.idle_worker_percent as f32 / 100.0);
+        allocation.worker_gpu_percent = scale(allocation.worker_gpu_percent);
+        allocation.profile = "idle".to_string();
+    }
+    allocation
+}
+
+/// Share of Autopilot's worker allocation to run with `headroom_c` below the
+/// thermal limit: 1 from `autopilot_full_headroom_c` up, falling to
+/// `autopilot_min_scale` at the limit along
+/// `1 - (1 - headroom / full)^autopilot_curve_exponent`. Rounded to 0.01 so
+/// small temperature jitter doesn't rewrite the cgroups.
+pub fn autopilot_scale(headroom_c: f32, cfg: &Config) -> f32 {
+    if cfg.autopilot_full_headroom_c <= 0.0 {
+        return 1.0;
+    }
+    let x = (headroom_c / cfg.autopilot_full_headroom_c).clamp(0.0, 1.0);
+    let curve = 1.0 - (1.0 - x).powf(cfg.autopilot_curve_exponent);
+    let scale = cfg.autopilot_min_scale + (1.0 - cfg.autopilot_min_scale) * curve;
+    (scale * 100.0).round() / 100.0
+}
+
+/// Autopilot's allocation for the thermal headroom in `snapshot`: the
+/// worker shares scaled by `autopilot_scale`, UI shares untouched.
+pub fn autopilot_allocation(snapshot: &TelemetrySnapshot, cfg: &Config) -> Allocation {
+    let mode = PerformanceMode::Autopilot;
+    let allocation = allocation_for_mode(mode, cfg);
+    let scale = autopilot_scale(cfg.thermal_limit_for(mode) - snapshot.gpu_temp_c, cfg);
+    if scale >= 1.0 {
+        return allocation;
+    }
+    let scaled = |share: u8| (share as f32 * scale).round() as u8;
+    Allocation {
+        worker_cpu_percent: cpu_share(allocation.worker_cpu_percent * scale),
+        worker_gpu_percent: scaled(allocation.worker_gpu_percent),
+        ..allocation
+    }
+}
+
+/// `allocation` with the worker shares multiplied by `focus_worker_boost`,
+/// still leaving the UI its share and never above `max_worker_*_percent`.
+pub fn focus_boosted(allocation: Allocation, cfg: &Config) -> Allocation {
+    let boost = cfg.focus_worker_boost;
+    let gpu_cap = (100 - allocation.ui_gpu_percent).min(cfg.max_worker_gpu_percent);
+    Allocation {
+        worker_cpu_percent: cpu_share(allocation.worker_cpu_percent * boost)
+            .min(100.0 - allocation.ui_cpu_percent)
+            .min(cfg.max_worker_cpu_percent as f32),
+        worker_gpu_percent: ((allocation.worker_gpu_percent as f32 * boost).round() as u8)
+            .min(gpu_cap),
+        profile: format!("{}+focus", allocation.profile),
+        ..allocation
+    }
+}
+
+/// Whether `allocation` gives the worker more CPU or GPU than Balanced
+/// would.
+pub fn above_balanced(allocation: &Allocation, cfg: &Config) -> bool {
+    let balanced = allocation_for_mode(PerformanceMode::Balanced, cfg);
+    allocation.worker_cpu_percent > balanced.worker_cpu_percent
+        || allocation.worker_gpu_percent > balanced.worker_gpu_percent
+}
+
+/// Allocation for a point on the intensity dial: every share moves
+/// linearly from Gaming's at `0.0` to Sleep's at `1.0`, so the UI floors
+/// of both ends are kept throughout.
+pub fn intensity_allocation(intensity: f32, cfg: &Config) -> Allocation {
+    let low = allocation_for_mode(PerformanceMode::Gaming, cfg);
+    let high = allocation_for_mode(PerformanceMode::Sleep, cfg);
+    let t = intensity.clamp(0.0, 1.0);
+    let lerp = |from: u8, to: u8| (from as f32 + (to as f32 - from as f32) * t).round() as u8;
+    let lerp_cpu = |from: f32, to: f32| cpu_share(from + (to - from) * t);
+    let ui_cpu_percent = lerp_cpu(low.ui_cpu_percent, high.ui_cpu_percent);
+    let ui_gpu_percent = lerp(low.ui_gpu_percent, high.ui_gpu_percent);
+    Allocation {
+        ui_cpu_percent,
+        worker_cpu_percent: lerp_cpu(low.worker_cpu_percent, high.worker_cpu_percent)
+            .min(100.0 - ui_cpu_percent),
+        ui_gpu_percent,
+        worker_gpu_percent: lerp(low.worker_gpu_percent, high.worker_gpu_percent)
+            .min(100 - ui_gpu_percent),
+        ui_gpu_mem_percent: lerp(low.ui_gpu_mem_percent, high.ui_gpu_mem_percent),
+        worker_gpu_mem_percent: lerp(low.worker_gpu_mem_percent, high.worker_gpu_mem_percent),
+        profile: "intensity".to_string(),
+        worker_cpuset: None,
+    }
+}
+
+fn base_allocation(mode: PerformanceMode, cfg: &Config) -> Allocation {
     match mode {
         PerformanceMode::Gaming => Allocation {
-            ui_cpu_percent: cfg.ui_reserved_cpu_percent.max(15),
-            worker_cpu_percent: 20,
-            ui_gpu_percent: cfg.ui_reserved_gpu_percent.max(20),
+            ui_cpu_percent: ui_share(cfg.ui_reserved_cpu_percent, 15) as f32,
+            worker_cpu_percent: 20.0,
+            ui_gpu_percent: ui_share(cfg.ui_reserved_gpu_percent, 20),
             worker_gpu_percent: 10,
-            profile: "gaming",
+            ui_gpu_mem_percent: 25,
+            worker_gpu_mem_percent: 40,
+            profile: "gaming".to_string(),
+            worker_cpuset: None,
         },
         PerformanceMode::Sleep => Allocation {
-            ui_cpu_percent: cfg.ui_reserved_cpu_percent.max(3),
-            worker_cpu_percent: 95,
-            ui_gpu_percent: cfg.ui_reserved_gpu_percent.max(2),
+            ui_cpu_percent: ui_share(cfg.ui_reserved_cpu_percent, 3) as f32,
+            worker_cpu_percent: 95.0,
+            ui_gpu_percent: ui_share(cfg.ui_reserved_gpu_percent, 2),
             worker_gpu_percent: 98,
-            profile: "sleep",
+            ui_gpu_mem_percent: 5,
+            worker_gpu_mem_percent: 90,
+            profile: "sleep".to_string(),
+            worker_cpuset: None,
         },
         PerformanceMode::Autopilot => Allocation {
-            ui_cpu_percent: cfg.ui_reserved_cpu_percent.max(5),
-            worker_cpu_percent: 85,
-            ui_gpu_percent: cfg.ui_reserved_gpu_percent.max(5),
+            ui_cpu_percent: ui_share(cfg.ui_reserved_cpu_percent, 5) as f32,
+            worker_cpu_percent: 85.0,
+            ui_gpu_percent: ui_share(cfg.ui_reserved_gpu_percent, 5),
             worker_gpu_percent: 90,
-            profile: "autopilot",
+            ui_gpu_mem_percent: 10,
+            worker_gpu_mem_percent: 80,
+            profile: "autopilot".to_string(),
+            worker_cpuset: None,
         },
         PerformanceMode::Balanced => Allocation {
-            ui_cpu_percent: cfg.ui_reserved_cpu_percent.max(5),
-            worker_cpu_percent: 80,
-            ui_gpu_percent: cfg.ui_reserved_gpu_percent.max(5),
+            ui_cpu_percent: ui_share(cfg.ui_reserved_cpu_percent, 5) as f32,
+            worker_cpu_percent: 80.0,
+            ui_gpu_percent: ui_share(cfg.ui_reserved_gpu_percent, 5),
             worker_gpu_percent: 85,
-            profile: "balanced",
+            ui_gpu_mem_percent: 10,
+            worker_gpu_mem_percent: 80,
+            profile: "balanced".to_string(),
+            worker_cpuset: None,
         },
     }
 }
 
+fn ui_share(reserved: u8, mode_min: u8) -> u8 {
+    reserved.max(mode_min).clamp(UI_FLOOR_PERCENT, 100)
+}
+
+/// A computed CPU share to two decimal places, so scaling doesn't leave
+/// float noise in responses or churn the cgroup files.
+fn cpu_share(percent: f32) -> f32 {
+    (percent * 100.0).round() / 100.0
+}
+
+/// Advisory mode choice with the reason behind it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Recommendation {
+    pub mode: PerformanceMode,
+    pub rationale: String,
+}
+
+/// Suggests a mode from the latest snapshot, checked in order: thermal
+/// headroom first, then whether there is work at all, then how much room
+/// the GPU has left.
+pub fn recommend_mode(
+    snapshot: &TelemetrySnapshot,
+    cfg: &Config,
+    mission_domain: Option<&str>,
+) -> Recommendation {
+    let headroom = cfg.thermal_limit_for(snapshot.mode) - snapshot.gpu_temp_c;
+    let (mode, rationale) = if headroom <= 5.0 {
+        (
+            PerformanceMode::Balanced,
+            format!("GPU within {headroom:.1}C of the thermal limit; Balanced avoids a throttle"),
+        )
+    } else if mission_domain.is_none() {
+        (
+            PerformanceMode::Gaming,
+            "no active mission; Gaming leaves the machine to you".to_string(),
+        )
+    } else if headroom >= 20.0 && snapshot.gpu_load_percent < 80.0 {
+        (
+            PerformanceMode::Autopilot,
+            format!(
+                "{headroom:.1}C of thermal headroom and GPU at {:.0}%; Autopilot can push the {} mission harder",
+                snapshot.gpu_load_percent,
+                mission_domain.unwrap_or_default()
+            ),
+        )
+    } else {
+        (
+            PerformanceMode::Balanced,
+            "moderate thermal headroom; Balanced keeps the worker busy without risking a throttle"
+                .to_string(),
+        )
+    };
+    Recommendation { mode, rationale }
+}
+
+/// Whether starting `mission` now suits the GPU's temperature: missions in a
+/// `gpu_heavy_domains` domain need `GPU_HEAVY_MIN_HEADROOM_C` below the
+/// thermal limit, anything else is always fine.
+pub fn mission_recommended(mission: &Mission, snapshot: &TelemetrySnapshot, cfg: &Config) -> bool {
+    !cfg.gpu_heavy_domains.contains(&mission.domain)
+        || cfg.thermal_limit_for(snapshot.mode) - snapshot.gpu_temp_c >= GPU_HEAVY_MIN_HEADROOM_C
+}
+
+/// Mode change the thermal policy calls for on one worker tick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThermalAction {
+    /// Too hot for long enough: drop to this mode.
+    Throttle(PerformanceMode),
+    /// Cooled to `thermal_resume_c` with no cooldown pending: return to the
+    /// mode the throttle switched away from.
+    Resume(PerformanceMode),
+}
+
+/// What a `POST /api/v1/mode` does while a thermal throttle is in place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThrottleOverride {
+    /// A mode giving the worker more than the throttled mode is only
+    /// remembered, and restored once the throttle lifts; others apply.
+    Defer,
+    /// Any mode applies at once and ends the throttle.
+    Allow,
+}
+
+impl FromStr for ThrottleOverride {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "defer" => Ok(Self::Defer),
+            "allow" => Ok(Self::Allow),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Whether a requested switch from `from` to `to` is only remembered as
+/// the mode to restore: thermal safety wins over a request that would load
+/// a throttled GPU further, unless `throttle_user_override` allows it.
+pub fn defers_mode_change(
+    throttled: bool,
+    from: PerformanceMode,
+    to: PerformanceMode,
+    cfg: &Config,
+) -> bool {
+    throttled
+        && cfg.throttle_user_override == ThrottleOverride::Defer
+        && raises_worker_load(from, to, cfg)
+}
+
+/// Whether switching from `from` to `to` gives the worker more CPU or GPU.
+pub fn raises_worker_load(from: PerformanceMode, to: PerformanceMode, cfg: &Config) -> bool {
+    let (from, to) = (allocation_for_mode(from, cfg), allocation_for_mode(to, cfg));
+    to.worker_cpu_percent > from.worker_cpu_percent
+        || to.worker_gpu_percent > from.worker_gpu_percent
+}
+
+/// Mode the GPU thermal policy throttles to.
+pub const THROTTLED_MODE: PerformanceMode = PerformanceMode::Balanced;
+
+/// `allocation` with the worker shares capped at the throttled mode's, so a
+/// throttle never hands the worker more than it already had.
+pub fn throttled_allocation(allocation: &Allocation, cfg: &Config) -> Allocation {
+    let throttled = allocation_for_mode(THROTTLED_MODE, cfg);
+    Allocation {
+        worker_cpu_percent: allocation
+            .worker_cpu_percent
+            .min(throttled.worker_cpu_percent),
+        worker_gpu_percent: allocation
+            .worker_gpu_percent
+            .min(throttled.worker_gpu_percent),
+        ..allocation.clone()
+    }
+}
+
+/// Whether throttling `allocation` cuts the worker's CPU or GPU at all.
+pub fn throttle_lowers_worker(allocation: &Allocation, cfg: &Config) -> bool {
+    let throttled = throttled_allocation(allocation, cfg);
+    throttled.worker_cpu_percent < allocation.worker_cpu_percent
+        || throttled.worker_gpu_percent < allocation.worker_gpu_percent
+}
+
+/// The runtime fields the thermal policy looks at.
+#[derive(Debug, Clone, Copy)]
+pub struct ThermalState {
+    pub mode: PerformanceMode,
+    /// The intensity dial or a custom allocation drives the shares rather
+    /// than `mode`.
+    pub mode_overridden: bool,
+    /// `throttle_lowers_worker` for the current allocation; only consulted
+    /// while `mode_overridden`.
+    pub throttle_lowers_worker: bool,
+    pub throttled_from: Option<PerformanceMode>,
+    /// Consecutive samples at or over the current mode's limit, including
+    /// this one.
+    pub over_limit_samples: u32,
+    pub cooldown_active: bool,
+}
+
+/// Decides whether this tick throttles, resumes or leaves the mode alone.
+/// Gaming is never throttled, and neither is a mode that already is the
+/// throttled one. A set intensity or custom allocation can push any mode
+/// hard, so it is throttled from any mode unless a throttle is already in
+/// place or the throttled mode's shares wouldn't lower the worker.
+pub fn thermal_decision(
+    snapshot: &TelemetrySnapshot,
+    state: &ThermalState,
+    cfg: &Config,
+) -> Option<ThermalAction> {
+    let hot = state.over_limit_samples >= cfg.thermal_grace_samples.max(1);
+    let throttleable = if state.mode_overridden {
+        state.throttled_from.is_none() && state.throttle_lowers_worker
+    } else {
+        !matches!(state.mode, PerformanceMode::Gaming | THROTTLED_MODE)
+    };
+    if hot && throttleable {
+        return Some(ThermalAction::Throttle(THROTTLED_MODE));
+    }
+    match state.throttled_from {
+        Some(restored) if snapshot.gpu_temp_c <= cfg.thermal_resume_c && !state.cooldown_active => {
+            Some(ThermalAction::Resume(restored))
+        }
+        _ => None,
+    }
+}
+
+/// Whether the thermal emergency holds after this sample: it starts on the
+/// first reading at `thermal_critical_c`, with no grace period, and ends once
+/// the GPU is back down to `thermal_resume_c`.
+pub fn thermal_emergency(gpu_temp_c: f32, active: bool, cfg: &Config) -> bool {
+    if active {
+        gpu_temp_c > cfg.thermal_resume_c
+    } else {
+        gpu_temp_c >= cfg.thermal_critical_c
+    }
+}
+
+/// `allocation` with the worker cut to `critical_worker_percent`; UI shares
+/// are kept.
+pub fn critical_allocation(allocation: &Allocation, cfg: &Config) -> Allocation {
+    Allocation {
+        worker_cpu_percent: allocation
+            .worker_cpu_percent
+            .min(cfg.critical_worker_percent as f32),
+        worker_gpu_percent: allocation
+            .worker_gpu_percent
+            .min(cfg.critical_worker_percent),
+        profile: "critical".to_string(),
+        ..allocation.clone()
+    }
+}
+
+/// Available memory must rise this far above `min_free_memory_mib` before
+/// the guard lifts, so hovering at the threshold doesn't flap.
+const MEMORY_RECOVERY_FACTOR: f64 = 1.2;
+
+/// Whether the memory guard holds after this sample: it starts once
+/// available memory drops below `min_free_memory_mib` and ends when it's
+/// back above that by `MEMORY_RECOVERY_FACTOR`. Samples without a reading
+/// keep the current state.
+pub fn memory_pressure(available_mib: Option<u64>, active: bool, cfg: &Config) -> bool {
+    if cfg.min_free_memory_mib == 0 {
+        return false;
+    }
+    let Some(available) = available_mib else {
+        return active;
+    };
+    if active {
+        (available as f64) < cfg.min_free_memory_mib as f64 * MEMORY_RECOVERY_FACTOR
+    } else {
+        available < cfg.min_free_memory_mib
+    }
+}
+
+/// `allocation` one step down for memory pressure: worker shares halved,
+/// UI shares kept.
+pub fn memory_guarded_allocation(allocation: &Allocation) -> Allocation {
+    Allocation {
+        worker_cpu_percent: cpu_share(allocation.worker_cpu_percent / 2.0),
+        worker_gpu_percent: allocation.worker_gpu_percent / 2,
+        profile: "low_memory".to_string(),
+        ..allocation.clone()
+    }
+}
+
+/// Whether a tick counts towards the idle poll backoff: no active mission
+/// and both loads under `idle_load_percent`.
+pub fn machine_idle(snapshot: &TelemetrySnapshot, mission_active: bool, cfg: &Config) -> bool {
+    !mission_active
+        && snapshot.cpu_load_percent < cfg.idle_load_percent
+        && snapshot.gpu_load_percent < cfg.idle_load_percent
+}
+
+/// Poll interval for the next worker tick. After `idle_poll_after_secs` of
+/// idling, each further idle tick doubles it, up to `idle_poll_max_ms`;
+/// anything else returns straight to `poll_interval_ms`.
+pub fn next_poll_interval_ms(current_ms: u64, idle_for_secs: Option<i64>, cfg: &Config) -> u64 {
+    match idle_for_secs {
+        Some(secs) if cfg.idle_poll_after_secs > 0 && secs >= cfg.idle_poll_after_secs as i64 => {
+            current_ms
+                .max(cfg.poll_interval_ms)
+                .saturating_mul(2)
+                .min(cfg.idle_poll_max_ms)
+        }
+        _ => cfg.poll_interval_ms,
+    }
+}
+
+/// Sustained mismatch between GPU and CPU load on the worker.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Imbalance {
+    GpuBound,
+    CpuBound,
+}
+
+impl Imbalance {
+    pub fn detect(snapshot: &TelemetrySnapshot) -> Option<Self> {
+        let (cpu, gpu) = (snapshot.cpu_load_percent, snapshot.gpu_load_percent);
+        if gpu > 90.0 && cpu < 20.0 {
+            Some(Self::GpuBound)
+        } else if cpu > 90.0 && gpu < 20.0 {
+            Some(Self::CpuBound)
+        } else {
+            None
+        }
+    }
+
+    pub fn suggestion(self) -> &'static str {
+        match self {
+            Self::GpuBound => {
+                "worker is GPU-bound while the CPU idles; lower worker_cpu_percent or pick a mode with a larger GPU share"
+            }
+            Self::CpuBound => {
+                "worker is CPU-bound while the GPU idles; raise worker_cpu_percent or pick a mode with a larger CPU share"
+            }
+        }
+    }
+}
+
+/// Counts consecutive samples showing the same imbalance.
+#[derive(Debug, Default)]
+pub struct BalanceTracker {
+    current: Option<Imbalance>,
+    streak: u32,
+}
+
+impl BalanceTracker {
+    /// Returns the imbalance exactly once, on the sample where it has been
+    /// sustained for `window` samples in a row.
+    pub fn observe(&mut self, snapshot: &TelemetrySnapshot, window: u32) -> Option<Imbalance> {
+        let detected = Imbalance::detect(snapshot);
+        if detected == self.current {
+            self.streak = self.streak.saturating_add(1);
+        } else {
+            self.current = detected;
+            self.streak = 1;
+        }
+
+        match self.current {
+            Some(imbalance) if self.streak == window.max(1) => Some(imbalance),
+            _ => None,
+        }
+    }
+}
+
+/// Sliding window of recent throttle engagements. Too many within the
+/// window forces a cooldown during which the reduced allocation is held
+/// regardless of temperature, so borderline hardware gets a chance to cool.
+#[derive(Debug, Default)]
+pub struct CooldownTracker {
+    throttles: VecDeque<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+}
+
+impl CooldownTracker {
+    /// Records a throttle at `now`. Returns the end of the cooldown if this
+    /// throttle starts one.
+    pub fn record_throttle(&mut self, now: DateTime<Utc>, cfg: &Config) -> Option<DateTime<Utc>> {
+        let window_start = now - chrono::Duration::seconds(cfg.cooldown_window_secs as i64);
+        self.throttles.push_back(now);
+        while self.throttles.front().is_some_and(|at| *at < window_start) {
+            self.throttles.pop_front();
+        }
+
+        if cfg.cooldown_throttle_count == 0
+            || self.active(now)
+            || (self.throttles.len() as u32) < cfg.cooldown_throttle_count
+        {
+            return None;
+        }
+        self.throttles.clear();
+        let until = now + chrono::Duration::seconds(cfg.cooldown_secs as i64);
+        self.until = Some(until);
+        Some(until)
+    }
+
+    pub fn active(&self, now: DateTime<Utc>) -> bool {
+        self.until.is_some_and(|until| now < until)
+    }
+
+    pub fn until(&self) -> Option<DateTime<Utc>> {
+        self.until
+    }
+
+    /// Clears a finished cooldown, returning true on the tick it ends.
+    pub fn expire(&mut self, now: DateTime<Utc>) -> bool {
+        match self.until {
+            Some(until) if now >= until => {
+                self.until = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::tests::snapshot;
+
+    fn config() -> Config {
+        Config::default()
+    }
+
+    #[test]
+    fn memory_pressure_enters_holds_and_restores() {
+        let cfg = Config {
+            min_free_memory_mib: 1000,
+            ..config()
+        };
+        assert!(!memory_pressure(Some(1000), false, &cfg));
+        assert!(memory_pressure(Some(999), false, &cfg));
+        // Back over the threshold but short of the 20% recovery margin.
+        assert!(memory_pressure(Some(1100), true, &cfg));
+        assert!(memory_pressure(Some(1199), true, &cfg));
+        assert!(!memory_pressure(Some(1200), true, &cfg));
+    }
+
+    #[test]
+    fn memory_pressure_keeps_state_without_a_reading() {
+        let cfg = Config {
+            min_free_memory_mib: 1000,
+            ..config()
+        };
+        assert!(memory_pressure(None, true, &cfg));
+        assert!(!memory_pressure(None, false, &cfg));
+    }
+
+    #[test]
+    fn memory_pressure_is_off_when_disabled() {
+        assert!(!memory_pressure(Some(0), true, &config()));
+    }
+
+    #[test]
+    fn memory_guard_halves_only_the_worker() {
+        let allocation = allocation_for_mode(PerformanceMode::Sleep, &config());
+        let guarded = memory_guarded_allocation(&allocation);
+        assert_eq!(
+            guarded.worker_cpu_percent,
+            allocation.worker_cpu_percent / 2.0
+        );
+        assert_eq!(
+            guarded.worker_gpu_percent,
+            allocation.worker_gpu_percent / 2
+        );
+        assert_eq!(guarded.ui_cpu_percent, allocation.ui_cpu_percent);
+        assert_eq!(guarded.ui_gpu_percent, allocation.ui_gpu_percent);
+        assert_eq!(guarded.profile, "low_memory");
+    }
+
+    #[test]
+    fn every_mode_keeps_the_ui_floor() {
+        for reserved in [0, 1, 2, 50, 99, 100, 255] {
+            for cap in [0, 50, 100] {
+                let cfg = Config {
+                    ui_reserved_cpu_percent: reserved,
+                    ui_reserved_gpu_percent: reserved,
+                    max_worker_cpu_percent: cap,
+                    max_worker_gpu_percent: cap,
+                    ..config()
+                };
+                for mode in PerformanceMode::ALL {
+                    let allocation = allocation_for_mode(mode, &cfg);
+                    let floor = UI_FLOOR_PERCENT;
+                    assert!(allocation.ui_cpu_percent >= floor as f32, "{allocation:?}");
+                    assert!(allocation.ui_gpu_percent >= floor, "{allocation:?}");
+                    assert!(allocation.validate().is_ok(), "{allocation:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn built_in_modes_validate_without_warnings() {
+        for mode in PerformanceMode::ALL {
+            let allocation = allocation_for_mode(mode, &config());
+            assert_eq!(allocation.validate(), Ok(Vec::new()), "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn validate_rejects_oversubscription() {
+        let allocation = Allocation {
+            ui_gpu_percent: 30,
+            worker_gpu_percent: 71,
+            ..allocation_for_mode(PerformanceMode::Balanced, &config())
+        };
+        let error = allocation.validate().unwrap_err();
+        assert!(
+            error.contains("gpu ui 30% + worker 71% exceeds 100%"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn validate_warns_about_implausibly_low_sums() {
+        let allocation = Allocation {
+            ui_cpu_percent: 5.0,
+            worker_cpu_percent: 10.0,
+            ..allocation_for_mode(PerformanceMode::Balanced, &config())
+        };
+        let warnings = allocation.validate().unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("leaves 85% unmanaged"), "{warnings:?}");
+    }
+
+    fn at(gpu_temp_c: f32, gpu_load_percent: f32) -> TelemetrySnapshot {
+        TelemetrySnapshot {
+            gpu_temp_c,
+            gpu_load_percent,
+            ..snapshot()
+        }
+    }
+
+    #[test]
+    fn recommends_balanced_near_the_limit() {
+        let recommendation = recommend_mode(&at(81.0, 50.0), &config(), Some("render"));
+        assert_eq!(recommendation.mode, PerformanceMode::Balanced);
+        assert!(recommendation
+            .rationale
+            .contains("4.0C of the thermal limit"));
+    }
+
+    #[test]
+    fn recommends_gaming_without_a_mission() {
+        let recommendation = recommend_mode(&at(50.0, 10.0), &config(), None);
+        assert_eq!(recommendation.mode, PerformanceMode::Gaming);
+    }
+
+    #[test]
+    fn recommends_autopilot_with_headroom_to_spare() {
+        let recommendation = recommend_mode(&at(60.0, 40.0), &config(), Some("render"));
+        assert_eq!(recommendation.mode, PerformanceMode::Autopilot);
+        assert!(recommendation.rationale.contains("render mission"));
+        let busy = recommend_mode(&at(60.0, 95.0), &config(), Some("render"));
+        assert_eq!(busy.mode, PerformanceMode::Balanced);
+    }
+
+    #[test]
+    fn gpu_heavy_missions_need_thermal_headroom() {
+        let catalog = crate::missions::default_catalog();
+        let render = crate::missions::find(&catalog, "render-cyberpunk-2099").unwrap();
+        let medical = crate::missions::find(&catalog, "med-pancreas-001").unwrap();
+        let cfg = config();
+        // The limit is 85C, so 10C of headroom ends at 75C.
+        assert!(mission_recommended(render, &at(75.0, 0.0), &cfg));
+        assert!(!mission_recommended(render, &at(75.5, 0.0), &cfg));
+        assert!(mission_recommended(medical, &at(84.0, 0.0), &cfg));
+    }
+
+    fn thermal(mode: PerformanceMode, over_limit_samples: u32) -> ThermalState {
+        ThermalState {
+            mode,
+            mode_overridden: false,
+            throttle_lowers_worker: true,
+            throttled_from: None,
+            over_limit_samples,
+            cooldown_active: false,
+        }
+    }
+
+    #[test]
+    fn throttles_once_the_grace_samples_are_over_the_limit() {
+        let cfg = config();
+        let hot = at(85.0, 90.0);
+        assert_eq!(
+            thermal_decision(&hot, &thermal(PerformanceMode::Sleep, 2), &cfg),
+            None
+        );
+        assert_eq!(
+            thermal_decision(&hot, &thermal(PerformanceMode::Sleep, 3), &cfg),
+            Some(ThermalAction::Throttle(PerformanceMode::Balanced))
+        );
+        assert_eq!(
+            thermal_decision(&at(70.0, 90.0), &thermal(PerformanceMode::Sleep, 0), &cfg),
+            None
+        );
+    }
+
+    #[test]
+    fn gaming_and_balanced_are_never_throttled() {
+        let cfg = config();
+        for mode in [PerformanceMode::Gaming, PerformanceMode::Balanced] {
+            assert_eq!(
+                thermal_decision(&at(99.0, 90.0), &thermal(mode, 10), &cfg),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn resumes_only_at_the_resume_temperature() {
+        let cfg = config();
+        let throttled = ThermalState {
+            throttled_from: Some(PerformanceMode::Sleep),
+            ..thermal(PerformanceMode::Balanced, 0)
+        };
+        // Between resume (75C) and the limit the throttle holds.
+        assert_eq!(thermal_decision(&at(80.0, 50.0), &throttled, &cfg), None);
+        assert_eq!(
+            thermal_decision(&at(75.0, 50.0), &throttled, &cfg),
+            Some(ThermalAction::Resume(PerformanceMode::Sleep))
+        );
+        let cooling = ThermalState {
+            cooldown_active: true,
+            ..throttled
+        };
+        assert_eq!(thermal_decision(&at(60.0, 50.0), &cooling, &cfg), None);
+    }
+
+    #[test]
+    fn overridden_shares_throttle_from_any_mode_once() {
+        let cfg = config();
+        let overridden = ThermalState {
+            mode_overridden: true,
+            ..thermal(PerformanceMode::Gaming, 3)
+        };
+        assert_eq!(
+            thermal_decision(&at(90.0, 90.0), &overridden, &cfg),
+            Some(ThermalAction::Throttle(PerformanceMode::Balanced))
+        );
+        let throttled = ThermalState {
+            throttled_from: Some(PerformanceMode::Gaming),
+            ..overridden
+        };
+        assert_eq!(thermal_decision(&at(90.0, 90.0), &throttled, &cfg), None);
+    }
+
+    #[test]
+    fn low_intensity_is_not_throttled_up() {
+        let cfg = config();
+        let low = intensity_allocation(0.0, &cfg);
+        assert!(!throttle_lowers_worker(&low, &cfg));
+        let capped = throttled_allocation(&low, &cfg);
+        assert_eq!(capped.worker_cpu_percent, low.worker_cpu_percent);
+        assert_eq!(capped.worker_gpu_percent, low.worker_gpu_percent);
+        let overridden = ThermalState {
+            mode_overridden: true,
+            throttle_lowers_worker: false,
+            ..thermal(PerformanceMode::Gaming, 3)
+        };
+        assert_eq!(thermal_decision(&at(90.0, 90.0), &overridden, &cfg), None);
+        assert!(throttle_lowers_worker(
+            &intensity_allocation(1.0, &cfg),
+            &cfg
+        ));
+    }
+
+    fn curve() -> Config {
+        Config {
+            autopilot_full_headroom_c: 20.0,
+            autopilot_min_scale: 0.3,
+            autopilot_curve_exponent: 2.0,
+            ..config()
+        }
+    }
+
+    #[test]
+    fn autopilot_curve_at_several_headrooms() {
+        let cfg = curve();
+        // 1 - (1 - h/20)^2, mapped onto 0.3..=1.
+        for (headroom, scale) in [
+            (30.0, 1.0),
+            (20.0, 1.0),
+            (10.0, 0.83),
+            (5.0, 0.61),
+            (0.0, 0.3),
+        ] {
+            assert_eq!(autopilot_scale(headroom, &cfg), scale, "{headroom}C");
+        }
+        assert_eq!(autopilot_scale(-5.0, &cfg), 0.3);
+    }
+
+    #[test]
+    fn autopilot_curve_is_off_without_a_full_headroom() {
+        assert_eq!(autopilot_scale(0.0, &config()), 1.0);
+    }
+
+    #[test]
+    fn autopilot_allocation_scales_only_the_worker() {
+        let cfg = curve();
+        let full = allocation_for_mode(PerformanceMode::Autopilot, &cfg);
+        // 75C is 10C under the 85C limit.
+        let scaled = autopilot_allocation(&at(75.0, 90.0), &cfg);
+        assert_eq!(
+            scaled.worker_cpu_percent,
+            cpu_share(full.worker_cpu_percent * 0.83)
+        );
+        assert_eq!(scaled.worker_gpu_percent, 75);
+        assert_eq!(scaled.ui_cpu_percent, full.ui_cpu_percent);
+        assert_eq!(scaled.ui_gpu_percent, full.ui_gpu_percent);
+        let cool = autopilot_allocation(&at(50.0, 90.0), &cfg);
+        assert_eq!(cool.worker_gpu_percent, full.worker_gpu_percent);
+    }
+
+    fn capped() -> Config {
+        Config {
+            max_worker_cpu_percent: 70,
+            max_worker_gpu_percent: 60,
+            ..config()
+        }
+    }
+
+    #[test]
+    fn no_mode_exceeds_the_worker_caps() {
+        let cfg = capped();
+        for mode in PerformanceMode::ALL {
+            let allocation = allocation_for_mode(mode, &cfg);
+            assert!(allocation.worker_cpu_percent <= 70.0, "{allocation:?}");
+            assert!(allocation.worker_gpu_percent <= 60, "{allocation:?}");
+            let boosted = focus_boosted(allocation, &cfg);
+            assert!(boosted.worker_cpu_percent <= 70.0, "{boosted:?}");
+            assert!(boosted.worker_gpu_percent <= 60, "{boosted:?}");
+        }
+        let sleep = allocation_for_mode(PerformanceMode::Sleep, &cfg);
+        assert_eq!(sleep.worker_cpu_percent, 70.0);
+    }
+
+    #[test]
+    fn custom_allocations_above_the_cap_are_rejected() {
+        let cfg = capped();
+        let custom = Allocation {
+            worker_cpu_percent: 71.0,
+            ..allocation_for_mode(PerformanceMode::Gaming, &cfg)
+        };
+        let error = custom.validate_custom(&cfg).unwrap_err();
+        assert!(error.contains("above the 70% worker cap"), "{error}");
+    }
+
+    #[test]
+    fn cpu_shares_may_be_fractional_within_range() {
+        let base = allocation_for_mode(PerformanceMode::Balanced, &config());
+        let fine = Allocation {
+            worker_cpu_percent: 12.5,
+            ..base.clone()
+        };
+        assert!(fine.validate().is_ok());
+        let json = serde_json::to_value(&fine).unwrap();
+        assert_eq!(json["worker_cpu_percent"], 12.5);
+        for share in [-0.5, 100.5] {
+            let out_of_range = Allocation {
+                worker_cpu_percent: share,
+                ..base.clone()
+            };
+            assert!(out_of_range.validate().is_err(), "{share}");
+        }
+    }
+
+    #[test]
+    fn load_raising_requests_wait_out_a_throttle() {
+        let cfg = config();
+        let (balanced, sleep, gaming) = (
+            PerformanceMode::Balanced,
+            PerformanceMode::Sleep,
+            PerformanceMode::Gaming,
+        );
+        assert!(defers_mode_change(true, balanced, sleep, &cfg));
+        assert!(!defers_mode_change(true, balanced, gaming, &cfg));
+        assert!(!defers_mode_change(false, balanced, sleep, &cfg));
+        let allow = Config {
+            throttle_user_override: ThrottleOverride::Allow,
+            ..config()
+        };
+        assert!(!defers_mode_change(true, balanced, sleep, &allow));
+    }
+
+    #[test]
+    fn a_request_racing_a_throttle_is_restored_after_it() {
+        let cfg = config();
+        let hot = at(88.0, 95.0);
+        // The worker tick throttles Autopilot first...
+        let mut state = thermal(PerformanceMode::Autopilot, 3);
+        let Some(ThermalAction::Throttle(throttled)) = thermal_decision(&hot, &state, &cfg) else {
+            panic!("expected a throttle");
+        };
+        state = ThermalState {
+            mode: throttled,
+            throttled_from: Some(state.mode),
+            ..state
+        };
+        // ...then the user asks for Sleep, which would load the GPU further.
+        let requested = PerformanceMode::Sleep;
+        assert!(defers_mode_change(true, state.mode, requested, &cfg));
+        state.throttled_from = Some(requested);
+        // Still hot: nothing changes. Once cool, the request is honoured.
+        assert_eq!(thermal_decision(&hot, &state, &cfg), None);
+        state.over_limit_samples = 0;
+        assert_eq!(
+            thermal_decision(&at(70.0, 50.0), &state, &cfg),
+            Some(ThermalAction::Resume(requested))
+        );
+    }
+}
+
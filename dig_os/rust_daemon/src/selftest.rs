@@ -0,0 +1,95 @@
+use anyhow::{anyhow, Result};
+
+use crate::{
+    cgroups, config::Config, counters::ErrorCounters, health::Status, scheduler::PerformanceMode,
+    telemetry,
+};
+
+/// Probes config, data dir, cgroups, the GPU tool and one telemetry
+/// collection, prints a report and fails if any check failed. Degraded
+/// checks (the daemon would run, but with reduced function) don't fail.
+pub fn run() -> Result<()> {
+    let mut checks = Vec::new();
+
+    let config = match Config::from_env() {
+        Ok(config) => {
+            checks.push((Status::Ok, "config", "parsed and validated".to_string()));
+            Some(config)
+        }
+        Err(error) => {
+            checks.push((Status::Failed, "config", format!("{error:#}")));
+            None
+        }
+    };
+
+    let probe = cgroups::probe();
+    checks.push(match (probe.version, probe.writable) {
+        ("v2", true) => (Status::Ok, "cgroups", "v2, writable".to_string()),
+        (version, writable) => (
+            Status::Degraded,
+            "cgroups",
+            format!("{version}, writable={writable}; limits will not be enforced"),
+        ),
+    });
+
+    if let Some(config) = &config {
+        checks.push(match config.create_data_dir() {
+            Ok(()) => (
+                Status::Ok,
+                "data dir",
+                config.data_dir.display().to_string(),
+            ),
+            Err(error) => (Status::Failed, "data dir", format!("{error:#}")),
+        });
+
+        checks.push(if telemetry::gpu_tool_present(config) {
+            (Status::Ok, "gpu tool", config.gpu_smi_cmd.clone())
+        } else {
+            (
+                Status::Degraded,
+                "gpu tool",
+                format!(
+                    "{} not found; telemetry will be synthetic",
+                    config.gpu_smi_cmd
+                ),
+            )
+        });
+
+        let snapshot = telemetry::collect_snapshot(
+            PerformanceMode::Balanced,
+            config,
+            config.telemetry_source,
+            &ErrorCounters::default(),
+        );
+        checks.push((
+            Status::Ok,
+            "telemetry",
+            format!(
+                "source={:?} cpu={}% gpu={}% gpu_temp={}C",
+                snapshot.source,
+                snapshot.cpu_load_percent,
+                snapshot.gpu_load_percent,
+                snapshot.gpu_temp_c
+            ),
+        ));
+    }
+
+    for (status, name, detail) in &checks {
+        let label = match status {
+            Status::Ok => "PASS",
+            Status::Degraded => "WARN",
+            Status::Failed => "FAIL",
+        };
+        println!("{label}  {name:<10} {detail}");
+    }
+
+    let failed = checks
+        .iter()
+        .filter(|(status, ..)| *status == Status::Failed)
+        .count();
+    if failed > 0 {
+        return Err(anyhow!("selftest failed: {failed} check(s) failed"));
+    }
+    Ok(())
+}
+
@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+/// Exponential moving average weight. Built from a time window so the same
+/// window smooths alike at any poll rate: a sample's weight has decayed to
+/// 1/e once `window` has passed since it was taken.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ema {
+    alpha: f32,
+}
+
+impl Ema {
+    /// A fixed weight for the newest sample, clamped to `0..=1`.
+    pub fn with_alpha(alpha: f32) -> Self {
+        Self {
+            alpha: alpha.clamp(0.0, 1.0),
+        }
+    }
+
+    /// The weight for samples `interval` apart: `1 - e^(-interval / window)`.
+    /// A zero window disables smoothing (alpha 1); a zero interval leaves
+    /// the average where it was (alpha 0).
+    pub fn from_window(window: Duration, interval: Duration) -> Self {
+        if window.is_zero() {
+            return Self::with_alpha(1.0);
+        }
+        let ratio = interval.as_secs_f32() / window.as_secs_f32();
+        Self::with_alpha(1.0 - (-ratio).exp())
+    }
+
+    pub fn alpha(self) -> f32 {
+        self.alpha
+    }
+
+    /// One step: moves `previous` towards `sample` by `alpha`.
+    pub fn step(self, previous: f32, sample: f32) -> f32 {
+        previous + self.alpha * (sample - previous)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_moves_by_alpha() {
+        let ema = Ema::with_alpha(0.25);
+        assert_eq!(ema.step(10.0, 20.0), 12.5);
+        assert_eq!(ema.step(12.5, 12.5), 12.5);
+        assert_eq!(Ema::with_alpha(1.0).step(10.0, 20.0), 20.0);
+    }
+
+    #[test]
+    fn repeated_steps_converge_on_a_steady_sample() {
+        let ema = Ema::with_alpha(0.5);
+        let average = (0..20).fold(0.0, |average, _| ema.step(average, 8.0));
+        assert!((average - 8.0).abs() < 1e-3, "{average}");
+    }
+
+    #[test]
+    fn alpha_is_clamped() {
+        assert_eq!(Ema::with_alpha(-1.0).alpha(), 0.0);
+        assert_eq!(Ema::with_alpha(2.0).alpha(), 1.0);
+    }
+
+    #[test]
+    fn alpha_derives_from_the_window() {
+        let secs = Duration::from_secs;
+        let alpha = Ema::from_window(secs(10), secs(10)).alpha();
+        assert!((alpha - (1.0 - (-1.0f32).exp())).abs() < 1e-6, "{alpha}");
+        let fast_polls = Ema::from_window(secs(10), Duration::from_millis(500)).alpha();
+        assert!((fast_polls - 0.048_77).abs() < 1e-4, "{fast_polls}");
+    }
+
+    #[test]
+    fn one_window_decays_alike_at_any_poll_rate() {
+        let window = Duration::from_secs(30);
+        let remaining = |interval: Duration, steps: u32| {
+            let ema = Ema::from_window(window, interval);
+            (0..steps).fold(1.0, |average, _| ema.step(average, 0.0))
+        };
+        let slow = remaining(Duration::from_secs(5), 6);
+        let fast = remaining(Duration::from_millis(500), 60);
+        assert!((slow - fast).abs() < 1e-4, "{slow} vs {fast}");
+        assert!((slow - (-1.0f32).exp()).abs() < 1e-4);
+    }
+
+    #[test]
+    fn zero_window_disables_smoothing_and_zero_interval_holds() {
+        let secs = Duration::from_secs;
+        assert_eq!(Ema::from_window(Duration::ZERO, secs(1)).alpha(), 1.0);
+        assert_eq!(Ema::from_window(secs(10), Duration::ZERO).alpha(), 0.0);
+    }
+}
+
@@ -1,30 +1,1046 @@
-use tokio::sync::RwLock;
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock as StdRwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use tokio::sync::{watch, OwnedSemaphorePermit, RwLock, RwLockWriteGuard, Semaphore};
+use tracing::warn;
 
 use crate::{
+    cgroups::{self, InitialApply},
     config::Config,
-    scheduler::{Allocation, PerformanceMode},
-    telemetry::TelemetrySnapshot,
+    counters::ErrorCounters,
+    events::EventLog,
+    gpu_power::{self, PowerCap},
+    lifetime::Lifetime,
+    missions::{self, DatasetStatus, Mission},
+    scheduler::{
+        allocation_for_mode, autopilot_allocation, critical_allocation, focus_boosted,
+        idle_adjusted, intensity_allocation, memory_guarded_allocation, throttled_allocation,
+        Allocation, BalanceTracker, CooldownTracker, Imbalance, PerformanceMode,
+    },
+    smoothing::Ema,
+    telemetry::{self, SmoothedMetrics, SourcePreference, TelemetrySnapshot},
 };
 
+/// Lock waits longer than this are logged by `AppState::write_runtime`.
+const LOCK_WAIT_WARN: Duration = Duration::from_millis(250);
+
 pub struct RuntimeState {
+    /// Bumped whenever mode, allocation or the active mission changes.
+    pub version: u64,
+    /// Set only through `enter_mode`, which keeps `mode_entered_at` current.
     pub mode: PerformanceMode,
+    /// When `mode` last changed, from any source.
+    pub mode_entered_at: DateTime<Utc>,
+    /// Mode the thermal throttle switched away from, restored once the GPU
+    /// drops to `thermal_resume_c`. Cleared by any explicit mode change.
+    pub throttled_from: Option<PerformanceMode>,
+    /// Consecutive worker samples with the GPU at or over the limit.
+    pub over_limit_samples: u32,
+    /// Worker ticks left in the settle period after an API mode change.
+    pub settle_ticks_left: u32,
+    /// Which control last set the allocation. The latest write wins: a
+    /// mode change replaces the intensity dial and vice versa.
+    pub control: AllocationControl,
+    pub control_set_at: DateTime<Utc>,
+    /// Allocation posted to `/api/v1/allocation`, applied as is while
+    /// `control` is `Custom`.
+    pub custom_allocation: Option<Allocation>,
+    /// Set while the GPU is past `thermal_critical_c`; every allocation
+    /// applied meanwhile is cut down by `critical_allocation`.
+    pub thermal_emergency: bool,
+    /// Set while available memory is under `min_free_memory_mib`; applied
+    /// allocations are stepped down by `memory_guarded_allocation`.
+    pub memory_pressure: bool,
+    /// `autopilot_scale` behind the last Autopilot allocation the worker
+    /// loop applied.
+    pub autopilot_scale: f32,
+    /// Mode of the schedule window in effect as of the last worker tick;
+    /// the mode only follows the schedule when this changes, so manual
+    /// changes hold until the next boundary.
+    pub scheduled_mode: Option<PerformanceMode>,
+    /// Last successful `POST /api/v1/mode`, for
+    /// `min_mode_change_interval_ms`.
+    pub api_mode_changed_at: Option<DateTime<Utc>>,
     pub allocation: Allocation,
     pub telemetry: TelemetrySnapshot,
+    /// Recent stored snapshots, oldest first; see `telemetry_history_size`.
+    pub telemetry_history: VecDeque<TelemetrySnapshot>,
+    /// Sum of `approx_size_bytes` over `telemetry_history`.
+    pub telemetry_history_bytes: usize,
+    pub source_preference: SourcePreference,
     pub active_mission: Option<String>,
+    /// Mission started with `focus`; the boost only applies while it is
+    /// still the active one, so switching missions drops it.
+    pub focused_mission: Option<String>,
     pub session_xp: u64,
+    pub xp_limiter: XpLimiter,
+    /// DIG earned this session, integrated over worker ticks.
+    pub session_earnings_dig: f64,
+    /// Cumulative `impact_score` per mission domain this session, one
+    /// sample per worker tick.
+    pub session_impact: BTreeMap<String, f64>,
+    /// Sustained contribution: rises towards `impact_score` on working ticks
+    /// and decays on idle ones; see `tick_momentum`.
+    pub contribution_momentum: f32,
+    pub completed_missions: BTreeSet<String>,
+    /// Missions dropped by `StallAction::Fail`; starting one again clears it.
+    pub failed_missions: BTreeSet<String>,
+    /// Last working tick of the active mission, or when it became active;
+    /// see `mission_stalled`.
+    pub mission_progress_at: BTreeMap<String, DateTime<Utc>>,
+    /// Dataset statuses reported through the API, by mission id. Missions
+    /// without an entry are checked against the dataset dir.
+    pub dataset_status: BTreeMap<String, DatasetStatus>,
+    /// Active time per unfinished mission; removed when it completes, so a
+    /// restart begins from zero.
+    pub mission_elapsed_secs: BTreeMap<String, f64>,
+    /// Whether the last cgroup apply succeeded.
+    pub cgroups_applied: bool,
+    pub initial_cgroup_apply: InitialApply,
+    pub initial_cgroup_attempts: u32,
+    pub worker_tick_at: DateTime<Utc>,
+    /// Current worker poll interval: `poll_interval_ms`, or longer while
+    /// backing off on an idle machine.
+    pub poll_interval_ms: u64,
+    /// Start of the current idle stretch, if the machine is idle.
+    pub idle_since: Option<DateTime<Utc>>,
+    /// How long the last complete worker iteration took.
+    pub worker_tick_duration: Duration,
+    /// Worker iterations that went over `Config::tick_budget`.
+    pub slow_worker_ticks: u64,
+    /// Last heartbeat from the external worker process, if any arrived.
+    pub worker_heartbeat: Option<WorkerHeartbeat>,
+    /// Whether that heartbeat is older than `worker_heartbeat_timeout_secs`.
+    pub worker_lost: bool,
+    pub events: EventLog,
+    pub balance: BalanceTracker,
+    pub cooldown: CooldownTracker,
+    pub balance_suggestion: Option<BalanceSuggestion>,
+}
+
+/// What drives the allocation. The mode is kept under `Intensity` and
+/// `Custom` so the thermal policy and mode defaults still have one to work
+/// with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AllocationControl {
+    Mode,
+    /// Position of the intensity dial, 0..=1.
+    Intensity(f32),
+    /// `RuntimeState::custom_allocation`, set through the API.
+    Custom,
+}
+
+impl AllocationControl {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Mode => "mode",
+            Self::Intensity(_) => "intensity",
+            Self::Custom => "custom",
+        }
+    }
+
+    pub fn intensity(self) -> Option<f32> {
+        match self {
+            Self::Mode | Self::Custom => None,
+            Self::Intensity(value) => Some(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct WorkerHeartbeat {
+    pub pid: u32,
+    pub last_seen: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BalanceSuggestion {
+    pub timestamp: DateTime<Utc>,
+    pub imbalance: Imbalance,
+    pub suggestion: String,
+}
+
+impl RuntimeState {
+    /// Stores `snapshot` unless a newer one is already present, so an
+    /// on-demand refresh and the worker loop can't roll each other back.
+    /// Returns whether it was stored.
+    pub fn store_telemetry(&mut self, snapshot: TelemetrySnapshot) -> bool {
+        if snapshot.timestamp >= self.telemetry.timestamp {
+            self.telemetry = snapshot;
+            return true;
+        }
+        false
+    }
+
+    /// Appends the current snapshot to `telemetry_history`, dropping the
+    /// oldest beyond `capacity` snapshots or `max_bytes` (0 = no budget).
+    pub fn record_history(&mut self, capacity: usize, max_bytes: usize) {
+        self.telemetry_history_bytes += self.telemetry.approx_size_bytes();
+        self.telemetry_history.push_back(self.telemetry.clone());
+        while self.telemetry_history.len() > capacity
+            || (max_bytes > 0 && self.telemetry_history_bytes > max_bytes)
+        {
+            let Some(oldest) = self.telemetry_history.pop_front() else {
+                break;
+            };
+            self.telemetry_history_bytes -= oldest.approx_size_bytes();
+        }
+    }
+
+    /// The stored snapshot closest to `at`, or `None` when `at` is outside
+    /// the span `telemetry_history` covers.
+    pub fn snapshot_near(&self, at: DateTime<Utc>) -> Option<&TelemetrySnapshot> {
+        let first = self.telemetry_history.front()?;
+        let last = self.telemetry_history.back()?;
+        if at < first.timestamp || at > last.timestamp {
+            return None;
+        }
+        self.telemetry_history
+            .iter()
+            .min_by_key(|snapshot| (snapshot.timestamp - at).abs())
+    }
+
+    /// Switches to `mode`, restarting the dwell time if it differs.
+    pub fn enter_mode(&mut self, mode: PerformanceMode) {
+        if mode != self.mode {
+            self.mode = mode;
+            self.mode_entered_at = Utc::now();
+        }
+    }
+
+    /// Whole seconds spent in the current mode.
+    pub fn mode_dwell_secs(&self) -> u64 {
+        (Utc::now() - self.mode_entered_at).num_seconds().max(0) as u64
+    }
+
+    /// Records `control` as the latest writer of the allocation.
+    pub fn set_control(&mut self, control: AllocationControl) {
+        self.control = control;
+        self.control_set_at = Utc::now();
+    }
+
+    /// What should be applied now: a custom allocation verbatim, or the
+    /// intensity dial's allocation, if one is set; else the mode's
+    /// (Autopilot's scaled to the latest thermal headroom), scaled to the
+    /// idle share while the worker isn't busy. A thermal throttle caps a
+    /// custom or dial allocation at the throttled mode's worker shares,
+    /// keeping any that are already below them.
+    pub fn target_allocation(&self, cfg: &Config) -> Allocation {
+        if self.control == AllocationControl::Custom {
+            if let Some(custom) = &self.custom_allocation {
+                return match self.throttled_from {
+                    Some(_) => throttled_allocation(custom, cfg),
+                    None => custom.clone(),
+                };
+            }
+        }
+        let intensity = self.control.intensity();
+        let allocation = match intensity {
+            Some(intensity) if self.throttled_from.is_some() => {
+                throttled_allocation(&intensity_allocation(intensity, cfg), cfg)
+            }
+            Some(intensity) => intensity_allocation(intensity, cfg),
+            None if self.mode == PerformanceMode::Autopilot => {
+                autopilot_allocation(&self.telemetry, cfg)
+            }
+            None => allocation_for_mode(self.mode, cfg),
+        };
+        let allocation = if self.focus_boost_applies() {
+            focus_boosted(allocation, cfg)
+        } else {
+            allocation
+        };
+        idle_adjusted(allocation, cfg, self.worker_busy(cfg))
+    }
+
+    /// `allocation` as written to the cgroups: halved under memory pressure
+    /// and cut to the critical shares in a thermal emergency. Derived on
+    /// each call, so `allocation` itself stays the target and repeated
+    /// applies don't compound the cuts.
+    pub fn applied_allocation(&self, cfg: &Config) -> Allocation {
+        let mut allocation = self.allocation.clone();
+        if self.memory_pressure {
+            allocation = memory_guarded_allocation(&allocation);
+        }
+        if self.thermal_emergency {
+            allocation = critical_allocation(&allocation, cfg);
+        }
+        allocation
+    }
+
+    /// Whether `target_allocation` boosts the worker for a focused active
+    /// mission. Custom allocations are applied as given, and a throttle
+    /// would have part of its cut undone.
+    pub fn focus_boost_applies(&self) -> bool {
+        self.active_mission.is_some()
+            && self.focused_mission == self.active_mission
+            && self.throttled_from.is_none()
+            && !(self.control == AllocationControl::Custom && self.custom_allocation.is_some())
+    }
+
+    /// How much longer an API mode change has to wait to keep
+    /// `min_mode_change_interval_ms` after the previous one; `None` when it
+    /// may go ahead.
+    pub fn api_mode_change_wait(&self, cfg: &Config, now: DateTime<Utc>) -> Option<Duration> {
+        let changed_at = self.api_mode_changed_at?;
+        let interval = Duration::from_millis(cfg.min_mode_change_interval_ms);
+        let elapsed = (now - changed_at).to_std().unwrap_or_default();
+        interval.checked_sub(elapsed).filter(|wait| !wait.is_zero())
+    }
+
+    /// Age after which telemetry or the worker counts as stalled: three of
+    /// the current poll intervals.
+    pub fn stale_after_ms(&self) -> i64 {
+        (self.poll_interval_ms * 3) as i64
+    }
+
+    /// Whether the worker should get its mode's full shares: a mission is
+    /// active and, with `worker_lost_idle`, the external worker is alive.
+    pub fn worker_busy(&self, cfg: &Config) -> bool {
+        self.active_mission.is_some() && !(self.worker_lost && cfg.worker_lost_idle)
+    }
+
+    /// Whether the external worker's last heartbeat is older than the
+    /// configured timeout. Never true before the first heartbeat.
+    pub fn heartbeat_expired(&self, now: DateTime<Utc>, cfg: &Config) -> bool {
+        cfg.worker_heartbeat_timeout_secs > 0
+            && self.worker_heartbeat.is_some_and(|heartbeat| {
+                (now - heartbeat.last_seen).num_seconds()
+                    >= cfg.worker_heartbeat_timeout_secs as i64
+            })
+    }
+
+    /// Zeroes the per-session counters and, with `clear_history`, empties
+    /// the telemetry history. Mode, allocation and the active mission are
+    /// left alone.
+    pub fn reset_session(&mut self, clear_history: bool) {
+        self.session_xp = 0;
+        self.xp_limiter = XpLimiter::default();
+        self.session_earnings_dig = 0.0;
+        self.session_impact.clear();
+        self.contribution_momentum = 0.0;
+        if clear_history {
+            self.telemetry_history.clear();
+            self.telemetry_history_bytes = 0;
+        }
+    }
+}
+
+/// XP earned by one worker tick: nothing unless a mission is active, the
+/// GPU is doing real work and the impact score reaches `min_impact`,
+/// otherwise a tenth of the impact score (at least 1).
+pub fn tick_xp(
+    snapshot: &TelemetrySnapshot,
+    mission_active: bool,
+    min_gpu_load: f32,
+    min_impact: f32,
+) -> u64 {
+    if !mission_active
+        || snapshot.gpu_load_percent < min_gpu_load
+        || snapshot.impact_score < min_impact
+    {
+        return 0;
+    }
+    (snapshot.impact_score / 10.0).max(1.0) as u64
+}
+
+/// Whether a mission last seen progressing at `progress_at` has been stuck
+/// for `timeout_secs` (0 never stalls).
+pub fn mission_stalled(progress_at: DateTime<Utc>, now: DateTime<Utc>, timeout_secs: u64) -> bool {
+    timeout_secs > 0 && (now - progress_at).num_seconds() >= timeout_secs as i64
+}
+
+/// One-off XP for completing `mission`: its bounty at `xp_per_dig`, rounded.
+pub fn completion_xp(mission: &Mission, xp_per_dig: f32) -> u64 {
+    (mission.bounty_dig * xp_per_dig).round().max(0.0) as u64
+}
+
+/// Sliding one-minute window of XP grants, enforcing `xp_cap_per_minute`.
+#[derive(Debug, Default)]
+pub struct XpLimiter {
+    grants: VecDeque<(DateTime<Utc>, u64)>,
+    capped: bool,
+}
+
+impl XpLimiter {
+    const WINDOW: chrono::Duration = chrono::Duration::seconds(60);
+
+    /// Grants as much of `xp` as still fits under `cap` for the minute
+    /// ending at `now` (0 = unlimited) and records it.
+    pub fn grant(&mut self, now: DateTime<Utc>, xp: u64, cap: u64) -> u64 {
+        while self
+            .grants
+            .front()
+            .is_some_and(|(at, _)| now - *at >= Self::WINDOW)
+        {
+            self.grants.pop_front();
+        }
+        let granted = match cap {
+            0 => xp,
+            cap => xp.min(cap.saturating_sub(self.last_minute())),
+        };
+        self.capped = granted < xp;
+        if granted > 0 {
+            self.grants.push_back((now, granted));
+        }
+        granted
+    }
+
+    /// XP granted within the window as of the last grant.
+    pub fn last_minute(&self) -> u64 {
+        self.grants.iter().map(|(_, xp)| xp).sum()
+    }
+
+    /// Whether the cap withheld XP on the last tick.
+    pub fn capped(&self) -> bool {
+        self.capped
+    }
+}
+
+/// Contribution momentum after one worker tick. A tick that earned XP pulls
+/// it towards `impact_score` by `rate`; an idle tick decays it by `rate`
+/// towards zero, so after n idle ticks `(1 - rate)^n` of it remains.
+pub fn tick_momentum(previous: f32, impact_score: f32, working: bool, rate: f32) -> f32 {
+    let target = if working { impact_score } else { 0.0 };
+    telemetry::round_to(Ema::with_alpha(rate).step(previous, target), 4)
+}
+
+/// Runs cgroup writes one at a time, so two applies, or an apply and a
+/// worker attach, can never interleave their multi-file updates.
+#[derive(Debug, Default)]
+struct CgroupWrites(Mutex<()>);
+
+impl CgroupWrites {
+    fn run<T>(&self, write: impl FnOnce() -> T) -> T {
+        let _guard = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        write()
+    }
+}
+
+/// Holds a runtime watch open for `AppState::ui_idle`.
+pub struct WatchContact<'a>(&'a AtomicUsize);
+
+impl Drop for WatchContact<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 pub struct AppState {
-    pub config: Config,
+    config: StdRwLock<Arc<Config>>,
+    pub missions: Vec<Mission>,
     pub runtime: RwLock<RuntimeState>,
+    /// Independent of who holds the runtime lock.
+    cgroup_writes: CgroupWrites,
+    /// Bounds concurrent telemetry collections and power cap writes, and so
+    /// GPU tool processes, to `max_concurrent_collections`.
+    collections: Arc<Semaphore>,
+    /// Set with `gpu_power_limit` once the card's range has been read.
+    power_cap: Option<Arc<PowerCap>>,
+    /// One permit per open WebSocket or long poll, up to
+    /// `max_stream_subscribers`.
+    streams: Arc<Semaphore>,
+    /// Last API request other than a worker heartbeat, frame from a
+    /// WebSocket client, or streaming client disconnect; see `ui_idle`.
+    ui_contact: Mutex<Instant>,
+    /// Runtime watches (long polls) waiting for a change.
+    open_watches: AtomicUsize,
+    versions: watch::Sender<u64>,
+    /// Counts stored telemetry snapshots; stream subscribers diff it to
+    /// report how many they skipped.
+    telemetry_seq: watch::Sender<u64>,
+    pub lifetime: Lifetime,
+    pub errors: Arc<ErrorCounters>,
 }
 
 impl AppState {
-    pub fn new(config: Config, runtime: RuntimeState) -> Self {
+    pub fn new(
+        config: Config,
+        missions: Vec<Mission>,
+        runtime: RuntimeState,
+        lifetime: Lifetime,
+        errors: Arc<ErrorCounters>,
+    ) -> Self {
+        let (versions, _) = watch::channel(runtime.version);
+        let (telemetry_seq, _) = watch::channel(0);
+        let collections = Arc::new(Semaphore::new(config.max_concurrent_collections));
+        let power_cap = if config.gpu_power_limit {
+            match gpu_power::read_limits(&config) {
+                Ok(limits) => Some(Arc::new(PowerCap::new(limits))),
+                Err(error) => {
+                    warn!("gpu power limit disabled: {error}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let streams = Arc::new(Semaphore::new(config.max_stream_subscribers));
         Self {
-            config,
+            config: StdRwLock::new(Arc::new(config)),
+            missions,
             runtime: RwLock::new(runtime),
+            cgroup_writes: CgroupWrites::default(),
+            collections,
+            power_cap,
+            streams,
+            ui_contact: Mutex::new(Instant::now()),
+            open_watches: AtomicUsize::new(0),
+            versions,
+            telemetry_seq,
+            lifetime,
+            errors,
         }
     }
+
+    /// Current live config. Callers get a cheap snapshot that stays
+    /// consistent even if a reload swaps in a new one meanwhile.
+    pub fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.config.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Swaps in a freshly loaded config, keeping startup-only fields.
+    pub fn reload_config(&self, fresh: Config) {
+        let mut slot = self.config.write().unwrap_or_else(|e| e.into_inner());
+        *slot = Arc::new(slot.reloaded(fresh));
+    }
+
+    /// Claims a streaming slot for as long as the permit is held, or `None`
+    /// with `max_stream_subscribers` already open.
+    pub fn try_stream_slot(&self) -> Option<OwnedSemaphorePermit> {
+        Arc::clone(&self.streams).try_acquire_owned().ok()
+    }
+
+    /// Streaming clients currently connected.
+    pub fn stream_subscribers(&self) -> usize {
+        self.config()
+            .max_stream_subscribers
+            .saturating_sub(self.streams.available_permits())
+    }
+
+    pub fn touch_ui_contact(&self) {
+        *self.ui_contact.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+
+    /// Counts a runtime watch as UI contact until the guard is dropped.
+    pub fn watch_contact(&self) -> WatchContact<'_> {
+        self.open_watches.fetch_add(1, Ordering::Relaxed);
+        WatchContact(&self.open_watches)
+    }
+
+    /// How long the UI has been out of touch; zero while a runtime watch is
+    /// open, since a connected dashboard may go a long time without a
+    /// request. An open WebSocket only counts through the frames its client
+    /// sends, pongs included, so a dead one lets the idle time run.
+    pub fn ui_idle(&self) -> Duration {
+        if self.open_watches.load(Ordering::Relaxed) > 0 {
+            return Duration::ZERO;
+        }
+        self.ui_contact
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .elapsed()
+    }
+
+    /// Write-locks the runtime, logging if the wait exceeded
+    /// `LOCK_WAIT_WARN` so a stuck holder shows up in the logs.
+    pub async fn write_runtime(&self, context: &str) -> RwLockWriteGuard<'_, RuntimeState> {
+        let started = Instant::now();
+        let guard = self.runtime.write().await;
+        let waited = started.elapsed();
+        if waited > LOCK_WAIT_WARN {
+            warn!("{context}: waited {waited:?} for the runtime lock");
+        }
+        guard
+    }
+
+    /// Bumps the runtime version and wakes long-poll watchers. Call after
+    /// changing mode, allocation or the active mission.
+    pub fn mark_runtime_changed(&self, runtime: &mut RuntimeState) {
+        runtime.version += 1;
+        self.versions.send_replace(runtime.version);
+    }
+
+    pub fn subscribe_runtime(&self) -> watch::Receiver<u64> {
+        self.versions.subscribe()
+    }
+
+    /// Collects a snapshot on the blocking pool. Callers beyond
+    /// `max_concurrent_collections` wait for an in-flight one to finish.
+    pub async fn collect_telemetry(
+        &self,
+        mode: PerformanceMode,
+        preference: SourcePreference,
+    ) -> Result<TelemetrySnapshot> {
+        let _permit = self.collections.acquire().await?;
+        let config = self.config();
+        let errors = Arc::clone(&self.errors);
+        let snapshot = tokio::task::spawn_blocking(move || {
+            telemetry::collect_snapshot(mode, &config, preference, &errors)
+        })
+        .await?;
+        Ok(snapshot)
+    }
+
+    /// Stores a snapshot and, if it was newer, notifies stream subscribers.
+    /// Smooths earnings, loads and temperatures against the previous
+    /// snapshot first, and carries its slow metrics over when this one has
+    /// none.
+    pub fn store_telemetry(&self, runtime: &mut RuntimeState, mut snapshot: TelemetrySnapshot) {
+        let config = self.config();
+        let previous = &runtime.telemetry;
+        let elapsed = (snapshot.timestamp - previous.timestamp)
+            .to_std()
+            .unwrap_or_default();
+        let ema = Ema::from_window(config.smoothing_window(), elapsed);
+        let precision = config.telemetry_precision;
+        let step = |previous: f32, sample: f32, places: u8| {
+            telemetry::round_to(ema.step(previous, sample), places)
+        };
+        snapshot.earnings_per_sec_smoothed = telemetry::round_to(
+            config.earnings_ema_alpha.map_or(ema, Ema::with_alpha).step(
+                previous.earnings_per_sec_smoothed,
+                snapshot.earnings_per_sec,
+            ),
+            precision.earnings,
+        );
+        snapshot.smoothed = SmoothedMetrics {
+            cpu_load_percent: step(
+                previous.smoothed.cpu_load_percent,
+                snapshot.cpu_load_percent,
+                precision.loads,
+            ),
+            cpu_temp_c: step(
+                previous.smoothed.cpu_temp_c,
+                snapshot.cpu_temp_c,
+                precision.temps,
+            ),
+            gpu_load_percent: step(
+                previous.smoothed.gpu_load_percent,
+                snapshot.gpu_load_percent,
+                precision.loads,
+            ),
+            gpu_temp_c: step(
+                previous.smoothed.gpu_temp_c,
+                snapshot.gpu_temp_c,
+                precision.temps,
+            ),
+        };
+        if snapshot.slow.is_none() && config.slow_metrics_every > 0 {
+            snapshot.slow = runtime.telemetry.slow.clone();
+        }
+        snapshot.slow_collected_at = snapshot.slow.as_ref().map(|slow| slow.sampled_at);
+        if runtime.store_telemetry(snapshot) {
+            runtime.record_history(
+                config.telemetry_history_size,
+                config.telemetry_history_max_bytes,
+            );
+            self.telemetry_seq.send_modify(|seq| *seq += 1);
+        }
+    }
+
+    pub fn subscribe_telemetry(&self) -> watch::Receiver<u64> {
+        self.telemetry_seq.subscribe()
+    }
+
+    /// Writes `runtime.applied_allocation` to the cgroup tree for the
+    /// active mission and records the outcome for health reporting. With
+    /// `gpu_power_limit` the worker GPU share is also queued as a power cap.
+    pub fn apply_allocation(&self, runtime: &mut RuntimeState) -> Result<()> {
+        let config = self.config();
+        let domain = self.mission_domain(runtime.active_mission.as_deref());
+        let allocation = runtime.applied_allocation(&config);
+        let result = self
+            .cgroup_writes
+            .run(|| cgroups::apply_allocation(&allocation, &config, domain, &self.errors));
+        runtime.cgroups_applied = result.is_ok();
+        self.request_power_cap(allocation.worker_gpu_percent);
+        result
+    }
+
+    /// Caps the GPU's power at `worker_gpu_percent` of its range, unless it
+    /// already is. The write runs on a blocking thread under a collections
+    /// permit, so it holds no caller's lock and never overlaps another GPU
+    /// tool run; failing it is only logged, as the cgroup result is what's
+    /// reported.
+    pub fn request_power_cap(&self, worker_gpu_percent: u8) {
+        let config = self.config();
+        let Some(cap) = self.power_cap.clone().filter(|_| config.gpu_power_limit) else {
+            return;
+        };
+        if !cap.request(worker_gpu_percent) {
+            return;
+        }
+        let collections = Arc::clone(&self.collections);
+        tokio::spawn(async move {
+            let Ok(_permit) = collections.acquire_owned().await else {
+                return;
+            };
+            match tokio::task::spawn_blocking(move || cap.write(&config)).await {
+                Ok(Err(error)) => warn!("gpu power limit not applied: {error}"),
+                Err(error) => warn!("gpu power limit write panicked: {error}"),
+                Ok(Ok(())) => {}
+            }
+        });
+    }
+
+    /// Moves `pids` into `group`, queued behind any apply in progress.
+    /// Blocking; call from `spawn_blocking`.
+    pub fn attach_pids(&self, group: &str, pids: &[u32]) -> Result<usize> {
+        self.cgroup_writes
+            .run(|| cgroups::attach_pids(group, pids, &self.errors))
+    }
+
+    /// Progress of mission `id`: its active time against the ETA, or 100
+    /// once completed and not restarted since.
+    pub fn mission_progress(&self, runtime: &RuntimeState, id: &str) -> Option<f32> {
+        let mission = missions::find(&self.missions, id)?;
+        Some(match runtime.mission_elapsed_secs.get(id) {
+            Some(elapsed) => missions::progress_percent(mission, *elapsed),
+            None if runtime.completed_missions.contains(id) => 100.0,
+            None => 0.0,
+        })
+    }
+
+    pub fn dataset_status(&self, runtime: &RuntimeState, mission: &Mission) -> DatasetStatus {
+        missions::dataset_status(
+            mission,
+            runtime.dataset_status.get(&mission.id).copied(),
+            &self.config().dataset_dir,
+        )
+    }
+
+    pub fn mission_domain(&self, id: Option<&str>) -> Option<&str> {
+        missions::find(&self.missions, id?).map(|mission| mission.domain.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt, path::PathBuf, thread, time::Duration};
+
+    use super::*;
+    use crate::telemetry::tests::snapshot;
+
+    /// Balanced, idle, with no mission and nothing applied yet.
+    fn runtime(cfg: &Config) -> RuntimeState {
+        let now = Utc::now();
+        RuntimeState {
+            version: 0,
+            mode: PerformanceMode::Balanced,
+            mode_entered_at: now,
+            throttled_from: None,
+            over_limit_samples: 0,
+            settle_ticks_left: 0,
+            control: AllocationControl::Mode,
+            control_set_at: now,
+            custom_allocation: None,
+            thermal_emergency: false,
+            memory_pressure: false,
+            autopilot_scale: 1.0,
+            scheduled_mode: None,
+            api_mode_changed_at: None,
+            allocation: allocation_for_mode(PerformanceMode::Balanced, cfg),
+            telemetry: snapshot(),
+            telemetry_history: VecDeque::new(),
+            telemetry_history_bytes: 0,
+            source_preference: SourcePreference::Auto,
+            active_mission: None,
+            focused_mission: None,
+            session_xp: 0,
+            xp_limiter: XpLimiter::default(),
+            session_earnings_dig: 0.0,
+            session_impact: BTreeMap::new(),
+            contribution_momentum: 0.0,
+            completed_missions: BTreeSet::new(),
+            failed_missions: BTreeSet::new(),
+            mission_progress_at: BTreeMap::new(),
+            dataset_status: BTreeMap::new(),
+            mission_elapsed_secs: BTreeMap::new(),
+            cgroups_applied: false,
+            initial_cgroup_apply: InitialApply::Applied,
+            initial_cgroup_attempts: 1,
+            worker_tick_at: now,
+            poll_interval_ms: cfg.poll_interval_ms,
+            idle_since: None,
+            worker_tick_duration: Duration::ZERO,
+            slow_worker_ticks: 0,
+            worker_heartbeat: None,
+            worker_lost: false,
+            events: EventLog::default(),
+            balance: BalanceTracker::default(),
+            cooldown: CooldownTracker::default(),
+            balance_suggestion: None,
+        }
+    }
+
+    /// A fresh directory under the system temp dir, unique to `name`.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dig-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn app(cfg: Config) -> AppState {
+        let runtime = runtime(&cfg);
+        let lifetime = Lifetime::start(&cfg);
+        AppState::new(cfg, Vec::new(), runtime, lifetime, Arc::default())
+    }
+
+    #[test]
+    fn concurrent_cgroup_writes_do_not_interleave() {
+        let writes = CgroupWrites::default();
+        let log = Mutex::new(Vec::new());
+        thread::scope(|scope| {
+            for writer in 0..4 {
+                let (writes, log) = (&writes, &log);
+                scope.spawn(move || {
+                    writes.run(|| {
+                        log.lock().unwrap().push(("start", writer));
+                        thread::sleep(Duration::from_millis(5));
+                        log.lock().unwrap().push(("end", writer));
+                    })
+                });
+            }
+        });
+        let log = log.into_inner().unwrap();
+        assert_eq!(log.len(), 8);
+        for pair in log.chunks(2) {
+            assert_eq!((pair[0].0, pair[1].0), ("start", "end"));
+            assert_eq!(pair[0].1, pair[1].1, "{log:?}");
+        }
+    }
+
+    #[test]
+    fn idle_ticks_award_no_xp() {
+        let busy = TelemetrySnapshot {
+            gpu_load_percent: 80.0,
+            impact_score: 50.0,
+            ..snapshot()
+        };
+        assert_eq!(tick_xp(&busy, true, 10.0, 0.0), 5);
+        assert_eq!(tick_xp(&busy, false, 10.0, 0.0), 0);
+        let idle_gpu = TelemetrySnapshot {
+            gpu_load_percent: 5.0,
+            ..busy.clone()
+        };
+        assert_eq!(tick_xp(&idle_gpu, true, 10.0, 0.0), 0);
+        assert_eq!(tick_xp(&busy, true, 10.0, 60.0), 0);
+    }
+
+    #[test]
+    fn working_ticks_award_at_least_one_xp() {
+        let light = TelemetrySnapshot {
+            gpu_load_percent: 20.0,
+            impact_score: 3.0,
+            ..snapshot()
+        };
+        assert_eq!(tick_xp(&light, true, 10.0, 0.0), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_collections_never_overlap_gpu_tool_runs() {
+        let dir = scratch_dir("collections");
+        // Fails loudly if another copy is already running.
+        let tool = dir.join("nvidia-smi");
+        fs::write(
+            &tool,
+            format!(
+                "#!/bin/sh\nmkdir {dir}/running || {{ echo overlap >> {dir}/log; exit 1; }}\nsleep 0.05\necho run >> {dir}/log\nrmdir {dir}/running\necho '50, 60, 100, 20'\n",
+                dir = dir.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+        let state = Arc::new(app(Config {
+            gpu_smi_cmd: tool.display().to_string(),
+            max_concurrent_collections: 1,
+            data_dir: dir.clone(),
+            ..Config::default()
+        }));
+
+        let collections: Vec<_> = (0..4)
+            .map(|_| {
+                let state = Arc::clone(&state);
+                tokio::spawn(async move {
+                    state
+                        .collect_telemetry(PerformanceMode::Balanced, SourcePreference::Auto)
+                        .await
+                })
+            })
+            .collect();
+        for collection in collections {
+            let snapshot = collection.await.unwrap().unwrap();
+            assert_eq!(snapshot.gpu_load_percent, 50.0);
+        }
+        let log = fs::read_to_string(dir.join("log")).unwrap();
+        assert_eq!(log.lines().collect::<Vec<_>>(), ["run"; 4]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn momentum_decays_geometrically_when_idle() {
+        let mut momentum = 80.0;
+        for n in 1..=5 {
+            momentum = tick_momentum(momentum, 50.0, false, 0.1);
+            let expected = 80.0 * 0.9_f32.powi(n);
+            assert!((momentum - expected).abs() < 1e-3, "tick {n}: {momentum}");
+        }
+    }
+
+    #[test]
+    fn momentum_builds_towards_impact_when_working() {
+        assert_eq!(tick_momentum(0.0, 50.0, true, 0.1), 5.0);
+        let sustained = (0..200).fold(0.0, |momentum, _| tick_momentum(momentum, 50.0, true, 0.1));
+        assert!((sustained - 50.0).abs() < 1e-2, "{sustained}");
+    }
+
+    #[test]
+    fn xp_cap_applies_over_a_sliding_minute() {
+        let start = Utc::now();
+        let at = |secs| start + chrono::Duration::seconds(secs);
+        let mut limiter = XpLimiter::default();
+        assert_eq!(limiter.grant(at(0), 40, 100), 40);
+        assert_eq!(limiter.grant(at(30), 40, 100), 40);
+        assert!(!limiter.capped());
+        assert_eq!(limiter.grant(at(45), 40, 100), 20);
+        assert!(limiter.capped());
+        assert_eq!(limiter.grant(at(50), 40, 100), 0);
+        // The first grant has left the window.
+        assert_eq!(limiter.grant(at(60), 40, 100), 40);
+        assert!(!limiter.capped());
+        assert_eq!(limiter.last_minute(), 100);
+    }
+
+    #[test]
+    fn xp_cap_of_zero_is_unlimited() {
+        let mut limiter = XpLimiter::default();
+        let now = Utc::now();
+        assert_eq!(limiter.grant(now, 1_000_000, 0), 1_000_000);
+        assert!(!limiter.capped());
+    }
+
+    #[test]
+    fn history_drops_the_oldest_beyond_its_capacity() {
+        let mut runtime = runtime(&Config::default());
+        for load in 0..5 {
+            runtime.telemetry.cpu_load_percent = load as f32;
+            runtime.record_history(3, 0);
+        }
+        let loads: Vec<f32> = runtime
+            .telemetry_history
+            .iter()
+            .map(|snapshot| snapshot.cpu_load_percent)
+            .collect();
+        assert_eq!(loads, [2.0, 3.0, 4.0]);
+        assert_eq!(
+            runtime.telemetry_history_bytes,
+            3 * runtime.telemetry.approx_size_bytes()
+        );
+    }
+
+    #[test]
+    fn history_stays_within_its_byte_budget() {
+        let mut runtime = runtime(&Config::default());
+        let size = runtime.telemetry.approx_size_bytes();
+        for _ in 0..10 {
+            runtime.record_history(100, size * 4 + size / 2);
+        }
+        assert_eq!(runtime.telemetry_history.len(), 4);
+        assert_eq!(runtime.telemetry_history_bytes, size * 4);
+        // A budget smaller than one snapshot keeps none.
+        runtime.record_history(100, size - 1);
+        assert!(runtime.telemetry_history.is_empty());
+        assert_eq!(runtime.telemetry_history_bytes, 0);
+    }
+
+    #[test]
+    fn throttled_dial_never_raises_the_worker() {
+        let cfg = Config::default();
+        let mut state = RuntimeState {
+            control: AllocationControl::Intensity(0.0),
+            ..runtime(&cfg)
+        };
+        let dial = state.target_allocation(&cfg);
+        state.throttled_from = Some(PerformanceMode::Gaming);
+        let throttled = state.target_allocation(&cfg);
+        assert_eq!(throttled.worker_cpu_percent, dial.worker_cpu_percent);
+        assert_eq!(throttled.worker_gpu_percent, dial.worker_gpu_percent);
+
+        state.control = AllocationControl::Intensity(1.0);
+        let balanced = runtime(&cfg).target_allocation(&cfg);
+        let throttled = state.target_allocation(&cfg);
+        assert_eq!(throttled.worker_cpu_percent, balanced.worker_cpu_percent);
+        assert_eq!(throttled.worker_gpu_percent, balanced.worker_gpu_percent);
+    }
+
+    #[test]
+    fn throttled_custom_allocation_keeps_shares_below_balanced() {
+        let cfg = Config::default();
+        let balanced = allocation_for_mode(PerformanceMode::Balanced, &cfg);
+        let custom = Allocation {
+            worker_cpu_percent: 10.0,
+            worker_gpu_percent: 5,
+            profile: "custom".to_string(),
+            ..balanced.clone()
+        };
+        let mut state = RuntimeState {
+            control: AllocationControl::Custom,
+            custom_allocation: Some(custom.clone()),
+            throttled_from: Some(PerformanceMode::Sleep),
+            ..runtime(&cfg)
+        };
+        let throttled = state.target_allocation(&cfg);
+        assert_eq!(throttled.worker_cpu_percent, 10.0);
+        assert_eq!(throttled.worker_gpu_percent, 5);
+
+        let greedy = Allocation {
+            worker_cpu_percent: 100.0 - custom.ui_cpu_percent,
+            ..custom.clone()
+        };
+        state.custom_allocation = Some(greedy);
+        let throttled = state.target_allocation(&cfg);
+        assert_eq!(throttled.worker_cpu_percent, balanced.worker_cpu_percent);
+        assert_eq!(throttled.worker_gpu_percent, 5);
+    }
+
+    #[test]
+    fn only_open_watches_hold_off_the_ui_timeout() {
+        let state = app(Config::default());
+        let _stream = state.try_stream_slot().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert!(state.ui_idle() >= Duration::from_millis(20));
+        {
+            let _watch = state.watch_contact();
+            assert_eq!(state.ui_idle(), Duration::ZERO);
+        }
+        assert!(state.ui_idle() >= Duration::from_millis(20));
+        state.touch_ui_contact();
+        assert!(state.ui_idle() < Duration::from_millis(20));
+    }
+
+    #[test]
+    fn session_reset_clears_history_only_when_asked() {
+        let mut runtime = runtime(&Config::default());
+        runtime.session_xp = 40;
+        runtime.record_history(10, 0);
+        runtime.reset_session(false);
+        assert_eq!(runtime.session_xp, 0);
+        assert_eq!(runtime.telemetry_history.len(), 1);
+        runtime.reset_session(true);
+        assert!(runtime.telemetry_history.is_empty());
+        assert_eq!(runtime.telemetry_history_bytes, 0);
+    }
 }
 
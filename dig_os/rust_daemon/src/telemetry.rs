@@ -1,80 +1,721 @@
-use std::{process::Command, time::UNIX_EPOCH};
+use std::{
+    process::Command,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::UNIX_EPOCH,
+};
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sysinfo::{ComponentExt, CpuExt, System, SystemExt};
+use sysinfo::{
+    ComponentExt, CpuExt, CpuRefreshKind, DiskExt, NetworkExt, NetworksExt, ProcessRefreshKind,
+    RefreshKind, System, SystemExt,
+};
+use tracing::{debug, warn};
 
-use crate::scheduler::PerformanceMode;
+use crate::{config::Config, counters::ErrorCounters, gpu_source, scheduler::PerformanceMode};
+
+/// Sensor readings outside this range are treated as faulty.
+const CPU_TEMP_PLAUSIBLE_C: std::ops::RangeInclusive<f32> = 10.0..=120.0;
+const CPUFREQ_ROOT: &str = "/sys/devices/system/cpu";
+/// Idle cores clock down on purpose, so throttling is only called below
+/// this load...
+const CPU_THROTTLE_MIN_LOAD: f32 = 50.0;
+/// ...with the average clock under this share of the rated maximum.
+const CPU_THROTTLE_FREQ_RATIO: f32 = 0.7;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TelemetrySnapshot {
+    /// When the snapshot was assembled; each group of fields carries its
+    /// own collection time below.
     pub timestamp: DateTime<Utc>,
+    /// When the CPU load, temperature, clock and memory were read.
+    #[serde(default)]
+    pub cpu_collected_at: DateTime<Utc>,
+    /// When the GPU tool or NVML answered, or the synthetic values were
+    /// generated.
+    #[serde(default)]
+    pub gpu_collected_at: DateTime<Utc>,
+    /// `slow.sampled_at`, which can be several ticks old since slow
+    /// metrics are carried over between samples.
+    #[serde(default)]
+    pub slow_collected_at: Option<DateTime<Utc>>,
+    /// `DIG_NODE_ID`, the hostname by default.
+    #[serde(default)]
+    pub node_id: String,
     pub cpu_load_percent: f32,
     pub cpu_temp_c: f32,
     pub gpu_load_percent: f32,
     pub gpu_temp_c: f32,
+    /// Only reported by real hardware that supports it.
+    pub gpu_memory_used_mib: Option<f32>,
+    pub gpu_power_w: Option<f32>,
+    /// Memory available to new allocations, from `sysinfo`; not reported
+    /// for synthetic profiles, or where it reads as zero.
+    #[serde(default)]
+    pub memory_available_mib: Option<u64>,
+    /// Average current clock across cores; not reported for synthetic
+    /// profiles.
+    #[serde(default)]
+    pub cpu_freq_mhz: Option<u32>,
+    /// Busy CPU held well below its rated clock by heat or power limits;
+    /// see `cpu_throttled`. Unset when the maximum clock is unknown.
+    #[serde(default)]
+    pub cpu_throttled: Option<bool>,
+    /// Disk, network and process stats, sampled every `slow_metrics_every`
+    /// ticks rather than on each one.
+    #[serde(default)]
+    pub slow: Option<SlowMetrics>,
     pub net_latency_ms: f32,
     pub earnings_per_sec: f32,
+    /// Fiat estimate of `earnings_per_sec`; only set when a rate is configured.
+    pub earnings_per_sec_usd: Option<f32>,
+    /// Exponential moving average of `earnings_per_sec` across stored
+    /// snapshots; equal to the raw value on a fresh reading.
+    pub earnings_per_sec_smoothed: f32,
+    /// Loads and temperatures averaged the same way.
+    #[serde(default)]
+    pub smoothed: SmoothedMetrics,
     pub impact_score: f32,
     pub mode: PerformanceMode,
+    pub source: TelemetrySource,
+    /// How far the GPU figures can be trusted; `stale` is only reported on
+    /// the way out, see `quality_at`.
+    #[serde(default)]
+    pub data_quality: DataQuality,
+    /// Suspicious changes against the previous worker sample; see
+    /// `detect_anomalies`.
+    #[serde(default)]
+    pub anomalies: Vec<String>,
+    /// Taken within `mode_settle_ticks` of an API mode change, while the
+    /// new allocation takes hold; the thermal throttle ignores it.
+    #[serde(default)]
+    pub settling: bool,
+}
+
+/// Where the GPU figures of a snapshot came from.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TelemetrySource {
+    RealNvidia,
+    RealAmd,
+    Synthetic,
+}
+
+/// Trustworthiness of a snapshot, from best to worst.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DataQuality {
+    Real,
+    /// Read from the GPU, but its temperature was outside
+    /// `gpu_temp_min_c..=gpu_temp_max_c` and clamped into range.
+    RealClamped,
+    #[default]
+    Synthetic,
+    /// Older than `RuntimeState::stale_after_ms`: the worker stopped
+    /// refreshing it.
+    Stale,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TemperatureUnit {
+    #[serde(rename = "c")]
+    Celsius,
+    #[serde(rename = "f")]
+    Fahrenheit,
+}
+
+impl FromStr for TemperatureUnit {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "c" | "celsius" => Ok(Self::Celsius),
+            "f" | "fahrenheit" => Ok(Self::Fahrenheit),
+            _ => Err(()),
+        }
+    }
+}
+
+impl TemperatureUnit {
+    /// Converts from Celsius, rounding a converted value to `places`.
+    pub fn convert_celsius(self, value: f32, places: u8) -> f32 {
+        match self {
+            Self::Celsius => value,
+            Self::Fahrenheit => round_to(value * 9.0 / 5.0 + 32.0, places),
+        }
+    }
 }
 
-pub fn collect_snapshot(mode: PerformanceMode) -> TelemetrySnapshot {
-    let mut system = System::new_all();
-    system.refresh_all();
+/// Decimal places per group of snapshot fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TelemetryPrecision {
+    /// CPU and GPU temperatures.
+    pub temps: u8,
+    /// CPU and GPU load percentages.
+    pub loads: u8,
+    /// All `earnings_per_sec*` fields.
+    pub earnings: u8,
+    /// Latency, power draw and impact score.
+    pub other: u8,
+}
 
-    let cpu_load = system.global_cpu_info().cpu_usage().clamp(0.0, 100.0);
-    let cpu_temp = read_cpu_temp(&system).unwrap_or_else(|| synthetic_temp(cpu_load, 33.0, 88.0));
-    let (gpu_load, gpu_temp) = read_gpu_metrics().unwrap_or_else(|| synthetic_gpu(cpu_load));
+impl TelemetryPrecision {
+    /// More places than this exceed what an `f32` can hold for typical
+    /// values.
+    pub const MAX_PLACES: u8 = 6;
+}
+
+impl Default for TelemetryPrecision {
+    fn default() -> Self {
+        Self {
+            temps: 2,
+            loads: 2,
+            earnings: 4,
+            other: 2,
+        }
+    }
+}
+
+/// Exponential moving averages over `smoothing_window_secs`, see
+/// `AppState::store_telemetry`.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct SmoothedMetrics {
+    pub cpu_load_percent: f32,
+    pub cpu_temp_c: f32,
+    pub gpu_load_percent: f32,
+    pub gpu_temp_c: f32,
+}
+
+/// Host stats that are costly to enumerate and change slowly.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SlowMetrics {
+    pub sampled_at: DateTime<Utc>,
+    /// Free space summed over every mounted disk.
+    pub disk_available_mib: u64,
+    /// Totals across all interfaces since they came up.
+    pub net_received_bytes: u64,
+    pub net_transmitted_bytes: u64,
+    pub process_count: usize,
+}
+
+/// Enumerates disks, interfaces and processes; blocking, and slow enough
+/// that the worker loop only calls it every `slow_metrics_every` ticks.
+pub fn collect_slow_metrics() -> SlowMetrics {
+    let system = System::new_with_specifics(
+        RefreshKind::new()
+            .with_disks_list()
+            .with_networks_list()
+            .with_networks()
+            .with_processes(ProcessRefreshKind::new()),
+    );
+    let (net_received_bytes, net_transmitted_bytes) =
+        system
+            .networks()
+            .iter()
+            .fold((0, 0), |(rx, tx), (_, network)| {
+                (
+                    rx + network.total_received(),
+                    tx + network.total_transmitted(),
+                )
+            });
+    SlowMetrics {
+        sampled_at: Utc::now(),
+        disk_available_mib: system
+            .disks()
+            .iter()
+            .map(|disk| disk.available_space() / (1024 * 1024))
+            .sum(),
+        net_received_bytes,
+        net_transmitted_bytes,
+        process_count: system.processes().len(),
+    }
+}
+
+impl TelemetrySnapshot {
+    /// Rough memory the snapshot takes up, inline fields plus the strings
+    /// it owns, for budgeting `telemetry_history`.
+    pub fn approx_size_bytes(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.node_id.capacity()
+            + self.anomalies.capacity() * std::mem::size_of::<String>()
+            + self.anomalies.iter().map(String::capacity).sum::<usize>()
+    }
+
+    /// `data_quality` as of `now`, downgraded to `Stale` once the snapshot
+    /// is older than `stale_after_ms`.
+    pub fn quality_at(&self, now: DateTime<Utc>, stale_after_ms: i64) -> DataQuality {
+        if (now - self.timestamp).num_milliseconds() > stale_after_ms {
+            DataQuality::Stale
+        } else {
+            self.data_quality
+        }
+    }
+
+    /// Copy of the snapshot with temperatures rendered in `unit`. Internal
+    /// state always stays in Celsius; this is only for API output.
+    pub fn in_unit(&self, unit: TemperatureUnit, places: u8) -> Self {
+        let mut out = self.clone();
+        out.cpu_temp_c = unit.convert_celsius(self.cpu_temp_c, places);
+        out.gpu_temp_c = unit.convert_celsius(self.gpu_temp_c, places);
+        out
+    }
+}
+
+/// `to` minus `from` for every numeric reading, rounded like the fields
+/// themselves. Optional readings are only compared when both have them.
+#[derive(Debug, Clone, Serialize)]
+pub struct TelemetryDelta {
+    pub cpu_load_percent: f32,
+    pub cpu_temp_c: f32,
+    pub gpu_load_percent: f32,
+    pub gpu_temp_c: f32,
+    pub gpu_memory_used_mib: Option<f32>,
+    pub gpu_power_w: Option<f32>,
+    pub memory_available_mib: Option<i64>,
+    pub cpu_freq_mhz: Option<i64>,
+    pub net_latency_ms: f32,
+    pub earnings_per_sec: f32,
+    pub earnings_per_sec_smoothed: f32,
+    pub impact_score: f32,
+}
+
+impl TelemetryDelta {
+    pub fn between(
+        from: &TelemetrySnapshot,
+        to: &TelemetrySnapshot,
+        precision: TelemetryPrecision,
+    ) -> Self {
+        let diff = |from: f32, to: f32, places: u8| round_to(to - from, places);
+        let both = |from: Option<f32>, to: Option<f32>, places: u8| {
+            from.zip(to).map(|(from, to)| diff(from, to, places))
+        };
+        let whole = |from: Option<i64>, to: Option<i64>| from.zip(to).map(|(from, to)| to - from);
+        Self {
+            cpu_load_percent: diff(from.cpu_load_percent, to.cpu_load_percent, precision.loads),
+            cpu_temp_c: diff(from.cpu_temp_c, to.cpu_temp_c, precision.temps),
+            gpu_load_percent: diff(from.gpu_load_percent, to.gpu_load_percent, precision.loads),
+            gpu_temp_c: diff(from.gpu_temp_c, to.gpu_temp_c, precision.temps),
+            gpu_memory_used_mib: both(from.gpu_memory_used_mib, to.gpu_memory_used_mib, 0),
+            gpu_power_w: both(from.gpu_power_w, to.gpu_power_w, precision.other),
+            memory_available_mib: whole(
+                from.memory_available_mib.map(|mib| mib as i64),
+                to.memory_available_mib.map(|mib| mib as i64),
+            ),
+            cpu_freq_mhz: whole(
+                from.cpu_freq_mhz.map(i64::from),
+                to.cpu_freq_mhz.map(i64::from),
+            ),
+            net_latency_ms: diff(from.net_latency_ms, to.net_latency_ms, precision.other),
+            earnings_per_sec: diff(
+                from.earnings_per_sec,
+                to.earnings_per_sec,
+                precision.earnings,
+            ),
+            earnings_per_sec_smoothed: diff(
+                from.earnings_per_sec_smoothed,
+                to.earnings_per_sec_smoothed,
+                precision.earnings,
+            ),
+            impact_score: diff(from.impact_score, to.impact_score, precision.other),
+        }
+    }
+}
+
+/// Scripted telemetry curves for demos and UI testing. Each call to
+/// `collect_snapshot` advances one step and the curve wraps around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyntheticProfile {
+    Idle,
+    GamingHeat,
+    Ramp,
+}
+
+impl FromStr for SyntheticProfile {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "idle" => Ok(Self::Idle),
+            "gaming-heat" => Ok(Self::GamingHeat),
+            "ramp" => Ok(Self::Ramp),
+            _ => Err(()),
+        }
+    }
+}
+
+impl SyntheticProfile {
+    /// `(cpu_load, gpu_load)` pairs making up one cycle of the curve.
+    fn curve(self) -> &'static [(f32, f32)] {
+        match self {
+            Self::Idle => &[(4.0, 3.0), (6.0, 5.0), (5.0, 4.0), (7.0, 6.0)],
+            Self::GamingHeat => &[
+                (45.0, 60.0),
+                (50.0, 72.0),
+                (55.0, 84.0),
+                (58.0, 93.0),
+                (60.0, 99.0),
+                (60.0, 99.0),
+                (57.0, 95.0),
+                (52.0, 88.0),
+            ],
+            Self::Ramp => &[
+                (0.0, 0.0),
+                (10.0, 20.0),
+                (20.0, 40.0),
+                (30.0, 60.0),
+                (40.0, 80.0),
+                (50.0, 100.0),
+                (40.0, 80.0),
+                (30.0, 60.0),
+                (20.0, 40.0),
+                (10.0, 20.0),
+            ],
+        }
+    }
+
+    fn sample(self, step: usize) -> (f32, f32) {
+        let curve = self.curve();
+        curve[step % curve.len()]
+    }
+}
+
+static SYNTHETIC_STEP: AtomicUsize = AtomicUsize::new(0);
+
+pub fn collect_snapshot(
+    mode: PerformanceMode,
+    cfg: &Config,
+    preference: SourcePreference,
+    errors: &ErrorCounters,
+) -> TelemetrySnapshot {
+    let mut gpu_extra = GpuReading::default();
+    let mut memory_available_mib = None;
+    let mut cpu_freq = None;
+    let mut cpu_collected_at = Utc::now();
+    let mut gpu_collected_at = cpu_collected_at;
+    let (cpu_load, cpu_temp, gpu_load, gpu_temp, source) = match cfg.synthetic_profile {
+        Some(profile) => {
+            let (cpu_load, gpu_load) =
+                profile.sample(SYNTHETIC_STEP.fetch_add(1, Ordering::Relaxed));
+            (
+                cpu_load,
+                synthetic_temp(cpu_load, 33.0, 88.0),
+                gpu_load,
+                synthetic_temp(gpu_load, 38.0, 92.0),
+                TelemetrySource::Synthetic,
+            )
+        }
+        None => {
+            // Only what every tick needs; see `collect_slow_metrics`.
+            let refresh = RefreshKind::new()
+                .with_cpu(CpuRefreshKind::everything())
+                .with_memory()
+                .with_components_list()
+                .with_components();
+            let mut system = System::new_with_specifics(refresh);
+            system.refresh_specifics(refresh);
+
+            let cpu_load = system.global_cpu_info().cpu_usage().clamp(0.0, 100.0);
+            memory_available_mib = available_mib(system.available_memory());
+            cpu_freq = read_cpu_freq(&system);
+            let cpu_temp =
+                read_cpu_temp(&system, cfg).unwrap_or_else(|| synthetic_temp(cpu_load, 33.0, 88.0));
+            cpu_collected_at = Utc::now();
+            let real = match preference {
+                SourcePreference::Auto => read_gpu_metrics(cfg, errors, cpu_load),
+                SourcePreference::Synthetic => None,
+            };
+            gpu_collected_at = Utc::now();
+            // Fields the driver reports as unsupported are filled in
+            // synthetically; the reading still counts as real hardware.
+            if let Some((reading, _)) = real {
+                gpu_extra = reading;
+            }
+            let ((gpu_load, gpu_temp), source) =
+                match real.map(|(r, source)| (r.util, r.temp, source)) {
+                    Some((Some(util), temp, source)) => (
+                        (
+                            util,
+                            temp.unwrap_or_else(|| synthetic_temp(util, 38.0, 92.0)),
+                        ),
+                        source,
+                    ),
+                    Some((None, Some(temp), source)) => ((synthetic_gpu(cpu_load).0, temp), source),
+                    _ => (synthetic_gpu(cpu_load), TelemetrySource::Synthetic),
+                };
+            (cpu_load, cpu_temp, gpu_load, gpu_temp, source)
+        }
+    };
 
     let earnings = ((gpu_load / 100.0) * 0.08).max(0.002);
     let impact_score = ((earnings * 900.0) + ((100.0 - gpu_temp).max(0.0) * 0.8)).max(0.0);
     let latency = synthetic_latency(cpu_load, gpu_load);
 
+    let data_quality = match source {
+        TelemetrySource::Synthetic => DataQuality::Synthetic,
+        TelemetrySource::RealNvidia | TelemetrySource::RealAmd if gpu_extra.temp_clamped => {
+            DataQuality::RealClamped
+        }
+        TelemetrySource::RealNvidia | TelemetrySource::RealAmd => DataQuality::Real,
+    };
+    let precision = cfg.telemetry_precision;
+    let temp = |v: f32| round_to(v, precision.temps);
+    let load = |v: f32| round_to(v, precision.loads);
+    let money = |v: f32| round_to(v, precision.earnings);
+    let other = |v: f32| round_to(v, precision.other);
     TelemetrySnapshot {
         timestamp: Utc::now(),
-        cpu_load_percent: round2(cpu_load),
-        cpu_temp_c: round2(cpu_temp),
-        gpu_load_percent: round2(gpu_load),
-        gpu_temp_c: round2(gpu_temp),
-        net_latency_ms: round2(latency),
-        earnings_per_sec: round4(earnings),
-        impact_score: round2(impact_score),
+        cpu_collected_at,
+        gpu_collected_at,
+        slow_collected_at: None,
+        node_id: cfg.node_id.clone(),
+        cpu_load_percent: load(cpu_load),
+        cpu_temp_c: temp(cpu_temp),
+        gpu_load_percent: load(gpu_load),
+        gpu_temp_c: temp(gpu_temp),
+        gpu_memory_used_mib: gpu_extra.memory_used_mib,
+        gpu_power_w: gpu_extra.power_w.map(other),
+        memory_available_mib,
+        cpu_freq_mhz: cpu_freq.map(|freq| freq.current_mhz),
+        cpu_throttled: cpu_freq.and_then(|freq| {
+            freq.max_mhz
+                .map(|max_mhz| cpu_throttled(cpu_load, freq.current_mhz, max_mhz))
+        }),
+        slow: None,
+        net_latency_ms: other(latency),
+        earnings_per_sec: money(earnings),
+        earnings_per_sec_usd: cfg.dig_to_usd_rate.map(|rate| money(earnings * rate)),
+        earnings_per_sec_smoothed: money(earnings),
+        smoothed: SmoothedMetrics {
+            cpu_load_percent: load(cpu_load),
+            cpu_temp_c: temp(cpu_temp),
+            gpu_load_percent: load(gpu_load),
+            gpu_temp_c: temp(gpu_temp),
+        },
+        impact_score: other(impact_score),
         mode,
+        source,
+        data_quality,
+        anomalies: Vec::new(),
+        settling: false,
     }
 }
 
-fn read_cpu_temp(system: &System) -> Option<f32> {
+/// Rules-based checks of `current` against `previous`:
+/// - `gpu_temp_stuck`: GPU pinned near full load on both samples but the
+///   temperature didn't move at all, typical of a frozen sensor.
+/// - `temp_falling_under_load`: GPU load rose sharply while its
+///   temperature dropped.
+pub fn detect_anomalies(previous: &TelemetrySnapshot, current: &TelemetrySnapshot) -> Vec<String> {
+    let mut anomalies = Vec::new();
+    let load_delta = current.gpu_load_percent - previous.gpu_load_percent;
+    let temp_delta = current.gpu_temp_c - previous.gpu_temp_c;
+
+    if current.gpu_load_percent >= 95.0
+        && previous.gpu_load_percent >= 95.0
+        && temp_delta.abs() < 0.01
+    {
+        anomalies.push("gpu_temp_stuck".to_string());
+    }
+    if load_delta >= 20.0 && temp_delta <= -5.0 {
+        anomalies.push("temp_falling_under_load".to_string());
+    }
+    anomalies
+}
+
+/// Hottest plausible reading across sensors, skipping the labels listed in
+/// `cpu_temp_ignore` and values outside `CPU_TEMP_PLAUSIBLE_C` (stuck or
+/// garbage sensors would otherwise drive the throttle).
+fn read_cpu_temp(system: &System, cfg: &Config) -> Option<f32> {
     let mut hottest = None::<f32>;
     for component in system.components() {
+        let label = component.label();
         let value = component.temperature();
+        if cfg
+            .cpu_temp_ignore
+            .iter()
+            .any(|ignored| ignored.eq_ignore_ascii_case(label))
+        {
+            debug!("ignoring temperature sensor {label}: {value}C (DIG_CPU_TEMP_IGNORE)");
+            continue;
+        }
+        if !CPU_TEMP_PLAUSIBLE_C.contains(&value) {
+            debug!("ignoring implausible temperature from {label}: {value}C");
+            continue;
+        }
         hottest = Some(hottest.map_or(value, |h| h.max(value)));
     }
     hottest
 }
 
-fn read_gpu_metrics() -> Option<(f32, f32)> {
-    let output = Command::new("nvidia-smi")
+#[derive(Debug, Clone, Copy)]
+struct CpuFreq {
+    current_mhz: u32,
+    max_mhz: Option<u32>,
+}
+
+/// `available` bytes in MiB. Constrained containers can report no
+/// available memory at all, which is no reading rather than an empty
+/// machine, so zero is `None`.
+fn available_mib(available: u64) -> Option<u64> {
+    Some(available / (1024 * 1024)).filter(|&mib| mib > 0)
+}
+
+/// Average `scaling_cur_freq` and highest `cpuinfo_max_freq` from cpufreq
+/// in sysfs. Without cpufreq (VMs, some containers) the current clock
+/// comes from `sysinfo` and the maximum stays unknown.
+fn read_cpu_freq(system: &System) -> Option<CpuFreq> {
+    let read_khz = |cpu: usize, file: &str| {
+        std::fs::read_to_string(format!("{CPUFREQ_ROOT}/cpu{cpu}/cpufreq/{file}"))
+            .ok()
+            .and_then(|raw| raw.trim().parse::<u64>().ok())
+    };
+    let cores = system.cpus().len();
+    let current: Vec<u64> = (0..cores)
+        .filter_map(|cpu| read_khz(cpu, "scaling_cur_freq"))
+        .collect();
+    let max_khz = (0..cores)
+        .filter_map(|cpu| read_khz(cpu, "cpuinfo_max_freq"))
+        .max();
+    if !current.is_empty() {
+        let average_khz = current.iter().sum::<u64>() / current.len() as u64;
+        return Some(CpuFreq {
+            current_mhz: (average_khz / 1000) as u32,
+            max_mhz: max_khz.map(|khz| (khz / 1000) as u32),
+        });
+    }
+    let mhz: Vec<u64> = system
+        .cpus()
+        .iter()
+        .map(|cpu| cpu.frequency())
+        .filter(|mhz| *mhz > 0)
+        .collect();
+    (!mhz.is_empty()).then(|| CpuFreq {
+        current_mhz: (mhz.iter().sum::<u64>() / mhz.len() as u64) as u32,
+        max_mhz: None,
+    })
+}
+
+/// Whether a CPU at `load_percent` running at `current_mhz` is being held
+/// back: busy (at least `CPU_THROTTLE_MIN_LOAD`) yet under
+/// `CPU_THROTTLE_FREQ_RATIO` of `max_mhz`.
+pub fn cpu_throttled(load_percent: f32, current_mhz: u32, max_mhz: u32) -> bool {
+    max_mhz > 0
+        && load_percent >= CPU_THROTTLE_MIN_LOAD
+        && (current_mhz as f32) < max_mhz as f32 * CPU_THROTTLE_FREQ_RATIO
+}
+
+/// One GPU sample; any field may be missing if the driver doesn't support it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GpuReading {
+    pub util: Option<f32>,
+    pub temp: Option<f32>,
+    pub memory_used_mib: Option<f32>,
+    pub power_w: Option<f32>,
+    /// `temp` was out of range and clamped.
+    pub temp_clamped: bool,
+}
+
+/// Reads the `compute_gpu_index`-th GPU from the first of `gpu_sources`
+/// that delivers, with the temperature clamped to the plausible range.
+fn read_gpu_metrics(
+    cfg: &Config,
+    errors: &ErrorCounters,
+    cpu_load: f32,
+) -> Option<(GpuReading, TelemetrySource)> {
+    let ctx = gpu_source::CollectContext {
+        cfg,
+        errors,
+        cpu_load,
+    };
+    let (mut reading, source) = gpu_source::collect(&cfg.gpu_sources, &ctx)?;
+    reading.util = reading.util.map(|util| util.clamp(0.0, 100.0));
+    reading.temp = reading.temp.map(|temp| {
+        let clamped = temp.clamp(cfg.gpu_temp_min_c, cfg.gpu_temp_max_c);
+        if clamped != temp {
+            reading.temp_clamped = true;
+            warn!(
+                "gpu {} reported {temp}C, clamped to {clamped}C (DIG_GPU_TEMP_MIN_C..DIG_GPU_TEMP_MAX_C)",
+                cfg.compute_gpu_index
+            );
+        }
+        clamped
+    });
+    Some((reading, source))
+}
+
+/// Which GPU source the collector may use. `Auto` prefers real hardware
+/// and falls back to synthetic values; `Synthetic` never touches the GPU.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SourcePreference {
+    Auto,
+    Synthetic,
+}
+
+impl FromStr for SourcePreference {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "synthetic" => Ok(Self::Synthetic),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Temperature at which the compute GPU starts throttling itself, from NVML
+/// where available, else from `nvidia-smi -q -d TEMPERATURE`. The
+/// `temperature.gpu.tlimit` query field isn't used: it reports the
+/// remaining headroom, not the threshold.
+pub fn read_gpu_slowdown_c(cfg: &Config) -> Option<f32> {
+    #[cfg(feature = "nvml")]
+    if let Some(slowdown_c) = crate::nvml::slowdown_temp(cfg.compute_gpu_index) {
+        return Some(slowdown_c);
+    }
+    let output = Command::new(&cfg.gpu_smi_cmd)
         .args([
-            "--query-gpu=utilization.gpu,temperature.gpu",
-            "--format=csv,noheader,nounits",
+            "-i",
+            &cfg.compute_gpu_index.to_string(),
+            "-q",
+            "-d",
+            "TEMPERATURE",
         ])
         .output()
-        .ok()?;
+        .ok()
+        .filter(|output| output.status.success())?;
+    parse_slowdown_temp(&String::from_utf8_lossy(&output.stdout))
+}
 
-    if !output.status.success() {
-        return None;
-    }
+/// `GPU Slowdown Temp` from `nvidia-smi -q` output, falling back to `GPU Max
+/// Operating Temp` on cards that only report that.
+fn parse_slowdown_temp(text: &str) -> Option<f32> {
+    let field = |name: &str| {
+        text.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            (key.trim() == name)
+                .then(|| {
+                    value
+                        .trim()
+                        .trim_end_matches('C')
+                        .trim()
+                        .parse::<f32>()
+                        .ok()
+                })
+                .flatten()
+        })
+    };
+    field("GPU Slowdown Temp").or_else(|| field("GPU Max Operating Temp"))
+}
 
-    let text = String::from_utf8(output.stdout).ok()?;
-    let line = text.lines().next()?;
-    let mut parts = line.split(',').map(|p| p.trim());
-    let util = parts.next()?.parse::<f32>().ok()?;
-    let temp = parts.next()?.parse::<f32>().ok()?;
-    Some((util.clamp(0.0, 100.0), temp.clamp(20.0, 100.0)))
+/// Whether the GPU tool can be launched at all, regardless of whether it
+/// finds a device.
+pub fn gpu_tool_present(cfg: &Config) -> bool {
+    Command::new(&cfg.gpu_smi_cmd).arg("-L").output().is_ok()
 }
 
-fn synthetic_gpu(cpu_load: f32) -> (f32, f32) {
+pub(crate) fn synthetic_gpu(cpu_load: f32) -> (f32, f32) {
     let now = Utc::now()
         .timestamp_nanos_opt()
         .unwrap_or(UNIX_EPOCH.elapsed().unwrap_or_default().as_nanos() as i64);
@@ -92,11 +733,83 @@ fn synthetic_latency(cpu_load: f32, gpu_load: f32) -> f32 {
     (12.0 + (cpu_load * 0.18) + (gpu_load * 0.22)).clamp(8.0, 190.0)
 }
 
-fn round2(v: f32) -> f32 {
-    (v * 100.0).round() / 100.0
+pub fn round_to(v: f32, places: u8) -> f32 {
+    let scale = 10f32.powi(places as i32);
+    (v * scale).round() / scale
 }
 
-fn round4(v: f32) -> f32 {
-    (v * 10_000.0).round() / 10_000.0
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A real-hardware Balanced snapshot with every reading at zero, for
+    /// tests to fill in the fields they look at.
+    pub(crate) fn snapshot() -> TelemetrySnapshot {
+        let now = Utc::now();
+        TelemetrySnapshot {
+            timestamp: now,
+            cpu_collected_at: now,
+            gpu_collected_at: now,
+            slow_collected_at: None,
+            node_id: "test".to_string(),
+            cpu_load_percent: 0.0,
+            cpu_temp_c: 0.0,
+            gpu_load_percent: 0.0,
+            gpu_temp_c: 0.0,
+            gpu_memory_used_mib: None,
+            gpu_power_w: None,
+            memory_available_mib: None,
+            cpu_freq_mhz: None,
+            cpu_throttled: None,
+            slow: None,
+            net_latency_ms: 0.0,
+            earnings_per_sec: 0.0,
+            earnings_per_sec_usd: None,
+            earnings_per_sec_smoothed: 0.0,
+            smoothed: SmoothedMetrics::default(),
+            impact_score: 0.0,
+            mode: PerformanceMode::Balanced,
+            source: TelemetrySource::RealNvidia,
+            data_quality: DataQuality::Real,
+            anomalies: Vec::new(),
+            settling: false,
+        }
+    }
+
+    #[test]
+    fn reads_the_slowdown_temperature() {
+        let text = "\
+==============NVSMI LOG==============
+
+    Temperature
+        GPU Current Temp                  : 45 C
+        GPU Shutdown Temp                 : 98 C
+        GPU Slowdown Temp                 : 95 C
+        GPU Max Operating Temp            : 91 C
+";
+        assert_eq!(parse_slowdown_temp(text), Some(95.0));
+    }
+
+    #[test]
+    fn falls_back_to_the_max_operating_temperature() {
+        let text = "\
+        GPU Slowdown Temp                 : N/A
+        GPU Max Operating Temp            : 87 C
+";
+        assert_eq!(parse_slowdown_temp(text), Some(87.0));
+        assert_eq!(parse_slowdown_temp("GPU Current Temp : 45 C"), None);
+    }
+
+    #[test]
+    fn zero_available_memory_is_no_reading() {
+        assert_eq!(available_mib(0), None);
+        assert_eq!(available_mib(2 * 1024 * 1024 * 1024), Some(2048));
+        let cfg = Config {
+            min_free_memory_mib: 1000,
+            ..Config::default()
+        };
+        let guarded = crate::scheduler::memory_pressure(available_mib(0), false, &cfg);
+        assert!(!guarded);
+    }
 }
 
@@ -0,0 +1,104 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{config::Config, scheduler::PerformanceMode, telemetry::TelemetrySnapshot};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Tries per notification; the wait before each retry doubles from
+/// `RETRY_BACKOFF`.
+const WEBHOOK_ATTEMPTS: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+static DELIVERED: AtomicU64 = AtomicU64::new(0);
+static FAILED: AtomicU64 = AtomicU64::new(0);
+
+/// Notification outcomes since startup; a retried notification counts once.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct WebhookDeliveries {
+    pub delivered: u64,
+    pub failed: u64,
+}
+
+pub fn deliveries() -> WebhookDeliveries {
+    WebhookDeliveries {
+        delivered: DELIVERED.load(Ordering::Relaxed),
+        failed: FAILED.load(Ordering::Relaxed),
+    }
+}
+
+/// Why the mode changed.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModeChangeReason {
+    Api,
+    ThermalThrottle,
+    ThermalResume,
+    Schedule,
+    UiContactLost,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModeChange {
+    pub timestamp: DateTime<Utc>,
+    pub old_mode: PerformanceMode,
+    pub new_mode: PerformanceMode,
+    pub reason: ModeChangeReason,
+    pub telemetry: TelemetrySnapshot,
+}
+
+/// POSTs `change` to the configured webhook in the background, retrying
+/// timeouts, connection errors and 5xx responses up to `WEBHOOK_ATTEMPTS`
+/// times. Delivery failures are logged and never delay the mode change
+/// itself.
+pub fn notify_mode_change(cfg: &Config, change: ModeChange) {
+    let Some(url) = cfg.webhook_url.clone() else {
+        return;
+    };
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    let client = CLIENT.get_or_init(|| {
+        Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()
+            .unwrap_or_default()
+    });
+
+    tokio::spawn(async move {
+        let mut backoff = RETRY_BACKOFF;
+        for attempt in 1..=WEBHOOK_ATTEMPTS {
+            let result = client
+                .post(&url)
+                .json(&change)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status());
+            let error = match result {
+                Ok(_) => {
+                    DELIVERED.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                Err(error) => error,
+            };
+            // A 4xx won't go away on its own.
+            let retryable = error.status().is_none_or(|status| status.is_server_error());
+            if !retryable || attempt == WEBHOOK_ATTEMPTS {
+                warn!("mode webhook failed after {attempt} attempt(s): {error}");
+                break;
+            }
+            warn!("mode webhook attempt {attempt} failed, retrying in {backoff:?}: {error}");
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+        FAILED.fetch_add(1, Ordering::Relaxed);
+    });
+}
+